@@ -0,0 +1,36 @@
+// A small binding to GNU readline with the interface of the readline crate
+// the steps were written against, which can no longer be installed
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+mod ext_readline {
+    use std::os::raw::c_char;
+    #[link(name = "readline")]
+    extern "C" {
+        pub fn add_history(line: *const c_char);
+        pub fn readline(prompt: *const c_char) -> *mut c_char;
+    }
+    extern "C" {
+        pub fn free(ptr: *mut c_char);
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadlineError;
+
+// Err at end of input, when the user presses Ctrl-D
+pub fn readline(prompt: &CStr) -> Result<CString, ReadlineError> {
+    unsafe {
+        let ret: *mut c_char = ext_readline::readline(prompt.as_ptr());
+        if ret.is_null() {
+            return Err(ReadlineError);
+        }
+        let line = CStr::from_ptr(ret).to_owned();
+        ext_readline::free(ret);
+        Ok(line)
+    }
+}
+
+pub fn add_history(line: &CStr) {
+    unsafe { ext_readline::add_history(line.as_ptr()) }
+}
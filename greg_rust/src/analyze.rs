@@ -0,0 +1,303 @@
+// Compiling to closures, stepA's alternative to eval (--eval=closure)
+//
+// analyze walks a form once and gives back a Rust closure that does what eval
+// would do with it, having already worked out which special form it is,
+// checked its shape and decided how each symbol is looked up. A fn* body is
+// analyzed along with the fn*, after resolve.rs has given its locals slots,
+// and calling the closure runs the analyzed body (see types::run_code).
+//
+// Whether a call is a macro call isn't known until its head has been
+// evaluated, so that's checked every time it runs. The first time it is one,
+// the expansion is analyzed and kept, and run directly for as long as the
+// head is the same macro, so a macro is expanded once per call as a compiler
+// would.
+//
+// Calls to closures give a Step::TailCall for run_code to make, which keeps
+// tail calls from growing the stack just like eval's loop does.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections;
+
+use types;
+use types::{LispType, LispError, LispResult, ClosureData, Code, Step, Depth};
+use types::LispType::*;
+use env::Environment;
+use intern::{DEF, LET, DO, IF, FN, QUOTE, QUASIQUOTE, DEFMACRO, MACROEXPAND, TRY, CATCH};
+use resolve;
+use {quasiquote, macroexpand, frame};
+
+// Analyzes ast and runs it, in place of eval
+pub fn eval(ast: LispType, env: Rc<Environment>) -> LispResult {
+    types::run_code(&analyze(ast), &env)
+}
+
+pub fn analyze(ast: LispType) -> Code {
+    // too deep a form can't be analyzed, and fails when it's run instead
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => return error("stack overflow")
+    };
+    match ast {
+        Symbol(sym) => Rc::new(move |env| Ok(Step::Done(env.get(sym)?))),
+        Local(sym, depth, slot) => Rc::new(move |env| match env.get_local(depth, slot) {
+            Some(val) => Ok(Step::Done(val)),
+            None => Ok(Step::Done(env.get(sym)?))
+        }),
+        List(values, _) => analyze_list(values),
+        Vector(values, _) => {
+            let codes = analyze_all(values);
+            Rc::new(move |env| Ok(Step::Done(Vector(eval_all(&codes, env)?, None))))
+        },
+        HashMap(map, _) => {
+            let codes: Vec<(LispType, Code)> = map.into_iter().map(|(key, val)| (key, analyze(val))).collect();
+            Rc::new(move |env| {
+                let mut emap = collections::HashMap::with_capacity(codes.len());
+                for &(ref key, ref code) in codes.iter() {
+                    emap.insert(key.clone(), types::run_code(code, env)?);
+                }
+                Ok(Step::Done(HashMap(emap, None)))
+            })
+        },
+        anything => constant(anything)
+    }
+}
+
+fn analyze_all(values: Vec<LispType>) -> Vec<Code> {
+    values.into_iter().map(analyze).collect()
+}
+
+fn eval_all(codes: &[Code], env: &Rc<Environment>) -> Result<Vec<LispType>, LispError> {
+    let mut evalues = Vec::with_capacity(codes.len());
+    for code in codes.iter() {
+        evalues.push(types::run_code(code, env)?);
+    }
+    Ok(evalues)
+}
+
+fn constant(val: LispType) -> Code {
+    Rc::new(move |_| Ok(Step::Done(val.clone())))
+}
+
+// Malformed forms are only reported if they're run, as eval would
+fn error(msg: &str) -> Code {
+    let msg = msg.to_string();
+    Rc::new(move |_| Err(LispError::Message(msg.clone())))
+}
+
+fn analyze_list(mut values: Vec<LispType>) -> Code {
+    if values.is_empty() {
+        return error("tried to evaluate a list with no function")
+    }
+    let args = values.split_off(1);
+    let arg0 = values.into_iter().next().unwrap();
+
+    let (form, names) = match arg0 {
+        Symbol(sym) => (Some(sym), None),
+        Binder(sym, ref names) => (Some(sym), Some(names.clone())),
+        _ => (None, None)
+    };
+    match form {
+        Some(DEF) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                (Some(Symbol(sym)), Some(val), None) => {
+                    let val = analyze(val);
+                    Rc::new(move |env| {
+                        let val = types::run_code(&val, env)?;
+                        env.set(sym, val.clone());
+                        Ok(Step::Done(val))
+                    })
+                },
+                _ => error("def! must be called with 2 args, a symbol and a value")
+            }
+        },
+        Some(LET) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                (Some(List(bindings, _)), Some(val), None) | (Some(Vector(bindings, _)), Some(val), None) => {
+                    if bindings.len() % 2 != 0 {
+                        return error("Binding arguments must have even length")
+                    }
+                    let mut codes = Vec::with_capacity(bindings.len() / 2);
+                    let mut bindings_iter = bindings.into_iter();
+                    while let (Some(sym), Some(val)) = (bindings_iter.next(), bindings_iter.next()) {
+                        match sym {
+                            Symbol(sym) => codes.push((sym, analyze(val))),
+                            _ => return error("First binding argument must be a symbol")
+                        }
+                    }
+                    let body = analyze(val);
+                    Rc::new(move |env| {
+                        let let_env = frame(env, names.clone());
+                        for &(sym, ref code) in codes.iter() {
+                            let val = types::run_code(code, &let_env)?;
+                            let_env.set(sym, val);
+                        }
+                        body(&let_env)
+                    })
+                },
+                _ => error("let* must be called with a list/vector of bindings and an expression to evaluate")
+            }
+        },
+        Some(DO) => {
+            let mut codes = analyze_all(args);
+            let last = codes.pop().unwrap_or_else(|| constant(Nil));
+            Rc::new(move |env| {
+                for code in codes.iter() {
+                    types::run_code(code, env)?;
+                }
+                last(env)
+            })
+        },
+        Some(IF) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next()) {
+                (Some(cond), Some(then)) => {
+                    let cond = analyze(cond);
+                    let then = analyze(then);
+                    let otherwise = args_iter.next().map(analyze).unwrap_or_else(|| constant(Nil));
+                    Rc::new(move |env| {
+                        let cond = types::run_code(&cond, env)?;
+                        if cond == Nil || cond == False {
+                            otherwise(env)
+                        } else {
+                            then(env)
+                        }
+                    })
+                },
+                _ => error("if must be called with at least two arguments")
+            }
+        },
+        Some(FN) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                (Some(List(args, _)), Some(val), None) | (Some(Vector(args, _)), Some(val), None) => {
+                    let (names, val) = match names {
+                        Some(names) => (names, val),
+                        None => resolve::resolve_fn(&args, &val)
+                    };
+                    let code = analyze(val.clone());
+                    Rc::new(move |env| {
+                        Ok(Step::Done(Closure(ClosureData { eval, names: names.clone(), bindings: args.clone(), body: Box::new(val.clone()), env: env.clone(), is_macro: false, code: Some(code.clone()) }, None)))
+                    })
+                },
+                _ => error("fn* must be called with a binding list and an expression")
+            }
+        },
+        Some(QUOTE) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next()) {
+                (Some(val), None) => constant(val),
+                _ => error("quote must be called with a single argument")
+            }
+        },
+        Some(QUASIQUOTE) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next()) {
+                (Some(val), None) => analyze(quasiquote(val)),
+                _ => error("quasiquote must be called with a single argument")
+            }
+        },
+        Some(DEFMACRO) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                (Some(Symbol(sym)), Some(val), None) => {
+                    let val = analyze(val);
+                    Rc::new(move |env| {
+                        match types::run_code(&val, env)? {
+                            Closure(mut closure, meta) => {
+                                closure.is_macro = true;
+                                let mac = Closure(closure, meta);
+                                env.set(sym, mac.clone());
+                                Ok(Step::Done(mac))
+                            },
+                            misunderstood => Err(LispError::Message(format!("{} is not a closure, cannot use it as a macro", misunderstood)))
+                        }
+                    })
+                },
+                _ => error("defmacro! must be called with 2 args, a symbol and a closure")
+            }
+        },
+        Some(MACROEXPAND) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next()) {
+                (Some(val), None) => Rc::new(move |env| Ok(Step::Done(macroexpand(val.clone(), env.clone())?))),
+                _ => error("macroexpand must be called with a single argument")
+            }
+        },
+        Some(TRY) => {
+            let mut args_iter = args.into_iter();
+            match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                (Some(val), None, None) => analyze(val),
+                (Some(val), Some(List(catch, _)), None) => {
+                    let mut catch_iter = catch.into_iter();
+                    let (catch_head, names) = match catch_iter.next() {
+                        Some(Binder(sym, names)) => (Some(Symbol(sym)), Some(names)),
+                        catch_head => (catch_head, None)
+                    };
+                    match (catch_head, catch_iter.next(), catch_iter.next(), catch_iter.next()) {
+                        (Some(Symbol(CATCH)), Some(Symbol(exc)), Some(handler), None) => {
+                            let val = analyze(val);
+                            let handler = analyze(handler);
+                            Rc::new(move |env| {
+                                match types::run_code(&val, env) {
+                                    Ok(val) => Ok(Step::Done(val)),
+                                    Err(err) => {
+                                        let catch_env = frame(env, names.clone());
+                                        catch_env.set(exc, err.into_value());
+                                        handler(&catch_env)
+                                    }
+                                }
+                            })
+                        },
+                        _ => error("catch* must be called with a symbol and an expression")
+                    }
+                },
+                _ => error("try* must be called with an expression and an optional catch* form")
+            }
+        },
+        _ => analyze_call(arg0, args)
+    }
+}
+
+fn analyze_call(arg0: LispType, args: Vec<LispType>) -> Code {
+    let func = analyze(arg0);
+    let codes = analyze_all(args.clone());
+    // The macro last found at the head, known by its code and environment as
+    // closures are copied rather than shared, and the expansion it gave
+    let expansion: RefCell<Option<(Code, Rc<Environment>, Code)>> = RefCell::new(None);
+    Rc::new(move |env| {
+        match types::run_code(&func, env)? {
+            Func(func, _) => Ok(Step::Done(func(eval_all(&codes, env)?)?)),
+            Closure(closure, _) => {
+                if !closure.is_macro {
+                    return Ok(Step::TailCall(Closure(closure, None), eval_all(&codes, env)?))
+                }
+                let cached = match (&*expansion.borrow(), &closure.code) {
+                    (&Some((ref code, ref macro_env, ref expanded)), &Some(ref macro_code)) => {
+                        if Rc::ptr_eq(code, macro_code) && Rc::ptr_eq(macro_env, &closure.env) {
+                            Some(expanded.clone())
+                        } else {
+                            None
+                        }
+                    },
+                    _ => None
+                };
+                let expanded = match cached {
+                    Some(expanded) => expanded,
+                    None => {
+                        let key = closure.code.clone().map(|code| (code, closure.env.clone()));
+                        let expanded = analyze(types::apply(Closure(closure, None), args.iter().cloned().map(resolve::strip).collect())?);
+                        if let Some((code, macro_env)) = key {
+                            *expansion.borrow_mut() = Some((code, macro_env, expanded.clone()));
+                        }
+                        expanded
+                    }
+                };
+                expanded(env)
+            },
+            misunderstood => Err(LispError::Message(format!("{} is not a function, cannot evaluate it", misunderstood)))
+        }
+    })
+}
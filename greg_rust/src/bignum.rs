@@ -0,0 +1,415 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub, Mul, Div, Neg};
+
+// Each limb holds nine decimal digits, which keeps parsing and printing
+// simple and lets a limb product fit in a u64
+const BASE: u64 = 1_000_000_000;
+const BASE_DIGITS: usize = 9;
+
+// An arbitrary-precision integer. The magnitude is stored least significant
+// limb first with no trailing zero limbs, so zero is an empty vector and is
+// never negative. That keeps the representation of each value unique, which
+// the derived Eq and Hash rely on.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>
+}
+
+impl BigInt {
+    fn new(negative: bool, mut limbs: Vec<u32>) -> BigInt {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        let negative = negative && !limbs.is_empty();
+        BigInt { negative, limbs }
+    }
+
+    pub fn from_i64(int: i64) -> BigInt {
+        // unsigned_abs copes with i64::MIN, whose negation doesn't fit
+        let mut mag = int.unsigned_abs();
+        let mut limbs = Vec::new();
+        while mag > 0 {
+            limbs.push((mag % BASE) as u32);
+            mag /= BASE;
+        }
+        BigInt::new(int < 0, limbs)
+    }
+
+    // None if the value doesn't fit, which is how callers decide whether a
+    // result can go back to being a plain integer
+    pub fn to_i64(&self) -> Option<i64> {
+        let mut mag: u64 = 0;
+        for &limb in self.limbs.iter().rev() {
+            mag = mag.checked_mul(BASE).and_then(|m| m.checked_add(limb as u64))?;
+        }
+        if self.negative {
+            if mag <= i64::MAX as u64 + 1 {
+                Some((mag as i64).wrapping_neg())
+            } else {
+                None
+            }
+        } else if mag <= i64::MAX as u64 {
+            Some(mag as i64)
+        } else {
+            None
+        }
+    }
+
+    // Parses an optionally signed string of decimal digits
+    pub fn parse(s: &str) -> Option<BigInt> {
+        let (negative, digits) = if let Some(rest) = s.strip_prefix('-') {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix('+') {
+            (false, rest)
+        } else {
+            (false, s)
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None
+        }
+
+        let mut limbs = Vec::new();
+        let mut end = digits.len();
+        while end > 0 {
+            let start = end.saturating_sub(BASE_DIGITS);
+            limbs.push(digits[start..end].parse::<u32>().unwrap());
+            end = start;
+        }
+        Some(BigInt::new(negative, limbs))
+    }
+
+    // The integer part of a finite float
+    pub fn from_f64(float: f64) -> BigInt {
+        // {:.0} prints every digit rather than using an exponent
+        BigInt::parse(&format!("{:.0}", float.trunc())).unwrap()
+    }
+
+    // The nearest float, going through the decimal representation since
+    // Rust's float parsing already rounds correctly
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.limbs.clone())
+    }
+
+    // Always non-negative, and zero only if both are zero
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let (mut a, mut b) = (self.abs(), other.abs());
+        while !b.is_zero() {
+            let rem = a.div_rem(&b).unwrap().1;
+            a = b;
+            b = rem;
+        }
+        a
+    }
+
+    // Truncating division, like Rust's / and % on integers. None when
+    // dividing by zero.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None
+        }
+        let (quot, rem) = div_rem_mag(&self.limbs, &other.limbs);
+        Some((BigInt::new(self.negative != other.negative, quot), BigInt::new(self.negative, rem)))
+    }
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len())
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y)
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        result.push((sum % BASE) as u32);
+        carry = sum / BASE;
+    }
+    if carry > 0 {
+        result.push(carry as u32);
+    }
+    result
+}
+
+// a - b, where a must be at least as large as b
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &limb) in a.iter().enumerate() {
+        let mut diff = limb as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        if diff < 0 {
+            diff += BASE as i64;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+        result.push(diff as u32);
+    }
+    result
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u64; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, &y) in b.iter().enumerate() {
+            let cur = result[i + j] + x as u64 * y as u64 + carry;
+            result[i + j] = cur % BASE;
+            carry = cur / BASE;
+        }
+        let mut k = i + b.len();
+        while carry > 0 {
+            let cur = result[k] + carry;
+            result[k] = cur % BASE;
+            carry = cur / BASE;
+            k += 1;
+        }
+    }
+    result.into_iter().map(|limb| limb as u32).collect()
+}
+
+fn trim(mut limbs: Vec<u32>) -> Vec<u32> {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+    limbs
+}
+
+// Schoolbook long division, one limb of the quotient at a time. Each
+// quotient limb is found by binary search, which is slow next to Knuth's
+// algorithm D but easy to check.
+fn div_rem_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut quot = vec![0u32; a.len()];
+    let mut rem: Vec<u32> = Vec::new();
+    for i in (0..a.len()).rev() {
+        rem.insert(0, a[i]);
+        rem = trim(rem);
+
+        let (mut lo, mut hi) = (0u64, BASE - 1);
+        while lo < hi {
+            let mid = (lo + hi).div_ceil(2);
+            if cmp_mag(&trim(mul_mag(b, &[mid as u32])), &rem) != Ordering::Greater {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        if lo > 0 {
+            rem = trim(sub_mag(&rem, &trim(mul_mag(b, &[lo as u32]))));
+        }
+        quot[i] = lo as u32;
+    }
+    (quot, rem)
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_mag(&self.limbs, &other.limbs))
+        }
+        match cmp_mag(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::new(other.negative, sub_mag(&other.limbs, &self.limbs)),
+            _ => BigInt::new(self.negative, sub_mag(&self.limbs, &other.limbs))
+        }
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.limbs.clone())
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::new(self.negative != other.negative, mul_mag(&self.limbs, &other.limbs))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.limbs, &other.limbs),
+            (true, true) => cmp_mag(&other.limbs, &self.limbs)
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        if self.limbs.is_empty() {
+            return out.write_str("0")
+        }
+        let mut buf = String::new();
+        if self.negative {
+            buf.push('-');
+        }
+        let mut limbs = self.limbs.iter().rev();
+        buf.push_str(&limbs.next().unwrap().to_string());
+        for limb in limbs {
+            buf.push_str(&format!("{:09}", limb));
+        }
+        out.write_str(&buf)
+    }
+}
+
+// An exact fraction. The denominator is always positive and shares no
+// factors with the numerator, so again each value has one representation.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Ratio {
+    numer: BigInt,
+    denom: BigInt
+}
+
+impl Ratio {
+    // None if denom is zero
+    pub fn new(numer: BigInt, denom: BigInt) -> Option<Ratio> {
+        if denom.is_zero() {
+            return None
+        }
+        let gcd = numer.gcd(&denom);
+        let numer = numer.div_rem(&gcd).unwrap().0;
+        let denom = denom.div_rem(&gcd).unwrap().0;
+        if denom.is_negative() {
+            Some(Ratio { numer: -&numer, denom: -&denom })
+        } else {
+            Some(Ratio { numer, denom })
+        }
+    }
+
+    pub fn from_bigint(int: BigInt) -> Ratio {
+        Ratio { numer: int, denom: BigInt::from_i64(1) }
+    }
+
+    pub fn numer(&self) -> &BigInt {
+        &self.numer
+    }
+
+    pub fn denom(&self) -> &BigInt {
+        &self.denom
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.denom == BigInt::from_i64(1)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.numer.is_zero()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.numer.is_negative()
+    }
+
+    // Rounds towards zero
+    pub fn trunc(&self) -> BigInt {
+        self.numer.div_rem(&self.denom).unwrap().0
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.numer.to_f64() / self.denom.to_f64()
+    }
+}
+
+impl Add for &Ratio {
+    type Output = Ratio;
+
+    fn add(self, other: &Ratio) -> Ratio {
+        Ratio::new(&(&self.numer * &other.denom) + &(&other.numer * &self.denom), &self.denom * &other.denom).unwrap()
+    }
+}
+
+impl Neg for &Ratio {
+    type Output = Ratio;
+
+    fn neg(self) -> Ratio {
+        Ratio { numer: -&self.numer, denom: self.denom.clone() }
+    }
+}
+
+impl Sub for &Ratio {
+    type Output = Ratio;
+
+    fn sub(self, other: &Ratio) -> Ratio {
+        self + &-other
+    }
+}
+
+impl Mul for &Ratio {
+    type Output = Ratio;
+
+    fn mul(self, other: &Ratio) -> Ratio {
+        Ratio::new(&self.numer * &other.numer, &self.denom * &other.denom).unwrap()
+    }
+}
+
+// Panics if other is zero, like integer division
+impl Div for &Ratio {
+    type Output = Ratio;
+
+    fn div(self, other: &Ratio) -> Ratio {
+        Ratio::new(&self.numer * &other.denom, &self.denom * &other.numer).expect("division by zero")
+    }
+}
+
+impl Ord for Ratio {
+    fn cmp(&self, other: &Ratio) -> Ordering {
+        // denominators are positive so cross-multiplying keeps the order
+        (&self.numer * &other.denom).cmp(&(&other.numer * &self.denom))
+    }
+}
+
+impl PartialOrd for Ratio {
+    fn partial_cmp(&self, other: &Ratio) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "{}/{}", self.numer, self.denom)
+    }
+}
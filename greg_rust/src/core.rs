@@ -1,58 +1,308 @@
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections;
+use std::fs::File;
+use std::io::Read;
+use std::str;
+use std::ffi::CString;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Ordering;
+use readline;
 use types;
-use types::{LispType, LispResult, LispError};
+use types::{LispType, LispResult, LispError, EvalFn};
 use types::LispType::*;
 use env::Environment;
+use reader;
+use bignum::{BigInt, Ratio};
+use intern::SymbolId;
 
-pub fn default_environment() -> Rc<Environment> {
-    let env = Environment::new(None);
-    env.set("+", Func(add));
-    env.set("-", Func(sub));
-    env.set("*", Func(mul));
-    env.set("/", Func(div));
+// The step's evaluator and root environment, for eval and load-file to run
+// forms with
+thread_local! {
+    static ROOT: RefCell<Option<(EvalFn, Rc<Environment>)>> = RefCell::new(None);
+}
+
+// eval is the step's evaluator, which the eval function calls on the forms it
+// is given
+pub fn default_environment(eval: EvalFn) -> Rc<Environment> {
+    let env = Rc::new(Environment::new(None));
+    ROOT.with(|root| *root.borrow_mut() = Some((eval, env.clone())));
+    env.set("+", Func(add, None));
+    env.set("-", Func(sub, None));
+    env.set("*", Func(mul, None));
+    env.set("/", Func(div, None));
+    env.set("quot", Func(quot, None));
+    env.set("rem", Func(rem, None));
+    env.set("mod", Func(modulo, None));
+    env.set("int", Func(int, None));
+    env.set("double", Func(double, None));
+    env.set("numerator", Func(numerator, None));
+    env.set("denominator", Func(denominator, None));
+
+    env.set("list", Func(mk_list, None));
+    env.set("list?", Func(is_list, None));
+    env.set("empty?", Func(is_empty, None));
+    env.set("count", Func(count, None));
+    env.set("cons", Func(cons, None));
+    env.set("concat", Func(concat, None));
+    env.set("vec", Func(vec, None));
+    env.set("vector", Func(mk_vector, None));
+    env.set("vector?", Func(is_vector, None));
+    env.set("sequential?", Func(is_sequential, None));
+    env.set("conj", Func(conj, None));
+    env.set("nth", Func(nth, None));
+    env.set("first", Func(first, None));
+    env.set("rest", Func(rest, None));
+    env.set("apply", Func(apply, None));
+    env.set("map", Func(map, None));
+
+    env.set("hash-map", Func(hash_map, None));
+    env.set("map?", Func(is_map, None));
+    env.set("assoc", Func(assoc, None));
+    env.set("dissoc", Func(dissoc, None));
+    env.set("get", Func(get, None));
+    env.set("contains?", Func(contains, None));
+    env.set("keys", Func(keys, None));
+    env.set("vals", Func(vals, None));
+
+    env.set("=", Func(eq, None));
+    env.set("throw", Func(throw, None));
+
+    env.set("nil?", Func(is_nil, None));
+    env.set("true?", Func(is_true, None));
+    env.set("false?", Func(is_false, None));
+    env.set("symbol?", Func(is_symbol, None));
+    env.set("symbol", Func(symbol, None));
+    env.set("keyword?", Func(is_keyword, None));
+    env.set("keyword", Func(keyword, None));
+
+    env.set("<", Func(lt, None));
+    env.set("<=", Func(le, None));
+    env.set(">", Func(gt, None));
+    env.set(">=", Func(ge, None));
+
+    env.set("pr-str", Func(pr_str, None));
+    env.set("str", Func(str, None));
+    env.set("prn", Func(prn, None));
+    env.set("println", Func(println, None));
 
-    env.set("list", Func(mk_list));
-    env.set("list?", Func(is_list));
-    env.set("empty?", Func(is_empty));
-    env.set("count", Func(count));
+    env.set("atom", Func(atom, None));
+    env.set("atom?", Func(is_atom, None));
+    env.set("deref", Func(deref, None));
+    env.set("reset!", Func(reset, None));
+    env.set("swap!", Func(swap, None));
 
-    env.set("=", Func(eq));
+    env.set("meta", Func(meta, None));
+    env.set("with-meta", Func(with_meta, None));
 
-    env.set("<", Func(lt));
-    env.set("<=", Func(le));
-    env.set(">", Func(gt));
-    env.set(">=", Func(ge));
+    env.set("readline", Func(readline, None));
+    env.set("read-string", Func(read_string, None));
+    env.set("read-all-string", Func(read_all_string, None));
+    env.set("load-string", Func(load_string, None));
+    env.set("slurp", Func(slurp, None));
+    env.set("time-ms", Func(time_ms, None));
 
-    env.set("pr-str", Func(pr_str));
-    env.set("str", Func(str));
-    env.set("prn", Func(prn));
-    env.set("println", Func(println));
+    env.set("load-file", Func(load_file, None));
+    env.set("eval", Func(eval_form, None));
+    env.set("*ARGV*", List(Vec::new(), None));
 
-    Rc::new(env)
+    env
 }
 
-fn binary_int_op<F: Fn(i64,i64) -> i64>(f: F, args: Vec<LispType>) -> LispResult {
-    if args.len() != 2 {
-        return Err(LispError(format!("binary function called with {} arguments", args.len())))
+// The numeric tower. Integers that don't fit in an i64 are BigIntegers, and
+// exact fractions are Rationals. Arithmetic on two Integers is checked; if it
+// overflows, or either argument is a BigInteger or Rational, it's redone
+// exactly on Ratios and the result turned back into the simplest type that
+// holds it. If either argument is a Float, both are converted to f64 instead.
+fn binary_num_op<F, G, H>(small: F, exact: G, float: H, a: &LispType, b: &LispType) -> LispResult
+    where F: Fn(i64, i64) -> Option<i64>, G: Fn(&Ratio, &Ratio) -> Ratio, H: Fn(f64, f64) -> f64 {
+    match (a, b) {
+        (&Integer(a), &Integer(b)) => if let Some(int) = small(a, b) {
+            return Ok(Integer(int))
+        },
+        (&Float(_), _) | (_, &Float(_)) => {
+            return Ok(Float(float(to_f64(a)?, to_f64(b)?)))
+        },
+        _ => {}
     }
+    Ok(types::rational(exact(&to_ratio(a)?, &to_ratio(b)?)))
+}
 
-    if let Integer(a) = args[0] {
-        if let Integer(b) = args[1] {
-            Ok(Integer(f(a, b)))
-        } else {
-            return Err(LispError(format!("illegal argument: {} to function which expects integers", args[1])))
-        }
-    } else {
-        return Err(LispError(format!("illegal argument: {} to function which expects integers", args[1])))
+fn to_ratio(val: &LispType) -> Result<Ratio, LispError> {
+    match *val {
+        Integer(int) => Ok(Ratio::from_bigint(BigInt::from_i64(int))),
+        BigInteger(ref big) => Ok(Ratio::from_bigint(big.clone())),
+        Rational(ref ratio) => Ok(ratio.clone()),
+        _ => Err(LispError::Message(format!("illegal argument: {} to function which expects numbers", val)))
+    }
+}
+
+fn to_f64(val: &LispType) -> Result<f64, LispError> {
+    match *val {
+        Integer(int) => Ok(int as f64),
+        BigInteger(ref big) => Ok(big.to_f64()),
+        Rational(ref ratio) => Ok(ratio.to_f64()),
+        Float(float) => Ok(float),
+        _ => Err(LispError::Message(format!("illegal argument: {} to function which expects numbers", val)))
+    }
+}
+
+// As in Clojure, arithmetic folds over any number of arguments from the left.
+// identity is the result of calling it with none, if that's allowed.
+fn fold_num_op(name: &str, identity: Option<LispType>, op: fn(&LispType, &LispType) -> LispResult, args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    let mut acc = match (args_iter.next(), identity) {
+        (Some(first), _) => {
+            to_f64(&first)?;
+            first
+        },
+        (None, Some(identity)) => return Ok(identity),
+        (None, None) => return Err(LispError::Message(format!("{} called with 0 arguments", name)))
+    };
+    for arg in args_iter {
+        acc = op(&acc, &arg)?;
+    }
+    Ok(acc)
+}
+
+fn add2(a: &LispType, b: &LispType) -> LispResult { binary_num_op(i64::checked_add, |a, b| a + b, |a, b| a + b, a, b) }
+fn sub2(a: &LispType, b: &LispType) -> LispResult { binary_num_op(i64::checked_sub, |a, b| a - b, |a, b| a - b, a, b) }
+fn mul2(a: &LispType, b: &LispType) -> LispResult { binary_num_op(i64::checked_mul, |a, b| a * b, |a, b| a * b, a, b) }
+
+// Dividing integers that don't divide evenly gives a Rational
+fn div2(a: &LispType, b: &LispType) -> LispResult {
+    // zero is always an Integer. Dividing a Float by zero gives an infinity or
+    // NaN instead.
+    if *b == Integer(0) && !is_float(a) {
+        return Err(LispError::Message("divide by zero".to_string()))
+    }
+    binary_num_op(|a, b| a.checked_rem(b).and_then(|rem| if rem == 0 { a.checked_div(b) } else { None }),
+                  |a, b| a / b,
+                  |a, b| a / b,
+                  a, b)
+}
+
+fn add(args: Vec<LispType>) -> LispResult { fold_num_op("+", Some(Integer(0)), add2, args) }
+fn mul(args: Vec<LispType>) -> LispResult { fold_num_op("*", Some(Integer(1)), mul2, args) }
+
+fn sub(args: Vec<LispType>) -> LispResult {
+    // multiplying rather than subtracting from zero keeps the sign of -0.0
+    if args.len() == 1 {
+        return mul2(&Integer(-1), &args[0])
+    }
+    fold_num_op("-", None, sub2, args)
+}
+
+fn div(args: Vec<LispType>) -> LispResult {
+    if args.len() == 1 {
+        return div2(&Integer(1), &args[0])
+    }
+    fold_num_op("/", None, div2, args)
+}
+
+fn is_float(val: &LispType) -> bool {
+    matches!(*val, Float(_))
+}
+
+fn two_args(name: &str, args: Vec<LispType>) -> Result<(LispType, LispType), LispError> {
+    let len = args.len();
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(a), Some(b), None) => Ok((a, b)),
+        _ => Err(LispError::Message(format!("{} called with {} arguments", name, len)))
     }
 }
 
-fn add(args: Vec<LispType>) -> LispResult { binary_int_op(|a:i64, b:i64| { a + b }, args) }
-fn sub(args: Vec<LispType>) -> LispResult { binary_int_op(|a:i64, b:i64| { a - b }, args) }
-fn mul(args: Vec<LispType>) -> LispResult { binary_int_op(|a:i64, b:i64| { a * b }, args) }
-fn div(args: Vec<LispType>) -> LispResult { binary_int_op(|a:i64, b:i64| { a / b }, args) }
+// quot, rem and mod raise divide by zero for Floats too, as in Clojure
+fn check_divisor(divisor: &LispType) -> Result<(), LispError> {
+    match *divisor {
+        Integer(0) => Err(LispError::Message("divide by zero".to_string())),
+        Float(0.0) => Err(LispError::Message("divide by zero".to_string())),
+        _ => Ok(())
+    }
+}
+
+fn exact_quot(a: &Ratio, b: &Ratio) -> Ratio {
+    Ratio::from_bigint((a / b).trunc())
+}
+
+fn exact_rem(a: &Ratio, b: &Ratio) -> Ratio {
+    a - &(&exact_quot(a, b) * b)
+}
+
+// Division truncating towards zero
+fn quot(args: Vec<LispType>) -> LispResult {
+    let (a, b) = two_args("quot", args)?;
+    check_divisor(&b)?;
+    binary_num_op(i64::checked_div, exact_quot, |a, b| (a / b).trunc(), &a, &b)
+}
+
+// The remainder of quot, which has the sign of the dividend
+fn rem(args: Vec<LispType>) -> LispResult {
+    let (a, b) = two_args("rem", args)?;
+    check_divisor(&b)?;
+    binary_num_op(i64::checked_rem, exact_rem, |a, b| a % b, &a, &b)
+}
+
+// The remainder of flooring division, which has the sign of the divisor
+fn modulo(args: Vec<LispType>) -> LispResult {
+    let (a, b) = two_args("mod", args)?;
+    check_divisor(&b)?;
+    binary_num_op(|a, b| a.checked_rem(b).map(|r| if r != 0 && (r < 0) != (b < 0) { r + b } else { r }),
+                  |a, b| {
+                      let r = exact_rem(a, b);
+                      if !r.is_zero() && r.is_negative() != b.is_negative() { &r + b } else { r }
+                  },
+                  |a, b| {
+                      let r = a % b;
+                      if r != 0.0 && (r < 0.0) != (b < 0.0) { r + b } else { r }
+                  },
+                  &a, &b)
+}
+
+fn int(args: Vec<LispType>) -> LispResult {
+    if args.len() != 1 {
+        return Err(LispError::Message(format!("int called with {} arguments", args.len())))
+    }
+
+    match args.into_iter().next().unwrap() {
+        Float(float) if float.is_finite() => Ok(types::integer(BigInt::from_f64(float))),
+        Float(float) => Err(LispError::Message(format!("cannot convert {} to an integer", Float(float)))),
+        Rational(ratio) => Ok(types::integer(ratio.trunc())),
+        int @ Integer(_) | int @ BigInteger(_) => Ok(int),
+        other => Err(LispError::Message(format!("illegal argument: {} to function which expects numbers", other)))
+    }
+}
+
+fn numerator(args: Vec<LispType>) -> LispResult {
+    match (args.len(), args.into_iter().next()) {
+        (1, Some(Rational(ratio))) => Ok(types::integer(ratio.numer().clone())),
+        (1, Some(int @ Integer(_))) | (1, Some(int @ BigInteger(_))) => Ok(int),
+        _ => Err(LispError::Message("numerator must be called with a rational number".to_string()))
+    }
+}
+
+fn denominator(args: Vec<LispType>) -> LispResult {
+    match (args.len(), args.into_iter().next()) {
+        (1, Some(Rational(ratio))) => Ok(types::integer(ratio.denom().clone())),
+        (1, Some(Integer(_))) | (1, Some(BigInteger(_))) => Ok(Integer(1)),
+        _ => Err(LispError::Message("denominator must be called with a rational number".to_string()))
+    }
+}
+
+fn double(args: Vec<LispType>) -> LispResult {
+    if args.len() != 1 {
+        return Err(LispError::Message(format!("double called with {} arguments", args.len())))
+    }
+
+    Ok(Float(to_f64(&args[0])?))
+}
 
 fn eq(args: Vec<LispType>) -> LispResult {
+    if args.is_empty() {
+        return Err(LispError::Message("= called with 0 arguments".to_string()))
+    }
+
     let mut args_iter = args.into_iter();
     let arg0 = args_iter.next();
     for arg in args_iter {
@@ -63,10 +313,75 @@ fn eq(args: Vec<LispType>) -> LispResult {
     Ok(True)
 }
 
-fn mk_list(args: Vec<LispType>) -> LispResult { Ok(List(args)) }
+fn throw(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(val), None) => Err(LispError::Value(val)),
+        _ => Err(LispError::Message("throw must be called with a single argument".to_string()))
+    }
+}
+
+fn is_nil(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Nil) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("nil? must be called with a single argument".to_string()))
+    }
+}
+
+fn is_true(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&True) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("true? must be called with a single argument".to_string()))
+    }
+}
+
+fn is_false(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&False) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("false? must be called with a single argument".to_string()))
+    }
+}
+
+fn is_symbol(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Symbol(_)) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("symbol? must be called with a single argument".to_string()))
+    }
+}
+
+fn symbol(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(Str(name)), None) => Ok(Symbol(SymbolId::intern(&name))),
+        _ => Err(LispError::Message("symbol must be called with a single string".to_string()))
+    }
+}
+
+fn is_keyword(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Keyword(_)) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("keyword? must be called with a single argument".to_string()))
+    }
+}
+
+fn keyword(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(Str(name)), None) => Ok(Keyword(format!(":{}", name))),
+        (Some(Keyword(name)), None) => Ok(Keyword(name)),
+        _ => Err(LispError::Message("keyword must be called with a single string".to_string()))
+    }
+}
+
+fn mk_list(args: Vec<LispType>) -> LispResult { Ok(List(args, None)) }
 fn is_list(args: Vec<LispType>) -> LispResult {
     for arg in args {
-        if let List(_) = arg {
+        if let List(_, _) = arg {
         } else {
             return Ok(False);
         }
@@ -77,8 +392,9 @@ fn is_list(args: Vec<LispType>) -> LispResult {
 fn is_empty(args: Vec<LispType>) -> LispResult {
     for arg in args {
         match arg {
-            List(elems) | Vector(elems) => if !elems.is_empty() { return Ok(False) },
-            _ => return Err(LispError(format!("argument {} is not a list", arg)))
+            List(elems, _) | Vector(elems, _) => if !elems.is_empty() { return Ok(False) },
+            HashMap(map, _) => if !map.is_empty() { return Ok(False) },
+            _ => return Err(LispError::Message(format!("argument {} is not a list", arg)))
         }
     }
     Ok(True)
@@ -89,37 +405,251 @@ fn count(args: Vec<LispType>) -> LispResult {
     for arg in args {
         match arg {
             Nil => (),
-            List(elems) | Vector(elems) => count += elems.len() as i64,
-            _ => return Err(LispError(format!("argument {} is not a list", arg)))
+            List(elems, _) | Vector(elems, _) => count += elems.len() as i64,
+            HashMap(map, _) => count += map.len() as i64,
+            _ => return Err(LispError::Message(format!("argument {} is not a list", arg)))
         }
     }
     Ok(Integer(count))
 }
 
-fn binary_bool_op<F: Fn(i64,i64) -> bool>(f: F, args: Vec<LispType>) -> LispResult {
-    if args.len() != 2 {
-        return Err(LispError(format!("binary function called with {} arguments", args.len())))
+fn cons(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(head), Some(List(mut elems, _)), None) | (Some(head), Some(Vector(mut elems, _)), None) => {
+            elems.insert(0, head);
+            Ok(List(elems, None))
+        },
+        _ => Err(LispError::Message("cons must be called with a value and a list".to_string()))
+    }
+}
+
+fn concat(args: Vec<LispType>) -> LispResult {
+    let mut result = Vec::new();
+    for arg in args {
+        match arg {
+            List(elems, _) | Vector(elems, _) => result.extend(elems),
+            _ => return Err(LispError::Message(format!("argument {} is not a list", arg)))
+        }
+    }
+    Ok(List(result, None))
+}
+
+fn vec(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(List(elems, _)), None) | (Some(Vector(elems, _)), None) => Ok(Vector(elems, None)),
+        _ => Err(LispError::Message("vec must be called with a single list".to_string()))
+    }
+}
+
+fn mk_vector(args: Vec<LispType>) -> LispResult { Ok(Vector(args, None)) }
+
+fn is_vector(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Vector(_, _)) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("vector? must be called with a single argument".to_string()))
+    }
+}
+
+fn is_sequential(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&List(_, _)) | Some(&Vector(_, _)) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("sequential? must be called with a single argument".to_string()))
     }
+}
 
-    if let Integer(a) = args[0] {
-        if let Integer(b) = args[1] {
-            if f(a, b) {
-                Ok(True)
+// Lists grow at the front and vectors at the back, like Clojure
+fn conj(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match args_iter.next() {
+        Some(List(mut elems, meta)) => {
+            for arg in args_iter {
+                elems.insert(0, arg);
+            }
+            Ok(List(elems, meta))
+        },
+        Some(Vector(mut elems, meta)) => {
+            elems.extend(args_iter);
+            Ok(Vector(elems, meta))
+        },
+        _ => Err(LispError::Message("conj must be called with a list or vector".to_string()))
+    }
+}
+
+fn nth(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(List(elems, _)), Some(Integer(idx)), None) | (Some(Vector(elems, _)), Some(Integer(idx)), None) => {
+            if idx >= 0 && (idx as usize) < elems.len() {
+                Ok(elems.into_iter().nth(idx as usize).unwrap())
             } else {
-                Ok(False)
+                Err(LispError::Message(format!("index {} out of range", idx)))
             }
-        } else {
-            return Err(LispError(format!("illegal argument: {} to function which expects integers", args[1])))
+        },
+        _ => Err(LispError::Message("nth must be called with a list and an integer".to_string()))
+    }
+}
+
+fn first(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(List(elems, _)), None) | (Some(Vector(elems, _)), None) => Ok(elems.into_iter().next().unwrap_or(Nil)),
+        (Some(Nil), None) => Ok(Nil),
+        _ => Err(LispError::Message("first must be called with a single list".to_string()))
+    }
+}
+
+fn rest(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(List(elems, _)), None) | (Some(Vector(elems, _)), None) => Ok(List(elems.into_iter().skip(1).collect(), None)),
+        (Some(Nil), None) => Ok(List(Vec::new(), None)),
+        _ => Err(LispError::Message("rest must be called with a single list".to_string()))
+    }
+}
+
+fn apply(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    let func = match args_iter.next() {
+        Some(func) => func,
+        None => return Err(LispError::Message("apply must be called with a function and a list".to_string()))
+    };
+    let mut func_args: Vec<LispType> = args_iter.collect();
+    match func_args.pop() {
+        Some(List(elems, _)) | Some(Vector(elems, _)) => {
+            func_args.extend(elems);
+            types::apply(func, func_args)
+        },
+        _ => Err(LispError::Message("apply must be called with a function and a list".to_string()))
+    }
+}
+
+fn map(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(func), Some(List(elems, _)), None) | (Some(func), Some(Vector(elems, _)), None) => {
+            let mut result = Vec::with_capacity(elems.len());
+            for elem in elems.into_iter() {
+                result.push(types::apply(func.clone(), vec![elem])?);
+            }
+            Ok(List(result, None))
+        },
+        _ => Err(LispError::Message("map must be called with a function and a list".to_string()))
+    }
+}
+
+fn hash_map(args: Vec<LispType>) -> LispResult {
+    let mut map = collections::HashMap::with_capacity(args.len() / 2);
+    match types::assoc_pairs(&mut map, args) {
+        Ok(()) => Ok(HashMap(map, None)),
+        Err(msg) => Err(LispError::Message(msg))
+    }
+}
+
+fn is_map(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&HashMap(_, _)) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("map? must be called with a single argument".to_string()))
+    }
+}
+
+fn assoc(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    let (mut map, meta) = match args_iter.next() {
+        Some(HashMap(map, meta)) => (map, meta),
+        Some(Nil) => (collections::HashMap::new(), None),
+        _ => return Err(LispError::Message("assoc must be called with a hash-map".to_string()))
+    };
+    match types::assoc_pairs(&mut map, args_iter.collect()) {
+        Ok(()) => Ok(HashMap(map, meta)),
+        Err(msg) => Err(LispError::Message(msg))
+    }
+}
+
+fn dissoc(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match args_iter.next() {
+        Some(HashMap(mut map, meta)) => {
+            for key in args_iter {
+                map.remove(&key);
+            }
+            Ok(HashMap(map, meta))
+        },
+        Some(Nil) => Ok(Nil),
+        _ => Err(LispError::Message("dissoc must be called with a hash-map".to_string()))
+    }
+}
+
+fn get(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(HashMap(mut map, _)), Some(key), None) => Ok(map.remove(&key).unwrap_or(Nil)),
+        (Some(Nil), Some(_), None) => Ok(Nil),
+        _ => Err(LispError::Message("get must be called with a hash-map and a key".to_string()))
+    }
+}
+
+fn contains(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(HashMap(map, _)), Some(key), None) => Ok(if map.contains_key(&key) { True } else { False }),
+        (Some(Nil), Some(_), None) => Ok(False),
+        _ => Err(LispError::Message("contains? must be called with a hash-map and a key".to_string()))
+    }
+}
+
+fn keys(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(HashMap(map, _)), None) => Ok(List(map.into_keys().collect(), None)),
+        (Some(Nil), None) => Ok(Nil),
+        _ => Err(LispError::Message("keys must be called with a single hash-map".to_string()))
+    }
+}
+
+fn vals(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(HashMap(map, _)), None) => Ok(List(map.into_values().collect(), None)),
+        (Some(Nil), None) => Ok(Nil),
+        _ => Err(LispError::Message("vals must be called with a single hash-map".to_string()))
+    }
+}
+
+// None if either is NaN
+fn compare(a: &LispType, b: &LispType) -> Result<Option<Ordering>, LispError> {
+    match (a, b) {
+        (&Integer(a), &Integer(b)) => Ok(Some(a.cmp(&b))),
+        (&Float(_), _) | (_, &Float(_)) => Ok(to_f64(a)?.partial_cmp(&to_f64(b)?)),
+        _ => Ok(Some(to_ratio(a)?.cmp(&to_ratio(b)?)))
+    }
+}
+
+// As in Clojure, comparisons take one or more arguments and are true if every
+// adjacent pair is ordered, so (< 1 2 3) is true. Comparisons involving NaN
+// are always false.
+fn compare_op<F: Fn(Ordering) -> bool>(name: &str, f: F, args: Vec<LispType>) -> LispResult {
+    if args.is_empty() {
+        return Err(LispError::Message(format!("{} called with 0 arguments", name)))
+    }
+
+    for pair in args.windows(2) {
+        match compare(&pair[0], &pair[1])? {
+            Some(ordering) if f(ordering) => {},
+            _ => return Ok(False)
         }
-    } else {
-        return Err(LispError(format!("illegal argument: {} to function which expects integers", args[1])))
     }
+    Ok(True)
 }
 
-fn lt(args: Vec<LispType>) -> LispResult { binary_bool_op(|a:i64, b:i64| { a < b }, args) }
-fn le(args: Vec<LispType>) -> LispResult { binary_bool_op(|a:i64, b:i64| { a <= b }, args) }
-fn gt(args: Vec<LispType>) -> LispResult { binary_bool_op(|a:i64, b:i64| { a > b }, args) }
-fn ge(args: Vec<LispType>) -> LispResult { binary_bool_op(|a:i64, b:i64| { a >= b }, args) }
+fn lt(args: Vec<LispType>) -> LispResult { compare_op("<", |ord| ord == Ordering::Less, args) }
+fn le(args: Vec<LispType>) -> LispResult { compare_op("<=", |ord| ord != Ordering::Greater, args) }
+fn gt(args: Vec<LispType>) -> LispResult { compare_op(">", |ord| ord == Ordering::Greater, args) }
+fn ge(args: Vec<LispType>) -> LispResult { compare_op(">=", |ord| ord != Ordering::Less, args) }
 
 fn pr_str(args: Vec<LispType>) -> LispResult {
     let mut buf = String::new();
@@ -164,3 +694,182 @@ fn println(args: Vec<LispType>) -> LispResult {
     Ok(Nil)
 }
 
+
+fn atom(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(val), None) => Ok(Atom(Rc::new(RefCell::new(val)))),
+        _ => Err(LispError::Message("atom must be called with a single argument".to_string()))
+    }
+}
+
+fn is_atom(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Atom(_)) if args.len() == 1 => Ok(True),
+        Some(_) if args.len() == 1 => Ok(False),
+        _ => Err(LispError::Message("atom? must be called with a single argument".to_string()))
+    }
+}
+
+fn deref(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Atom(ref cell)) if args.len() == 1 => Ok(cell.borrow().clone()),
+        _ => Err(LispError::Message("deref must be called with a single atom".to_string()))
+    }
+}
+
+fn reset(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(Atom(cell)), Some(val), None) => {
+            *cell.borrow_mut() = val.clone();
+            Ok(val)
+        },
+        _ => Err(LispError::Message("reset! must be called with an atom and a value".to_string()))
+    }
+}
+
+fn swap(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(Atom(cell)), Some(func)) => {
+            // don't hold the borrow while calling func, it may deref the atom too
+            let mut func_args = vec![cell.borrow().clone()];
+            func_args.extend(args_iter);
+            let val = types::apply(func, func_args)?;
+            *cell.borrow_mut() = val.clone();
+            Ok(val)
+        },
+        _ => Err(LispError::Message("swap! must be called with an atom and a function".to_string()))
+    }
+}
+
+fn meta(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&List(_, ref meta)) | Some(&Vector(_, ref meta)) | Some(&HashMap(_, ref meta)) |
+        Some(&Func(_, ref meta)) | Some(&Closure(_, ref meta)) if args.len() == 1 => {
+            Ok(meta.as_ref().map_or(Nil, |meta| (**meta).clone()))
+        },
+        Some(_) if args.len() == 1 => Ok(Nil),
+        _ => Err(LispError::Message("meta must be called with a single argument".to_string()))
+    }
+}
+
+fn with_meta(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    let (val, meta) = match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(val), Some(meta), None) => (val, Some(Rc::new(meta))),
+        _ => return Err(LispError::Message("with-meta must be called with a value and its metadata".to_string()))
+    };
+    match val {
+        List(elems, _) => Ok(List(elems, meta)),
+        Vector(elems, _) => Ok(Vector(elems, meta)),
+        HashMap(map, _) => Ok(HashMap(map, meta)),
+        Func(func, _) => Ok(Func(func, meta)),
+        Closure(closure, _) => Ok(Closure(closure, meta)),
+        misunderstood => Err(LispError::Message(format!("{} cannot have metadata", misunderstood)))
+    }
+}
+
+fn readline(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Str(ref prompt)) if args.len() == 1 => {
+            let prompt = &CString::new(prompt.clone()).unwrap();
+            match readline::readline(prompt) {
+                Ok(line) => {
+                    readline::add_history(&line);
+                    Ok(Str(str::from_utf8(line.to_bytes()).unwrap().to_string()))
+                },
+                Err(_) => Ok(Nil)
+            }
+        },
+        _ => Err(LispError::Message("readline must be called with a single string".to_string()))
+    }
+}
+
+// An optional second argument names the file the string came from, and ends up
+// in the metadata of the lists read
+fn read_string(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(Str(input)), None, None) => Ok(reader::read_str(&input)?),
+        (Some(Str(input)), Some(Str(file)), None) => Ok(reader::read_str_from(&input, Some(file))?),
+        _ => Err(LispError::Message("read-string must be called with a string and optionally a file name".to_string()))
+    }
+}
+
+fn read_all_string(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    let forms = match (args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(Str(input)), None, None) => reader::read_all(&input, None)?,
+        (Some(Str(input)), Some(Str(file)), None) => reader::read_all(&input, Some(file))?,
+        _ => return Err(LispError::Message("read-all-string must be called with a string and optionally a file name".to_string()))
+    };
+    Ok(List(forms.into_iter().map(|form| form.value).collect(), None))
+}
+
+// (load-string source name evaluator) calls evaluator on each form read from
+// source in turn, returning the last result. Errors say which form failed and
+// where, using name as the file name
+fn load_string(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    let (source, name, evaluator) = match (args_iter.next(), args_iter.next(), args_iter.next(), args_iter.next()) {
+        (Some(Str(source)), Some(Str(name)), Some(evaluator), None) => (source, name, evaluator),
+        _ => return Err(LispError::Message("load-string must be called with a string, its file name and a function".to_string()))
+    };
+
+    let forms = match reader::read_all(&source, Some(name.clone())) {
+        Ok(forms) => forms,
+        Err(err) => return Err(LispError::Message(format!("{}: {}", name, err.into_message())))
+    };
+    let mut result = Nil;
+    for (i, form) in forms.into_iter().enumerate() {
+        result = match types::apply(evaluator.clone(), vec![form.value]) {
+            Ok(val) => val,
+            Err(LispError::Message(msg)) => {
+                return Err(LispError::Message(format!("in form {} at {}:{}:{}: {}", i + 1, name, form.line, form.column, msg)))
+            },
+            Err(err) => return Err(err)
+        };
+    }
+    Ok(result)
+}
+
+// (eval form) evaluates form in the root environment
+fn eval_form(args: Vec<LispType>) -> LispResult {
+    let mut args_iter = args.into_iter();
+    match (args_iter.next(), args_iter.next()) {
+        (Some(form), None) => {
+            let (eval, env) = ROOT.with(|root| root.borrow().clone()).expect("eval called before default_environment");
+            eval(form, env)
+        },
+        _ => Err(LispError::Message("eval must be called with a single argument".to_string()))
+    }
+}
+
+// (load-file f) evaluates each form in the file f, see load-string
+fn load_file(args: Vec<LispType>) -> LispResult {
+    let source = slurp(args.clone())?;
+    load_string(vec![source, args[0].clone(), Func(eval_form, None)])
+}
+
+fn slurp(args: Vec<LispType>) -> LispResult {
+    match args.first() {
+        Some(&Str(ref filename)) if args.len() == 1 => {
+            let mut buf = String::new();
+            match File::open(filename).and_then(|mut file| file.read_to_string(&mut buf)) {
+                Ok(_) => Ok(Str(buf)),
+                Err(err) => Err(LispError::Message(format!("could not read {}: {}", filename, err)))
+            }
+        },
+        _ => Err(LispError::Message("slurp must be called with a single string".to_string()))
+    }
+}
+
+fn time_ms(args: Vec<LispType>) -> LispResult {
+    if !args.is_empty() {
+        return Err(LispError::Message("time-ms must be called with no arguments".to_string()))
+    }
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Ok(Integer(elapsed.as_secs() as i64 * 1000 + elapsed.subsec_nanos() as i64 / 1000000))
+}
@@ -1,61 +1,113 @@
 #![allow(dead_code)]
-use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::rc::Rc;
 use std::cell::RefCell;
 
 use types::{LispType, LispError, LispResult};
 use types::LispType::*;
+use intern::{SymbolId, SymbolMap, AMPERSAND};
 
 #[derive(Debug, Clone)]
 pub struct Environment {
-    symbols: RefCell<HashMap<String, LispType>>,
+    // A frame has a slot for each of names, which is shared by every frame
+    // made for the same fn* or let* so that resolved code (see resolve.rs)
+    // can refer to a slot by its index
+    names: Rc<Vec<SymbolId>>,
+    slots: RefCell<Vec<Option<LispType>>>,
+    // The root environment, and anything set that the frame has no slot for
+    symbols: RefCell<SymbolMap<LispType>>,
     outer_env: Option<Rc<Environment>>
 }
 impl Environment {
-    pub fn set(&self, symbol: &str, value: LispType) {
-        self.symbols.borrow_mut().insert(symbol.to_string(), value);
+    // Takes a name too, for setting up the default environment
+    pub fn set<S: Into<SymbolId>>(&self, symbol: S, value: LispType) {
+        let symbol = symbol.into();
+        match self.names.iter().position(|&name| name == symbol) {
+            Some(slot) => self.slots.borrow_mut()[slot] = Some(value),
+            None => { self.symbols.borrow_mut().insert(symbol, value); }
+        }
     }
 
-    pub fn get(&self, symbol: &str) -> LispResult {
-        if let Some(value) = self.symbols.borrow().get(symbol) {
-            Ok(value.clone())
-        } else if let Some(ref env) = self.outer_env {
-            env.get(symbol)
-        } else {
-            Err(LispError(format!("{} not found", symbol)))
+    pub fn get(&self, symbol: SymbolId) -> LispResult {
+        let mut env = self;
+        loop {
+            if let Some(value) = env.lookup(symbol) {
+                return Ok(value)
+            }
+            match env.outer_env {
+                Some(ref outer) => env = outer,
+                None => return Err(LispError::Message(format!("'{}' not found", symbol)))
+            }
+        }
+    }
+
+    fn lookup(&self, symbol: SymbolId) -> Option<LispType> {
+        if let Some(slot) = self.names.iter().position(|&name| name == symbol) {
+            if let Some(ref value) = self.slots.borrow()[slot] {
+                return Some(value.clone())
+            }
+        }
+        self.symbols.borrow().get(&symbol).cloned()
+    }
+
+    // The value in a slot of the frame depth levels out, or None if that slot
+    // hasn't been bound yet (a def! that hasn't run)
+    pub fn get_local(&self, depth: usize, slot: usize) -> Option<LispType> {
+        let mut env = self;
+        for _ in 0..depth {
+            match env.outer_env {
+                Some(ref outer) => env = outer,
+                None => return None
+            }
+        }
+        match env.slots.borrow().get(slot) {
+            Some(&Some(ref value)) => Some(value.clone()),
+            _ => None
         }
     }
 
     pub fn new(outer_env: Option<Rc<Environment>>) -> Environment {
-        Environment { symbols: RefCell::new(HashMap::new()), outer_env: outer_env }
+        Environment::with_names(outer_env, Rc::new(Vec::new()))
+    }
+
+    pub fn with_names(outer_env: Option<Rc<Environment>>, names: Rc<Vec<SymbolId>>) -> Environment {
+        let slots = vec![None; names.len()];
+        Environment { names, slots: RefCell::new(slots), symbols: RefCell::new(SymbolMap::default()), outer_env }
+    }
+
+    // The slot names for a closure's frames: its parameters, less the &
+    pub fn param_names(binds: &[LispType]) -> Rc<Vec<SymbolId>> {
+        Rc::new(binds.iter().filter_map(|bind| match *bind {
+            Symbol(sym) if sym != AMPERSAND => Some(sym),
+            _ => None
+        }).collect())
     }
 
-    pub fn with_bindings(outer_env: Option<Rc<Environment>>, binds: Vec<LispType>, exprs: Vec<LispType>) -> Result<Environment, LispError> {
-        let mut symbols = HashMap::with_capacity(binds.len());
+    pub fn with_bindings(outer_env: Option<Rc<Environment>>, names: Rc<Vec<SymbolId>>, binds: Vec<LispType>, exprs: Vec<LispType>) -> Result<Environment, LispError> {
+        let env = Environment::with_names(outer_env, names);
         let mut binds_it = binds.into_iter();
         let mut exprs_it = exprs.into_iter();
 
         loop {
             match binds_it.next() {
                 Some(Symbol(sym)) => {
-                    if sym == "&" {
+                    if sym == AMPERSAND {
                         if let Some(Symbol(more)) = binds_it.next() {
-                            symbols.insert(more, List(Vec::from_iter(exprs_it)));
+                            env.set(more, List(Vec::from_iter(exprs_it), None));
                             break;
                         } else {
-                            return Err(LispError("binding after & must be a symbol".to_string()))
+                            return Err(LispError::Message("binding after & must be a symbol".to_string()))
                         }
                     } else if let Some(value) = exprs_it.next() {
-                        symbols.insert(sym, value);
+                        env.set(sym, value);
                     } else {
-                        return Err(LispError("Closure called with incorrect number of arguments".to_string()));
+                        return Err(LispError::Message("Closure called with incorrect number of arguments".to_string()));
                     }
                 },
-                Some(_) => return Err(LispError("Closure with non-symbol bindings".to_string())),
+                Some(_) => return Err(LispError::Message("Closure with non-symbol bindings".to_string())),
                 None => {
-                    if let Some(_) = exprs_it.next() {
-                        return Err(LispError("Closure called with incorrect number of arguments".to_string()));
+                    if exprs_it.next().is_some() {
+                        return Err(LispError::Message("Closure called with incorrect number of arguments".to_string()));
                     } else {
                         break;
                     }
@@ -63,6 +115,6 @@ impl Environment {
             }
         }
 
-        Ok(Environment { symbols: RefCell::new(symbols), outer_env: outer_env })
+        Ok(env)
     }
 }
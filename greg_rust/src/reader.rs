@@ -1,27 +1,70 @@
+#![allow(dead_code)]
+
 use std::result::Result;
+use std::collections;
+use std::rc::Rc;
+use std::iter::Peekable;
+use std::str::CharIndices;
 
-use types::LispType;
+use types;
+use types::{LispType, LispError, Depth};
 use types::LispType::*;
+use bignum::{BigInt, Ratio};
+use intern::SymbolId;
 
 #[derive(Debug)]
-pub struct ParseError(String);
+pub enum ParseError {
+    Invalid(String),
+    // The input ran out inside a form, so more input could complete it
+    Incomplete(String)
+}
 pub type ParseResult = Result<LispType, ParseError>;
 
+impl ParseError {
+    pub fn into_message(self) -> String {
+        match self {
+            ParseError::Invalid(msg) | ParseError::Incomplete(msg) => msg
+        }
+    }
+}
+
+impl From<ParseError> for LispError {
+    fn from(err: ParseError) -> LispError {
+        LispError::Message(err.into_message())
+    }
+}
+
+// A top-level form and where it starts in the input
+pub struct Form {
+    pub value: LispType,
+    pub line: usize,
+    pub column: usize
+}
+
+struct Token {
+    text: String,
+    #[allow(dead_code)]
+    offset: usize, // in bytes, from the start of the input
+    line: usize,
+    column: usize
+}
+
 struct Reader {
-    tokens: Vec<String>,
-    position: usize
+    tokens: Vec<Token>,
+    position: usize,
+    file: Option<String>
 }
 
 impl Reader {
-    fn new(tokens: Vec<String>) -> Reader {
-        Reader { tokens: tokens, position: 0 }
+    fn new(tokens: Vec<Token>, file: Option<String>) -> Reader {
+        Reader { tokens, position: 0, file }
     }
 
     fn peek(&self) -> Result<&str, ParseError> {
         if self.position < self.tokens.len() {
-            Ok(&self.tokens[self.position])
+            Ok(&self.tokens[self.position].text)
         } else {
-            Err(ParseError("expected more input but got EOF".to_string()))
+            Err(ParseError::Incomplete("expected more input but got EOF".to_string()))
         }
     }
 
@@ -29,66 +72,295 @@ impl Reader {
         if self.position < self.tokens.len() {
             let token = &self.tokens[self.position];
             self.position += 1;
-            Ok(token)
+            Ok(&token.text)
         } else {
-            Err(ParseError("expected more input but got EOF".to_string()))
+            Err(ParseError::Incomplete("expected more input but got EOF".to_string()))
+        }
+    }
+
+    // The next token in a form opened at start, which the input must not end
+    // before closing
+    fn peek_within(&self, open: &str, start: &str) -> Result<&str, ParseError> {
+        self.peek().map_err(|_| ParseError::Incomplete(format!("unclosed '{}' at {}", open, start)))
+    }
+
+    // "line:column" of the next token, for error messages
+    fn line_column(&self) -> String {
+        let token = &self.tokens[self.position];
+        format!("{}:{}", token.line, token.column)
+    }
+
+    // Metadata describing where the next token was read from
+    fn location(&self) -> LispType {
+        let token = &self.tokens[self.position];
+        let mut map = collections::HashMap::with_capacity(3);
+        map.insert(Keyword(":line".to_string()), Integer(token.line as i64));
+        map.insert(Keyword(":column".to_string()), Integer(token.column as i64));
+        if let Some(ref file) = self.file {
+            map.insert(Keyword(":file".to_string()), Str(file.clone()));
         }
+        HashMap(map, None)
+    }
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Lexer<'a> {
+        Lexer { input, chars: input.char_indices().peekable(), line: 1, column: 1 }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn offset(&mut self) -> usize {
+        let len = self.input.len();
+        self.chars.peek().map_or(len, |&(i, _)| i)
     }
+
+    fn bump(&mut self) -> Option<char> {
+        let next = self.chars.next().map(|(_, c)| c);
+        match next {
+            Some('\n') => { self.line += 1; self.column = 1; },
+            Some(_) => self.column += 1,
+            None => ()
+        }
+        next
+    }
+
+    // Consume characters while pred holds, appending them to buf
+    fn take_while<F: Fn(char) -> bool>(&mut self, buf: &mut String, pred: F) {
+        while let Some(c) = self.peek() {
+            if !pred(c) {
+                break;
+            }
+            buf.push(c);
+            self.bump();
+        }
+    }
+}
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || "[]{}()'\"`,;".contains(c)
 }
 
-fn tokenize(input: &str) -> Vec<String> {
-    let re = regex!(r#"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"|;.*|[^\t\n\v\f\r \[\]{}('"`,;)]+)"#);
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut lexer = Lexer::new(input);
     let mut tokens = Vec::new();
 
-    for cap in re.captures_iter(input) {
-        tokens.push(cap.at(1).unwrap().to_string())
+    loop {
+        lexer.take_while(&mut String::new(), |c| c.is_whitespace() || c == ',');
+
+        let offset = lexer.offset();
+        let (line, column) = (lexer.line, lexer.column);
+        let mut text = String::new();
+        match lexer.bump() {
+            None => break,
+            Some(';') => {
+                lexer.take_while(&mut String::new(), |c| c != '\n');
+                continue;
+            },
+            Some('~') if lexer.peek() == Some('@') => {
+                lexer.bump();
+                text.push_str("~@");
+            },
+            Some(c @ '[') | Some(c @ ']') | Some(c @ '{') | Some(c @ '}') | Some(c @ '(') | Some(c @ ')') |
+            Some(c @ '\'') | Some(c @ '`') | Some(c @ '~') | Some(c @ '^') | Some(c @ '@') => text.push(c),
+            Some('"') => {
+                text.push('"');
+                loop {
+                    match lexer.bump() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            match lexer.bump() {
+                                Some(c) => text.push(c),
+                                None => return Err(ParseError::Incomplete(format!("unterminated string at {}:{}", line, column)))
+                            }
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(ParseError::Incomplete(format!("unterminated string at {}:{}", line, column)))
+                    }
+                }
+                text.push('"');
+            },
+            Some(c) => {
+                text.push(c);
+                lexer.take_while(&mut text, |c| !is_special(c));
+            }
+        }
+
+        tokens.push(Token { text, offset, line, column });
     }
 
-    tokens
+    Ok(tokens)
 }
 
 pub fn read_str(input: &str) -> ParseResult {
-    let tokens = tokenize(input);
-    let mut reader = Reader::new(tokens);
+    read_str_from(input, None)
+}
+
+// Like read_str, but records the file name in the metadata of each list
+pub fn read_str_from(input: &str, file: Option<String>) -> ParseResult {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err(ParseError::Invalid("expected a form but got nothing".to_string()))
+    }
+    let mut reader = Reader::new(tokens, file);
     read_form(&mut reader)
 }
 
+// Reads every form in input, rather than just the first like read_str
+pub fn read_all(input: &str, file: Option<String>) -> Result<Vec<Form>, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut reader = Reader::new(tokens, file);
+    let mut forms = Vec::new();
+    while reader.position < reader.tokens.len() {
+        let (line, column) = {
+            let token = &reader.tokens[reader.position];
+            (token.line, token.column)
+        };
+        let value = read_form(&mut reader)?;
+        forms.push(Form { value, line, column });
+    }
+    Ok(forms)
+}
+
 fn read_form(reader: &mut Reader) -> ParseResult {
-    match try!(reader.peek()) {
+    let _depth = Depth::enter().map_err(|_| ParseError::Invalid("stack overflow".to_string()))?;
+    match reader.peek()? {
         "(" => read_list(reader),
         "[" => read_vector(reader),
+        "{" => read_hash_map(reader),
+        "'" => read_macro(reader, "quote"),
+        "`" => read_macro(reader, "quasiquote"),
+        "~" => read_macro(reader, "unquote"),
+        "~@" => read_macro(reader, "splice-unquote"),
+        "@" => read_macro(reader, "deref"),
+        "^" => read_meta(reader),
+        ")" | "]" | "}" => Err(ParseError::Invalid(format!("unexpected '{}' at {}", reader.peek()?, reader.line_column()))),
         _ => read_atom(reader)
     }
 }
 
+fn read_macro(reader: &mut Reader, symbol: &str) -> ParseResult {
+    reader.next().unwrap(); // skip the macro character
+
+    let form = read_form(reader)?;
+    Ok(List(vec![Symbol(SymbolId::intern(symbol)), form], None))
+}
+
+fn read_meta(reader: &mut Reader) -> ParseResult {
+    reader.next().unwrap(); // skip the "^"
+
+    let meta = read_form(reader)?;
+    let form = read_form(reader)?;
+    Ok(List(vec![Symbol(SymbolId::intern("with-meta")), form, meta], None))
+}
+
 fn read_list(reader: &mut Reader) -> ParseResult {
+    let location = reader.location();
+    let start = reader.line_column();
     reader.next().unwrap(); // skip the opening "("
 
     let mut elems = Vec::new();
-    while try!(reader.peek()) != ")" {
-        elems.push(try!(read_form(reader)));
+    while reader.peek_within("(", &start)? != ")" {
+        elems.push(read_form(reader)?);
     }
 
     reader.next().unwrap(); // skip the trailing ")"
 
-    Ok(List(elems))
+    Ok(List(elems, Some(Rc::new(location))))
 }
 
 fn read_vector(reader: &mut Reader) -> ParseResult {
+    let start = reader.line_column();
     reader.next().unwrap(); // skip the opening "["
 
     let mut elems = Vec::new();
-    while try!(reader.peek()) != "]" {
-        elems.push(try!(read_form(reader)));
+    while reader.peek_within("[", &start)? != "]" {
+        elems.push(read_form(reader)?);
     }
 
     reader.next().unwrap(); // skip the trailing "]"
 
-    Ok(Vector(elems))
+    Ok(Vector(elems, None))
+}
+
+fn read_hash_map(reader: &mut Reader) -> ParseResult {
+    let start = reader.line_column();
+    reader.next().unwrap(); // skip the opening "{"
+
+    let mut elems = Vec::new();
+    while reader.peek_within("{", &start)? != "}" {
+        elems.push(read_form(reader)?);
+    }
+
+    reader.next().unwrap(); // skip the trailing "}"
+
+    let mut map = collections::HashMap::with_capacity(elems.len() / 2);
+    match types::assoc_pairs(&mut map, elems) {
+        Ok(()) => Ok(HashMap(map, None)),
+        Err(msg) => Err(ParseError::Invalid(msg))
+    }
+}
+
+// The numerator and denominator of a ratio like 1/3 or -22/7
+fn split_ratio(token: &str) -> Option<(BigInt, BigInt)> {
+    let slash = token.find('/')?;
+    let denom = &token[slash + 1..];
+    if !denom.bytes().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+    match (BigInt::parse(&token[..slash]), BigInt::parse(denom)) {
+        (Some(numer), Some(denom)) => Some((numer, denom)),
+        _ => None
+    }
+}
+
+// Digits with a fraction, an exponent or both, like 1.5, -2e10 or 1.5E-3.
+// Rust's float parsing alone would also accept things like inf and nan,
+// which are symbols here.
+fn is_float(token: &str) -> bool {
+    fn digits(s: &str) -> usize {
+        s.bytes().take_while(|b| b.is_ascii_digit()).count()
+    }
+
+    let s = token.strip_prefix('-').or_else(|| token.strip_prefix('+')).unwrap_or(token);
+    let int_len = digits(s);
+    if int_len == 0 {
+        return false
+    }
+    let mut rest = &s[int_len..];
+    let mut seen_fraction_or_exponent = false;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let fraction_len = digits(fraction);
+        if fraction_len == 0 {
+            return false
+        }
+        rest = &fraction[fraction_len..];
+        seen_fraction_or_exponent = true;
+    }
+    if let Some(exponent) = rest.strip_prefix('e').or_else(|| rest.strip_prefix('E')) {
+        let exponent = exponent.strip_prefix('-').or_else(|| exponent.strip_prefix('+')).unwrap_or(exponent);
+        let exponent_len = digits(exponent);
+        if exponent_len == 0 {
+            return false
+        }
+        rest = &exponent[exponent_len..];
+        seen_fraction_or_exponent = true;
+    }
+    seen_fraction_or_exponent && rest.is_empty()
 }
 
 fn read_atom(reader: &mut Reader) -> ParseResult {
-    let token = try!(reader.next());
+    let token = reader.next()?;
     if token == "nil" {
         Ok(Nil)
     } else if token == "true" {
@@ -97,6 +369,21 @@ fn read_atom(reader: &mut Reader) -> ParseResult {
         Ok(False)
     } else if let Ok(int) = token.parse::<i64>() {
         Ok(Integer(int))
+    } else if let Some(big) = BigInt::parse(token) {
+        Ok(BigInteger(big))
+    } else if let Some((numer, denom)) = split_ratio(token) {
+        match Ratio::new(numer, denom) {
+            Some(ratio) => Ok(types::rational(ratio)),
+            None => Err(ParseError::Invalid(format!("divide by zero in ratio {}", token)))
+        }
+    } else if is_float(token) {
+        Ok(Float(token.parse().unwrap()))
+    } else if token == "##Inf" {
+        Ok(Float(f64::INFINITY))
+    } else if token == "##-Inf" {
+        Ok(Float(f64::NEG_INFINITY))
+    } else if token == "##NaN" {
+        Ok(Float(f64::NAN))
     } else if let Some('"') = token.chars().next() {
         let mut chars = token.chars();
         chars.next(); // skip leading "
@@ -121,6 +408,6 @@ fn read_atom(reader: &mut Reader) -> ParseResult {
     } else if let Some(':') = token.chars().next() {
         Ok(Keyword(token.to_string()))
     } else {
-        Ok(Symbol(token.to_string()))
+        Ok(Symbol(SymbolId::intern(token)))
     }
 }
@@ -0,0 +1,232 @@
+// Lexical addressing for the stepA evaluator
+//
+// The first time a fn* is evaluated its body is walked once, and each symbol
+// bound by a fn*, let* or catch* inside it becomes a Local saying how many
+// frames out the binding is and which slot it has there, so evaluating it
+// doesn't mean a string lookup in every frame on the way to the root.
+// Anything else, globals included, stays a Symbol and is looked up by name.
+//
+// A frame has a slot for each parameter or let* binding, and for each name
+// def!'d directly in the body. The def!'d names are found before the body is
+// resolved, so a symbol that comes before its def!, e.g. in a closure made
+// earlier, still stops at the frame the def! binds in. Until a slot is bound,
+// e.g. by a def! that hasn't run yet, the name is looked up instead, so the
+// result is always the same as the plain lookup would give.
+//
+// Quoted and quasiquoted forms are data and left alone. Macro arguments can't
+// be told apart from function arguments ahead of time so they are resolved
+// too, and strip turns them back into what was read before the macro runs.
+
+use std::rc::Rc;
+
+use types::{LispType, Depth};
+use types::LispType::*;
+use env::Environment;
+use intern::{SymbolId, DEF, DEFMACRO, FN, LET, TRY, CATCH, QUOTE, QUASIQUOTE, MACROEXPAND};
+
+// The slot names of the frames around the form being resolved, innermost
+// last.
+type Scopes = Vec<Vec<SymbolId>>;
+
+// Resolves the body of a fn* that is being evaluated for the first time,
+// giving the slot names for its frames along with it. The frames outside it
+// aren't known, so anything it doesn't bind itself stays a Symbol.
+pub fn resolve_fn(binds: &[LispType], body: &LispType) -> (Rc<Vec<SymbolId>>, LispType) {
+    let mut names = Environment::param_names(binds).to_vec();
+    declare_defs(body, &mut names);
+    let mut scopes = vec![names];
+    let body = resolve(body, &mut scopes);
+    (Rc::new(scopes.pop().unwrap()), body)
+}
+
+fn resolve(ast: &LispType, scopes: &mut Scopes) -> LispType {
+    // Too deep a form is left as it is, to be looked up by name
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => return ast.clone()
+    };
+    match *ast {
+        Symbol(sym) => local(sym, scopes).unwrap_or_else(|| ast.clone()),
+        List(ref values, ref meta) => {
+            let form = match values.first() {
+                Some(&Symbol(sym)) => Some(sym),
+                _ => None
+            };
+            let resolved = match form {
+                Some(QUOTE) | Some(QUASIQUOTE) | Some(MACROEXPAND) => None,
+                Some(DEF) | Some(DEFMACRO) => resolve_def(values, scopes),
+                Some(FN) => resolve_fn_form(values, scopes),
+                Some(LET) => resolve_let(values, scopes),
+                Some(TRY) => resolve_try(values, scopes),
+                _ => Some(resolve_all(values, scopes))
+            };
+            match resolved {
+                Some(values) => List(values, meta.clone()),
+                None => ast.clone()
+            }
+        },
+        Vector(ref values, ref meta) => Vector(resolve_all(values, scopes), meta.clone()),
+        HashMap(ref map, ref meta) => {
+            HashMap(map.iter().map(|(key, val)| (key.clone(), resolve(val, scopes))).collect(), meta.clone())
+        },
+        ref anything => anything.clone()
+    }
+}
+
+fn resolve_all(values: &[LispType], scopes: &mut Scopes) -> Vec<LispType> {
+    values.iter().map(|val| resolve(val, scopes)).collect()
+}
+
+fn local(sym: SymbolId, scopes: &Scopes) -> Option<LispType> {
+    for (depth, names) in scopes.iter().rev().enumerate() {
+        if let Some(slot) = names.iter().position(|&name| name == sym) {
+            return Some(Local(sym, depth, slot))
+        }
+    }
+    None
+}
+
+fn declare(sym: SymbolId, names: &mut Vec<SymbolId>) {
+    if !names.contains(&sym) {
+        names.push(sym);
+    }
+}
+
+// Declares the names ast def!s into the frame it's evaluated in, skipping the
+// forms that make frames of their own
+fn declare_defs(ast: &LispType, names: &mut Vec<SymbolId>) {
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => return
+    };
+    let values = match *ast {
+        List(ref values, _) | Vector(ref values, _) => values,
+        HashMap(ref map, _) => {
+            for val in map.values() {
+                declare_defs(val, names);
+            }
+            return
+        },
+        _ => return
+    };
+    match values.first() {
+        Some(&Symbol(QUOTE)) | Some(&Symbol(MACROEXPAND)) | Some(&Symbol(FN)) | Some(&Symbol(LET)) => return,
+        Some(&Symbol(DEF)) | Some(&Symbol(DEFMACRO)) if values.len() == 3 => {
+            if let Symbol(sym) = values[1] {
+                declare(sym, names);
+            }
+        },
+        // The catch* clause binds in a frame of its own
+        Some(&Symbol(TRY)) if values.len() == 3 => return declare_defs(&values[1], names),
+        _ => ()
+    }
+    for val in values.iter() {
+        declare_defs(val, names);
+    }
+}
+
+// The special forms below give None when malformed, leaving them as they were
+// for eval to report on
+
+fn resolve_def(values: &[LispType], scopes: &mut Scopes) -> Option<Vec<LispType>> {
+    let mut values_iter = values.iter();
+    match (values_iter.next(), values_iter.next(), values_iter.next(), values_iter.next()) {
+        (Some(def), Some(&Symbol(sym)), Some(val), None) => {
+            // Declared before the value is resolved so that a local function
+            // can call itself
+            if let Some(names) = scopes.last_mut() {
+                declare(sym, names);
+            }
+            Some(vec![def.clone(), Symbol(sym), resolve(val, scopes)])
+        },
+        _ => None
+    }
+}
+
+fn resolve_fn_form(values: &[LispType], scopes: &mut Scopes) -> Option<Vec<LispType>> {
+    let mut values_iter = values.iter();
+    match (values_iter.next(), values_iter.next(), values_iter.next(), values_iter.next()) {
+        (Some(_), Some(&List(ref binds, _)), Some(body), None) | (Some(_), Some(&Vector(ref binds, _)), Some(body), None) => {
+            let mut names = Environment::param_names(binds).to_vec();
+            declare_defs(body, &mut names);
+            scopes.push(names);
+            let body = resolve(body, scopes);
+            let names = scopes.pop().unwrap();
+            Some(vec![Binder(FN, Rc::new(names)), values[1].clone(), body])
+        },
+        _ => None
+    }
+}
+
+fn resolve_let(values: &[LispType], scopes: &mut Scopes) -> Option<Vec<LispType>> {
+    let mut values_iter = values.iter();
+    let (bindings, body) = match (values_iter.next(), values_iter.next(), values_iter.next(), values_iter.next()) {
+        (Some(_), Some(&List(ref bindings, _)), Some(body), None) | (Some(_), Some(&Vector(ref bindings, _)), Some(body), None) => (bindings, body),
+        _ => return None
+    };
+    if bindings.len() % 2 != 0 {
+        return None
+    }
+    let mut names = Vec::new();
+    for sym in bindings.iter().step_by(2) {
+        match *sym {
+            Symbol(sym) => declare(sym, &mut names),
+            _ => return None
+        }
+    }
+    for val in bindings.iter().skip(1).step_by(2) {
+        declare_defs(val, &mut names);
+    }
+    declare_defs(body, &mut names);
+    // The values are evaluated in the new frame, each seeing the bindings
+    // before it
+    scopes.push(names);
+    let mut resolved = Vec::with_capacity(bindings.len());
+    for pair in bindings.chunks(2) {
+        resolved.push(pair[0].clone());
+        resolved.push(resolve(&pair[1], scopes));
+    }
+    let body = resolve(body, scopes);
+    let names = scopes.pop().unwrap();
+    let resolved = match values[1] {
+        List(_, ref meta) => List(resolved, meta.clone()),
+        Vector(_, ref meta) => Vector(resolved, meta.clone()),
+        _ => unreachable!()
+    };
+    Some(vec![Binder(LET, Rc::new(names)), resolved, body])
+}
+
+fn resolve_try(values: &[LispType], scopes: &mut Scopes) -> Option<Vec<LispType>> {
+    let mut values_iter = values.iter();
+    match (values_iter.next(), values_iter.next(), values_iter.next(), values_iter.next()) {
+        (Some(_), Some(_), None, None) => Some(resolve_all(values, scopes)),
+        (Some(try_sym), Some(val), Some(&List(ref catch, ref meta)), None) => {
+            let mut catch_iter = catch.iter();
+            match (catch_iter.next(), catch_iter.next(), catch_iter.next(), catch_iter.next()) {
+                (Some(&Symbol(CATCH)), Some(&Symbol(exc)), Some(handler), None) => {
+                    let val = resolve(val, scopes);
+                    let mut names = vec![exc];
+                    declare_defs(handler, &mut names);
+                    scopes.push(names);
+                    let handler = resolve(handler, scopes);
+                    let names = scopes.pop().unwrap();
+                    let catch = List(vec![Binder(CATCH, Rc::new(names)), Symbol(exc), handler], meta.clone());
+                    Some(vec![try_sym.clone(), val, catch])
+                },
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+// Turns resolved code back into what was read, for handing to a macro
+pub fn strip(ast: LispType) -> LispType {
+    match ast {
+        Local(sym, _, _) | Binder(sym, _) => Symbol(sym),
+        List(values, meta) => List(values.into_iter().map(strip).collect(), meta),
+        Vector(values, meta) => Vector(values.into_iter().map(strip).collect(), meta),
+        HashMap(map, meta) => HashMap(map.into_iter().map(|(key, val)| (key, strip(val))).collect(), meta),
+        anything => anything
+    }
+}
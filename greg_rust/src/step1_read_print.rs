@@ -1,6 +1,3 @@
-#![feature(plugin)]
-#![plugin(regex_macros)]
-extern crate regex;
 extern crate readline;
 
 use std::str;
@@ -10,6 +7,8 @@ use types::LispType;
 
 mod reader;
 mod types;
+mod intern;
+mod bignum;
 mod env;
 
 fn read(input: &str) -> reader::ParseResult {
@@ -32,6 +31,10 @@ fn rep(input: &str) -> String {
 }
 
 fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
     let prompt = &CString::new("user> ").unwrap();
     loop {
         match readline::readline(prompt) {
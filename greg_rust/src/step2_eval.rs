@@ -1,9 +1,7 @@
-#![feature(plugin, collections)]
-#![plugin(regex_macros)]
-extern crate regex;
 extern crate readline;
 
 use std::rc::Rc;
+use std::collections;
 use std::str;
 use std::ffi::CString;
 
@@ -13,6 +11,8 @@ use env::Environment;
 
 mod reader;
 mod types;
+mod intern;
+mod bignum;
 mod env;
 mod core;
 
@@ -22,29 +22,43 @@ fn read(input: &str) -> reader::ParseResult {
 
 fn eval_ast(ast: LispType, env: Rc<Environment>) -> LispResult {
     match ast {
-        Symbol(sym) => env.get(&sym),
-        List(values) => {
+        Symbol(sym) => env.get(sym),
+        List(values, _) => {
             let mut evalues = Vec::with_capacity(values.len());
             for val in values.into_iter() {
-                evalues.push(try!(eval(val, env.clone())))
+                evalues.push(eval(val, env.clone())?)
             }
-            Ok(List(evalues))
+            Ok(List(evalues, None))
+        }
+        Vector(values, _) => {
+            let mut evalues = Vec::with_capacity(values.len());
+            for val in values.into_iter() {
+                evalues.push(eval(val, env.clone())?)
+            }
+            Ok(Vector(evalues, None))
+        }
+        HashMap(map, _) => {
+            let mut emap = collections::HashMap::with_capacity(map.len());
+            for (key, val) in map.into_iter() {
+                emap.insert(key, eval(val, env.clone())?);
+            }
+            Ok(HashMap(emap, None))
         }
         anything => Ok(anything)
     }
 }
 
 fn eval(ast: LispType, env: Rc<Environment>) -> LispResult {
-    if let List(values) = ast {
-        match try!(eval_ast(List(values), env)) {
-            List(mut values) => {
+    if let List(values, _) = ast {
+        match eval_ast(List(values, None), env)? {
+            List(mut values, _) => {
                 if values.is_empty() {
-                    return Err(LispError("tried to evaluate a list with no function".to_string()))
+                    return Err(LispError::Message("tried to evaluate a list with no function".to_string()))
                 }
                 let args = values.split_off(1);
                 match values[0] {
-                    Func(func) => func(args),
-                    ref misunderstood => return Err(LispError(format!("{} is not a function, cannot evaluate it", misunderstood)))
+                    Func(func, _) => func(args),
+                    ref misunderstood => return Err(LispError::Message(format!("{} is not a function, cannot evaluate it", misunderstood)))
                 }
             },
             _ => unreachable!()
@@ -62,7 +76,7 @@ fn rep(input: &str) -> String {
     match read(input) {
         Err(err) => format!("error: {:?}", err),
         Ok(ast) => {
-            match eval(ast, core::default_environment()) {
+            match eval(ast, core::default_environment(eval)) {
                 Err(err) => format!("error: {:?}", err),
                 Ok(ast) => print(ast)
             }
@@ -71,6 +85,10 @@ fn rep(input: &str) -> String {
 }
 
 fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
     let prompt = &CString::new("user> ").unwrap();
     loop {
         match readline::readline(prompt) {
@@ -1,18 +1,19 @@
-#![feature(plugin, collections)]
-#![plugin(regex_macros)]
-extern crate regex;
 extern crate readline;
 
 use std::rc::Rc;
+use std::collections;
 use std::str;
 use std::ffi::CString;
 
 use types::{LispType, LispError, LispResult};
 use types::LispType::*;
 use env::Environment;
+use intern::{DEF, LET};
 
 mod reader;
 mod types;
+mod intern;
+mod bignum;
 mod env;
 mod core;
 
@@ -22,13 +23,27 @@ fn read(input: &str) -> reader::ParseResult {
 
 fn eval_ast(ast: LispType, env: Rc<Environment>) -> LispResult {
     match ast {
-        Symbol(sym) => env.get(&sym),
-        List(values) => {
+        Symbol(sym) => env.get(sym),
+        List(values, _) => {
             let mut evalues = Vec::with_capacity(values.len());
             for val in values.into_iter() {
-                evalues.push(try!(eval(val, env.clone())))
+                evalues.push(eval(val, env.clone())?)
             }
-            Ok(List(evalues))
+            Ok(List(evalues, None))
+        }
+        Vector(values, _) => {
+            let mut evalues = Vec::with_capacity(values.len());
+            for val in values.into_iter() {
+                evalues.push(eval(val, env.clone())?)
+            }
+            Ok(Vector(evalues, None))
+        }
+        HashMap(map, _) => {
+            let mut emap = collections::HashMap::with_capacity(map.len());
+            for (key, val) in map.into_iter() {
+                emap.insert(key, eval(val, env.clone())?);
+            }
+            Ok(HashMap(emap, None))
         }
         anything => Ok(anything)
     }
@@ -39,11 +54,11 @@ fn set_bindings(bindings: Vec<LispType>, env: Rc<Environment>) -> LispResult {
     loop {
         match (it.next(), it.next()) {
             (Some(Symbol(sym)), Some(val)) => {
-                let val = try!(eval(val, env.clone()));
-                env.set(&sym, val);
+                let val = eval(val, env.clone())?;
+                env.set(sym, val);
             }
-            (Some(_), Some(_)) => return Err(LispError("First binding argument must be a symbol".to_string())),
-            (Some(_), None) => return Err(LispError("Binding arguments must have even length".to_string())),
+            (Some(_), Some(_)) => return Err(LispError::Message("First binding argument must be a symbol".to_string())),
+            (Some(_), None) => return Err(LispError::Message("Binding arguments must have even length".to_string())),
             (None, None) => return Ok(Nil),
             _ => unreachable!()
         }
@@ -51,45 +66,50 @@ fn set_bindings(bindings: Vec<LispType>, env: Rc<Environment>) -> LispResult {
 }
 
 fn eval(ast: LispType, env: Rc<Environment>) -> LispResult {
-    if let List(mut values) = ast {
+    if let List(mut values, _) = ast {
         if values.is_empty() {
-            return Err(LispError("tried to evaluate a list with no function".to_string()))
+            return Err(LispError::Message("tried to evaluate a list with no function".to_string()))
         }
         let args = values.split_off(1);
         let arg0 = values.into_iter().next().unwrap();
 
-        if arg0 == Symbol("def!".to_string()) {
-            let mut args_iter = args.into_iter();
-            match (args_iter.next(), args_iter.next(), args_iter.next()) {
-                (Some(Symbol(sym)), Some(val), None) => {
-                    let val = try!(eval(val, env.clone()));
-                    env.set(&sym, val.clone());
-                    Ok(val)
-                },
-                _ => Err(LispError("def! must be called with 2 args, a symbol and a value".to_string())),
-            }
-        } else if arg0 == Symbol("let*".to_string()) {
-            let let_env = Rc::new(Environment::new(Some(env.clone())));
-            let mut args_iter = args.into_iter();
-            if let Some(List(bindings)) = args_iter.next() {
-                try!(set_bindings(bindings, let_env.clone()));
-                match args_iter.next() {
-                    Some(val) => eval(val, let_env),
-                    None => Err(LispError("let* must be called with a second argument to evaluate".to_string()))
+        let form = match arg0 { Symbol(sym) => Some(sym), _ => None };
+        match form {
+            Some(DEF) => {
+                let mut args_iter = args.into_iter();
+                match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                    (Some(Symbol(sym)), Some(val), None) => {
+                        let val = eval(val, env.clone())?;
+                        env.set(sym, val.clone());
+                        Ok(val)
+                    },
+                    _ => Err(LispError::Message("def! must be called with 2 args, a symbol and a value".to_string())),
                 }
-            } else {
-                Err(LispError("let* must be called with a list of bindings as the first argument".to_string()))
-            }
-        } else {
-            match try!(eval_ast(arg0, env.clone())) {
-                Func(func) => {
-                    if let List(args) = try!(eval_ast(List(args), env)) {
-                        func(args)
-                    } else {
-                        unreachable!()
+            },
+            Some(LET) => {
+                let let_env = Rc::new(Environment::new(Some(env.clone())));
+                let mut args_iter = args.into_iter();
+                if let Some(List(bindings, _)) = args_iter.next() {
+                    set_bindings(bindings, let_env.clone())?;
+                    match args_iter.next() {
+                        Some(val) => eval(val, let_env),
+                        None => Err(LispError::Message("let* must be called with a second argument to evaluate".to_string()))
                     }
-                },
-                misunderstood => return Err(LispError(format!("{} is not a function, cannot evaluate it", misunderstood)))
+                } else {
+                    Err(LispError::Message("let* must be called with a list of bindings as the first argument".to_string()))
+                }
+            },
+            _ => {
+                match eval_ast(arg0, env.clone())? {
+                    Func(func, _) => {
+                        if let List(args, _) = eval_ast(List(args, None), env)? {
+                            func(args)
+                        } else {
+                            unreachable!()
+                        }
+                    },
+                    misunderstood => return Err(LispError::Message(format!("{} is not a function, cannot evaluate it", misunderstood)))
+                }
             }
         }
     } else {
@@ -114,7 +134,11 @@ fn rep(input: &str, env: Rc<Environment>) -> String {
 }
 
 fn main() {
-    let env = core::default_environment();
+    types::run_deep(repl);
+}
+
+fn repl() {
+    let env = core::default_environment(eval);
     let prompt = &CString::new("user> ").unwrap();
     loop {
         match readline::readline(prompt) {
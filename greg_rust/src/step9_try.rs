@@ -0,0 +1,365 @@
+extern crate readline;
+
+use std::rc::Rc;
+use std::collections;
+use std::str;
+use std::env as std_env;
+use std::process;
+use std::ffi::CString;
+
+use types::{LispType, LispError, LispResult, ClosureData, Depth};
+use types::LispType::*;
+use env::Environment;
+use intern::{SymbolId, DEF, LET, DO, IF, FN, QUOTE, QUASIQUOTE, UNQUOTE, SPLICE_UNQUOTE, DEFMACRO, MACROEXPAND, TRY, CATCH};
+
+mod reader;
+mod types;
+mod intern;
+mod bignum;
+mod env;
+mod core;
+
+fn read(input: &str) -> reader::ParseResult {
+    reader::read_str(input)
+}
+
+fn eval_ast(ast: LispType, env: Rc<Environment>) -> LispResult {
+    match ast {
+        Symbol(sym) => env.get(sym),
+        List(values, _) => {
+            let mut evalues = Vec::with_capacity(values.len());
+            for val in values.into_iter() {
+                evalues.push(eval(val, env.clone())?)
+            }
+            Ok(List(evalues, None))
+        }
+        Vector(values, _) => {
+            let mut evalues = Vec::with_capacity(values.len());
+            for val in values.into_iter() {
+                evalues.push(eval(val, env.clone())?)
+            }
+            Ok(Vector(evalues, None))
+        }
+        HashMap(map, _) => {
+            let mut emap = collections::HashMap::with_capacity(map.len());
+            for (key, val) in map.into_iter() {
+                emap.insert(key, eval(val, env.clone())?);
+            }
+            Ok(HashMap(emap, None))
+        }
+        anything => Ok(anything)
+    }
+}
+
+fn set_bindings(bindings: Vec<LispType>, env: Rc<Environment>) -> LispResult {
+    let mut it = bindings.into_iter();
+    loop {
+        match (it.next(), it.next()) {
+            (Some(Symbol(sym)), Some(val)) => {
+                let val = eval(val, env.clone())?;
+                env.set(sym, val);
+            }
+            (Some(_), Some(_)) => return Err(LispError::Message("First binding argument must be a symbol".to_string())),
+            (Some(_), None) => return Err(LispError::Message("Binding arguments must have even length".to_string())),
+            (None, None) => return Ok(Nil),
+            _ => unreachable!()
+        }
+    }
+}
+
+fn quasiquote(ast: LispType) -> LispType {
+    match ast {
+        List(elems, _) => {
+            if elems.len() == 2 && elems[0] == Symbol(UNQUOTE) {
+                elems.into_iter().nth(1).unwrap()
+            } else {
+                quasiquote_elems(elems)
+            }
+        },
+        Vector(elems, _) => List(vec![Symbol(SymbolId::intern("vec")), quasiquote_elems(elems)], None),
+        Symbol(_) => List(vec![Symbol(QUOTE), ast], None),
+        anything => anything
+    }
+}
+
+fn quasiquote_elems(elems: Vec<LispType>) -> LispType {
+    let mut result = List(vec![Symbol(SymbolId::intern("list"))], None);
+    for elem in elems.into_iter().rev() {
+        result = match elem {
+            List(ref inner, _) if inner.len() == 2 && inner[0] == Symbol(SPLICE_UNQUOTE) => {
+                List(vec![Symbol(SymbolId::intern("concat")), inner[1].clone(), result], None)
+            },
+            elem => List(vec![Symbol(SymbolId::intern("cons")), quasiquote(elem), result], None)
+        };
+    }
+    result
+}
+
+// Macros are flagged on the closure itself, so spotting a macro call costs no
+// more than the lookup of the head symbol that a function call needs anyway.
+fn macroexpand(mut ast: LispType, env: Rc<Environment>) -> LispResult {
+    loop {
+        let expanded = if let List(ref values, _) = ast {
+            match values.first() {
+                Some(&Symbol(sym)) => match env.get(sym) {
+                    Ok(Closure(closure, _)) => if closure.is_macro {
+                        Some(types::apply(Closure(closure, None), values[1..].to_vec())?)
+                    } else {
+                        None
+                    },
+                    _ => None
+                },
+                _ => None
+            }
+        } else {
+            None
+        };
+
+        match expanded {
+            Some(expanded) => ast = expanded,
+            None => return Ok(ast)
+        }
+    }
+}
+
+fn eval(mut ast: LispType, mut env: Rc<Environment>) -> LispResult {
+    let _depth = Depth::enter()?;
+    loop {
+        return if let List(mut values, _) = ast {
+            if values.is_empty() {
+                return Err(LispError::Message("tried to evaluate a list with no function".to_string()))
+            }
+            let args = values.split_off(1);
+            let arg0 = values.into_iter().next().unwrap();
+
+            let form = match arg0 { Symbol(sym) => Some(sym), _ => None };
+            match form {
+                Some(DEF) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                        (Some(Symbol(sym)), Some(val), None) => {
+                            let val = eval(val, env.clone())?;
+                            env.set(sym, val.clone());
+                            Ok(val)
+                        },
+                        _ => Err(LispError::Message("def! must be called with 2 args, a symbol and a value".to_string())),
+                    }
+                },
+                Some(LET) => {
+                    let let_env = Rc::new(Environment::new(Some(env.clone())));
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                        (Some(List(bindings, _)), Some(val), None) | (Some(Vector(bindings, _)), Some(val), None) => {
+                            set_bindings(bindings, let_env.clone())?;
+                            ast = val;
+                            env = let_env;
+                            continue;
+                        },
+                        _ => Err(LispError::Message("let* must be called with a list/vector of bindings and an expression to evaluate".to_string()))
+                    }
+                },
+                Some(DO) => {
+                    let mut last_arg = Nil;
+                    for arg in args.into_iter() {
+                        eval(last_arg, env.clone())?;
+                        last_arg = arg;
+                    }
+                    ast = last_arg;
+                    continue;
+                },
+                Some(IF) => {
+                    let mut args_iter = args.into_iter();
+                    if let Some(cond) = args_iter.next() {
+                        let cond = eval(cond, env.clone())?;
+                        if cond == Nil || cond == False {
+                            args_iter.next(); // Skip the second parameter
+                            match args_iter.next() {
+                                Some(val) => {
+                                    ast = val;
+                                    continue;
+                                },
+                                None => Ok(Nil)
+                            }
+                        } else {
+                            match args_iter.next() {
+                                Some(val) => {
+                                    ast = val;
+                                    continue;
+                                },
+                                None => Err(LispError::Message("if must be called with at least two arguments".to_string()))
+                            }
+                        }
+                    } else {
+                        Err(LispError::Message("if must be called with at least two arguments".to_string()))
+                    }
+                },
+                Some(FN) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                        (Some(List(args, _)), Some(val), None) | (Some(Vector(args, _)), Some(val), None) => {
+                            Ok(Closure(ClosureData { eval, names: Environment::param_names(&args), bindings: args, body: Box::new(val), env: env.clone(), is_macro: false, code: None }, None))
+                        },
+                        _ => Err(LispError::Message("fn* must be called with a binding list and an expression".to_string()))
+                    }
+                },
+                Some(QUOTE) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next()) {
+                        (Some(val), None) => Ok(val),
+                        _ => Err(LispError::Message("quote must be called with a single argument".to_string()))
+                    }
+                },
+                Some(QUASIQUOTE) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next()) {
+                        (Some(val), None) => {
+                            ast = quasiquote(val);
+                            continue;
+                        },
+                        _ => Err(LispError::Message("quasiquote must be called with a single argument".to_string()))
+                    }
+                },
+                Some(DEFMACRO) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                        (Some(Symbol(sym)), Some(val), None) => {
+                            match eval(val, env.clone())? {
+                                Closure(mut closure, meta) => {
+                                    closure.is_macro = true;
+                                    let mac = Closure(closure, meta);
+                                    env.set(sym, mac.clone());
+                                    Ok(mac)
+                                },
+                                misunderstood => Err(LispError::Message(format!("{} is not a closure, cannot use it as a macro", misunderstood)))
+                            }
+                        },
+                        _ => Err(LispError::Message("defmacro! must be called with 2 args, a symbol and a closure".to_string())),
+                    }
+                },
+                Some(MACROEXPAND) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next()) {
+                        (Some(val), None) => macroexpand(val, env.clone()),
+                        _ => Err(LispError::Message("macroexpand must be called with a single argument".to_string()))
+                    }
+                },
+                Some(TRY) => {
+                    let mut args_iter = args.into_iter();
+                    match (args_iter.next(), args_iter.next(), args_iter.next()) {
+                        (Some(val), None, None) => {
+                            ast = val;
+                            continue;
+                        },
+                        (Some(val), Some(List(catch, _)), None) => {
+                            let mut catch_iter = catch.into_iter();
+                            match (catch_iter.next(), catch_iter.next(), catch_iter.next(), catch_iter.next()) {
+                                (Some(Symbol(CATCH)), Some(Symbol(exc)), Some(handler), None) => {
+                                    match eval(val, env.clone()) {
+                                        Ok(val) => Ok(val),
+                                        Err(err) => {
+                                            let catch_env = Rc::new(Environment::new(Some(env.clone())));
+                                            catch_env.set(exc, err.into_value());
+                                            env = catch_env;
+                                            ast = handler;
+                                            continue;
+                                        }
+                                    }
+                                },
+                                _ => Err(LispError::Message("catch* must be called with a symbol and an expression".to_string()))
+                            }
+                        },
+                        _ => Err(LispError::Message("try* must be called with an expression and an optional catch* form".to_string()))
+                    }
+                },
+                _ => {
+                    match eval(arg0, env.clone())? {
+                        Func(func, _) => {
+                            if let List(args, _) = eval_ast(List(args, None), env)? {
+                                func(args)
+                            } else {
+                                unreachable!()
+                            }
+                        },
+                        Closure(closure, _) => {
+                            if closure.is_macro {
+                                ast = types::apply(Closure(closure, None), args)?;
+                                continue;
+                            }
+                            if let List(args, _) = eval_ast(List(args, None), env)? {
+                                let closure_env = Rc::new(Environment::with_bindings(Some(closure.env), closure.names, closure.bindings, args)?);
+                                env = closure_env;
+                                ast = *closure.body;
+                                continue;
+                            } else {
+                                unreachable!()
+                            }
+                        },
+                        misunderstood => return Err(LispError::Message(format!("{} is not a function, cannot evaluate it", misunderstood)))
+                    }
+                }
+            }
+        } else {
+            eval_ast(ast, env)
+        }
+    }
+}
+
+fn print(value: LispType) -> String {
+    format!("{}", value)
+}
+
+fn rep(input: &str, env: Rc<Environment>) -> String {
+    match read(input) {
+        Err(err) => format!("error: {:?}", err),
+        Ok(ast) => {
+            match eval(ast, env) {
+                Err(err) => format!("error: {:?}", err),
+                Ok(ast) => print(ast)
+            }
+        }
+    }
+}
+
+fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
+    let env = core::default_environment(eval);
+    rep("(def! not (fn* (a) (if a false true)))", env.clone());
+    rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", env.clone());
+    rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or_FIXME ~(first xs)) (if or_FIXME or_FIXME (or ~@(rest xs))))))))", env.clone());
+
+    let args: Vec<String> = std_env::args().collect();
+    if args.len() > 1 {
+        env.set("*ARGV*", List(args[2..].iter().map(|arg| Str(arg.clone())).collect(), None));
+        let load_file = List(vec![Symbol(SymbolId::intern("load-file")), Str(args[1].clone())], None);
+        match eval(load_file, env) {
+            Ok(_) => return,
+            Err(err) => {
+                println!("error: {:?}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    let prompt = &CString::new("user> ").unwrap();
+    let continue_prompt = &CString::new("   ... ").unwrap();
+    let mut input = String::new();
+    loop {
+        match readline::readline(if input.is_empty() { prompt } else { continue_prompt }) {
+            Ok(line) => {
+                readline::add_history(&line);
+                input.push_str(str::from_utf8(line.to_bytes()).unwrap());
+                // Keep reading lines until the form is balanced
+                if let Err(reader::ParseError::Incomplete(_)) = read(&input) {
+                    input.push('\n');
+                    continue;
+                }
+                println!("{}", rep(&input, env.clone()));
+                input.clear();
+            },
+            Err(_) => return
+        }
+    }
+}
@@ -1,27 +1,160 @@
+#![allow(dead_code)]
+
 use std::fmt;
 use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::process;
 
 use types::LispType::*;
 use env::Environment;
+use bignum::{BigInt, Ratio};
+use intern::SymbolId;
 
 #[derive(Debug)]
-pub struct LispError(pub String);
+pub enum LispError {
+    Message(String),
+    Value(LispType)
+}
+
+impl LispError {
+    // What catch* binds: native errors are seen as strings
+    pub fn into_value(self) -> LispType {
+        match self {
+            LispError::Message(msg) => Str(msg),
+            LispError::Value(val) => val
+        }
+    }
+}
 
 pub type LispResult = Result<LispType, LispError>;
 
+pub type EvalFn = fn(LispType, Rc<Environment>) -> LispResult;
+
+#[derive(Clone)]
+pub struct ClosureData {
+    pub eval: EvalFn,
+    pub bindings: Vec<LispType>,
+    // The slots of the frame made for each call, see Environment
+    pub names: Rc<Vec<SymbolId>>,
+    pub body: Box<LispType>,
+    pub env: Rc<Environment>,
+    pub is_macro: bool,
+    // The body compiled by analyze.rs, which is run in its place
+    pub code: Option<Code>
+}
+
+// What running compiled code gives: its value, or a call in tail position,
+// which run_code makes in a loop rather than recursing so that tail calls
+// don't grow the stack
+pub enum Step {
+    Done(LispType),
+    TailCall(LispType, Vec<LispType>)
+}
+
+pub type Code = Rc<dyn Fn(&Rc<Environment>) -> Result<Step, LispError>>;
+
+pub fn run_code(code: &Code, env: &Rc<Environment>) -> LispResult {
+    let _depth = Depth::enter()?;
+    let mut step = code(env)?;
+    loop {
+        step = match step {
+            Step::Done(val) => return Ok(val),
+            Step::TailCall(Closure(ClosureData { code: Some(code), names, bindings, env, .. }, _), args) => {
+                let closure_env = Rc::new(Environment::with_bindings(Some(env), names, bindings, args)?);
+                code(&closure_env)?
+            },
+            Step::TailCall(func, args) => return apply(func, args)
+        }
+    }
+}
+
+// How deep evaluation and reading may nest, counting each eval (or run of
+// compiled code, or form read) started while another is still going. Going
+// deeper fails with a "stack overflow" error that catch* can handle, rather
+// than overflowing the native stack and killing the process.
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(10000);
+
+// The native stack each level of depth is given, with room to spare for the
+// deepest path between two checks in a debug build
+const STACK_PER_LEVEL: usize = 32 * 1024;
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn set_max_depth(max: usize) {
+    MAX_DEPTH.store(max, Ordering::Relaxed);
+}
+
+// A level of depth, given back when it's dropped
+pub struct Depth(());
+
+impl Depth {
+    pub fn enter() -> Result<Depth, LispError> {
+        DEPTH.with(|depth| {
+            if depth.get() >= MAX_DEPTH.load(Ordering::Relaxed) {
+                return Err(LispError::Message("stack overflow".to_string()))
+            }
+            depth.set(depth.get() + 1);
+            Ok(Depth(()))
+        })
+    }
+}
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+// Runs main on a thread with stack enough for the max depth, and exits the
+// way it does
+pub fn run_deep<F: FnOnce() + Send + 'static>(main: F) {
+    let stack = MAX_DEPTH.load(Ordering::Relaxed).saturating_mul(STACK_PER_LEVEL);
+    match thread::Builder::new().stack_size(stack).spawn(main) {
+        Ok(thread) => if thread.join().is_err() {
+            process::exit(101) // it panicked, which has been reported
+        },
+        Err(err) => {
+            println!("error: can't start the interpreter: {}", err);
+            process::exit(1)
+        }
+    }
+}
+
+// Shared so that cloning a value, which the evaluator does a lot, doesn't copy
+// its metadata
+pub type Meta = Option<Rc<LispType>>;
+
 #[derive(Clone)]
 pub enum LispType {
     Nil,
     True,
     False,
     Integer(i64),
-    Symbol(String),
+    // only ever holds values too large for an Integer, see integer()
+    BigInteger(BigInt),
+    // never has a denominator of one, see rational()
+    Rational(Ratio),
+    Float(f64),
+    Symbol(SymbolId),
     Str(String),
     Keyword(String),
-    List(Vec<LispType>),
-    Vector(Vec<LispType>),
-    Func(fn(Vec<LispType>) -> LispResult),
-    Closure(Vec<LispType>, Box<LispType>, Rc<Environment>)
+    List(Vec<LispType>, Meta),
+    Vector(Vec<LispType>, Meta),
+    HashMap(collections::HashMap<LispType, LispType>, Meta),
+    Func(fn(Vec<LispType>) -> LispResult, Meta),
+    Closure(ClosureData, Meta),
+    Atom(Rc<RefCell<LispType>>),
+    // Only in code rewritten by resolve.rs: a local symbol with how many
+    // frames out it is bound and its slot there, and the head of a fn*, let*
+    // or catch* form with the names of the slots in the frame it makes
+    Local(SymbolId, usize, usize),
+    Binder(SymbolId, Rc<Vec<SymbolId>>)
 }
 
 impl PartialEq for LispType {
@@ -31,20 +164,120 @@ impl PartialEq for LispType {
             (&True, &True) => true,
             (&False, &False) => true,
             (&Integer(ref a), &Integer(ref b)) => a == b,
+            (&BigInteger(ref a), &BigInteger(ref b)) => a == b,
+            (&Rational(ref a), &Rational(ref b)) => a == b,
+            (&Float(a), &Float(b)) => a == b,
             (&Symbol(ref a), &Symbol(ref b)) => a == b,
             (&Str(ref a), &Str(ref b)) => a == b,
             (&Keyword(ref a), &Keyword(ref b)) => a == b,
-            (&List(ref a), &List(ref b)) => a == b,
-            (&List(ref a), &Vector(ref b)) => a == b,
-            (&Vector(ref a), &Vector(ref b)) => a == b,
-            (&Vector(ref a), &List(ref b)) => a == b,
-            (&Func(_), &Func(_)) => false,
-            (&Closure(_,_,_), &Closure(_,_,_)) => false,
+            (&List(ref a, _), &List(ref b, _)) => a == b,
+            (&List(ref a, _), &Vector(ref b, _)) => a == b,
+            (&Vector(ref a, _), &Vector(ref b, _)) => a == b,
+            (&Vector(ref a, _), &List(ref b, _)) => a == b,
+            (&HashMap(ref a, _), &HashMap(ref b, _)) => a == b,
+            (&Func(_, _), &Func(_, _)) => false,
+            (&Closure(_, _), &Closure(_, _)) => false,
             _ => false
         }
     }
 }
 
+// Only hashable values (see is_hashable) are ever used as hash-map keys, and
+// those are all equal to themselves
+impl Eq for LispType {}
+
+impl Hash for LispType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Nil => state.write_u8(0),
+            True => state.write_u8(1),
+            False => state.write_u8(2),
+            Integer(int) => { state.write_u8(3); int.hash(state) },
+            BigInteger(ref big) => { state.write_u8(9); big.hash(state) },
+            Rational(ref ratio) => { state.write_u8(11); ratio.hash(state) },
+            // 0.0 and -0.0 are equal but have different bits
+            Float(float) => { state.write_u8(10); (if float == 0.0 { 0 } else { float.to_bits() }).hash(state) },
+            Symbol(ref sym) => { state.write_u8(4); sym.hash(state) },
+            Str(ref s) => { state.write_u8(5); s.hash(state) },
+            Keyword(ref s) => { state.write_u8(6); s.hash(state) },
+            // lists and vectors compare equal so must hash the same
+            List(ref elems, _) | Vector(ref elems, _) => { state.write_u8(7); elems.hash(state) },
+            _ => state.write_u8(8)
+        }
+    }
+}
+
+pub fn is_hashable(val: &LispType) -> bool {
+    match *val {
+        List(ref elems, _) | Vector(ref elems, _) => elems.iter().all(is_hashable),
+        HashMap(ref map, _) => map.values().all(is_hashable),
+        Func(_, _) | Closure(_, _) | Atom(_) => false,
+        _ => true
+    }
+}
+
+// Turns the result of BigInt arithmetic back into an Integer when it fits, so
+// that each number has exactly one representation
+pub fn integer(big: BigInt) -> LispType {
+    match big.to_i64() {
+        Some(int) => Integer(int),
+        None => BigInteger(big)
+    }
+}
+
+// Debug formatting gives the shortest string that reads back as the same
+// float, and always includes a . or an exponent so the reader doesn't take it
+// for an integer
+fn float_str(float: f64) -> String {
+    if float.is_nan() {
+        "##NaN".to_string()
+    } else if float == f64::INFINITY {
+        "##Inf".to_string()
+    } else if float == f64::NEG_INFINITY {
+        "##-Inf".to_string()
+    } else {
+        format!("{:?}", float)
+    }
+}
+
+// Likewise a Ratio with a denominator of one becomes an integer
+pub fn rational(ratio: Ratio) -> LispType {
+    if ratio.is_integer() {
+        integer(ratio.numer().clone())
+    } else {
+        Rational(ratio)
+    }
+}
+
+pub fn assoc_pairs(map: &mut collections::HashMap<LispType, LispType>, kvs: Vec<LispType>) -> Result<(), String> {
+    if !kvs.len().is_multiple_of(2) {
+        return Err("hash-map must be given an even number of keys and values".to_string())
+    }
+
+    let mut kvs_iter = kvs.into_iter();
+    while let (Some(key), Some(val)) = (kvs_iter.next(), kvs_iter.next()) {
+        if !is_hashable(&key) {
+            return Err(format!("{} cannot be used as a hash-map key", key))
+        }
+        map.insert(key, val);
+    }
+    Ok(())
+}
+
+pub fn apply(func: LispType, args: Vec<LispType>) -> LispResult {
+    match func {
+        Func(func, _) => func(args),
+        Closure(closure, _) => {
+            let closure_env = Rc::new(Environment::with_bindings(Some(closure.env), closure.names, closure.bindings, args)?);
+            match closure.code {
+                Some(code) => run_code(&code, &closure_env),
+                None => (closure.eval)(*closure.body, closure_env)
+            }
+        },
+        misunderstood => Err(LispError::Message(format!("{} is not a function, cannot evaluate it", misunderstood)))
+    }
+}
+
 impl fmt::Display for LispType {
     fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
         out.write_str(&pr_str(self, true))
@@ -63,7 +296,10 @@ pub fn pr_str(val: &LispType, print_readably: bool) -> String {
         &True => "true".to_string(),
         &False => "false".to_string(),
         &Integer(int) => int.to_string(),
-        &Symbol(ref sym) => sym.clone(),
+        &BigInteger(ref big) => big.to_string(),
+        &Rational(ref ratio) => ratio.to_string(),
+        &Float(float) => float_str(float),
+        &Symbol(sym) | &Local(sym, _, _) | &Binder(sym, _) => sym.name().to_string(),
         &Str(ref s) => if print_readably {
             let mut buf = String::new();
             buf.push('"');
@@ -83,7 +319,7 @@ pub fn pr_str(val: &LispType, print_readably: bool) -> String {
             s.clone()
         },
         &Keyword(ref s) => s.clone(),
-        &List(ref elems) => {
+        &List(ref elems, _) => {
             let mut buf = String::new();
             buf.push('(');
             for (i, v) in elems.iter().enumerate() {
@@ -95,7 +331,7 @@ pub fn pr_str(val: &LispType, print_readably: bool) -> String {
             buf.push(')');
             buf
         },
-        &Vector(ref elems) => {
+        &Vector(ref elems, _) => {
             let mut buf = String::new();
             buf.push('[');
             for (i, v) in elems.iter().enumerate() {
@@ -107,7 +343,22 @@ pub fn pr_str(val: &LispType, print_readably: bool) -> String {
             buf.push(']');
             buf
         },
-        &Func(_) => "#<function ...>".to_string(),
-        &Closure(_,_,_) => "#<function ...>".to_string()
+        &HashMap(ref map, _) => {
+            let mut buf = String::new();
+            buf.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i != 0 {
+                    buf.push(' ');
+                }
+                buf.push_str(&pr_str(k, print_readably));
+                buf.push(' ');
+                buf.push_str(&pr_str(v, print_readably));
+            }
+            buf.push('}');
+            buf
+        },
+        &Func(_, _) => "#<function ...>".to_string(),
+        &Closure(_, _) => "#<function ...>".to_string(),
+        &Atom(ref cell) => format!("(atom {})", pr_str(&cell.borrow(), print_readably))
     }
 }
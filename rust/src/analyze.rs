@@ -0,0 +1,338 @@
+// Compiling to closures, stepA's alternative to eval (--eval=closure).
+//
+// analyze walks a form once and gives back a Rust closure that does what
+// eval would do with it. Which special form it is, whether it's well formed
+// and how each symbol is looked up are all settled ahead of time rather than
+// every time it runs. A fn* body is analyzed along with the fn*, after
+// resolve.rs has given its locals slots, and calling the function runs the
+// analyzed body (see run_code in types.rs).
+//
+// A call can't be told apart from a macro call until its head is looked up,
+// so that is checked each time it runs. The first time the head turns out
+// to be a macro, the form is expanded and the expansion analyzed; that code
+// is kept and run directly for as long as the head is the same macro, as a
+// compiler would expand the macro once.
+//
+// Calls to mal functions give a Step::TailCall rather than making the call,
+// and run_code makes it in a loop, so tail calls run in constant stack like
+// they do in eval's loop.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use types::{MalVal,MalRet,MalError,Code,Step,Depth,
+            MalFuncData,Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,Local,Binder,
+            err_str,err_string,_nil,vector,hash_map,malfunc,malfuncd,run_code,
+            named,source,exception};
+use env::{Env,env_new,env_frame,env_root,env_set,env_get,env_get_local};
+use intern::{SymbolId,DEF,LET,DO,IF,FN,QUOTE,QUASIQUOTE,DEFMACRO,MACROEXPAND,TRY,EVAL};
+use resolve;
+use pmap::PMap;
+use {quasiquote,macroexpand};
+
+// Analyzes ast and runs it, in place of eval
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    run_code(&analyze(&ast), &env)
+}
+
+pub fn analyze(ast: &MalVal) -> Code {
+    // a form nested too deep to analyze here is analyzed when it runs,
+    // where that either fits or fails as a stack overflow
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => {
+            let ast = ast.clone();
+            return Rc::new(move |env| {
+                let _depth = Depth::enter()?;
+                analyze(&ast)(env)
+            });
+        },
+    };
+    match **ast {
+        Sym(_) => {
+            let sym = ast.clone();
+            Rc::new(move |env| step(env_get(env.clone(), sym.clone())))
+        },
+        Local(name, depth, slot) => {
+            let sym = Rc::new(Sym(name));
+            Rc::new(move |env| match env_get_local(env, depth, slot) {
+                Some(v) => Ok(Step::Done(v)),
+                None => step(env_get(env.clone(), sym.clone())),
+            })
+        },
+        List(ref a,_) if a.len() > 0 => analyze_list(ast, &a.to_vec()),
+        Vector(ref a,_) => {
+            let codes = analyze_all(&a.to_vec());
+            Rc::new(move |env| Ok(Step::Done(vector(values(&codes, env)?))))
+        },
+        Hash_Map(ref hm,_) => {
+            let codes: Vec<(String,Code)> = hm.iter()
+                .map(|(k, v)| (k.clone(), analyze(v)))
+                .collect();
+            Rc::new(move |env| {
+                let mut new_hm: PMap<MalVal> = PMap::new();
+                for &(ref k, ref code) in codes.iter() {
+                    new_hm.insert(k.clone(), run_code(code, env)?);
+                }
+                Ok(Step::Done(hash_map(new_hm)))
+            })
+        },
+        _ => constant(ast.clone()),
+    }
+}
+
+fn analyze_all(a: &[MalVal]) -> Vec<Code> {
+    a.iter().map(analyze).collect()
+}
+
+fn step(res: MalRet) -> Result<Step,MalError> {
+    res.map(Step::Done)
+}
+
+fn constant(val: MalVal) -> Code {
+    Rc::new(move |_| Ok(Step::Done(val.clone())))
+}
+
+fn error(msg: String) -> Code {
+    Rc::new(move |_| step(err_string(msg.clone())))
+}
+
+fn values(codes: &[Code], env: &Env) -> Result<Vec<MalVal>,MalError> {
+    let mut vals = Vec::with_capacity(codes.len());
+    for code in codes.iter() {
+        vals.push(run_code(code, env)?);
+    }
+    Ok(vals)
+}
+
+// The frame for a let* or catch*, with slots if the form was resolved
+fn frame(env: &Env, names: &Option<Rc<Vec<SymbolId>>>) -> Env {
+    match *names {
+        Some(ref names) => env_frame(Some(env.clone()), names.clone()),
+        None => env_new(Some(env.clone())),
+    }
+}
+
+fn analyze_list(ast: &MalVal, a: &[MalVal]) -> Code {
+    let (head, names) = match *a[0] {
+        Sym(s) => (Some(s), None),
+        Binder(s, ref names) => (Some(s), Some(names.clone())),
+        _ => (None, None),
+    };
+    let arity = |n: usize| if a.len() == n + 1 {
+        None
+    } else {
+        Some(error(format!("wrong number of arguments to {}", a[0])))
+    };
+    match head {
+        Some(DEF) => arity(2).unwrap_or_else(|| def_form(a)),
+        Some(LET) => arity(2).unwrap_or_else(|| let_form(a, names)),
+        Some(DO) => do_form(a),
+        Some(IF) if a.len() == 3 || a.len() == 4 => if_form(a),
+        Some(IF) => error("wrong number of arguments to if".to_string()),
+        Some(FN) => arity(2).unwrap_or_else(|| fn_form(ast, a, names)),
+        Some(QUOTE) => arity(1).unwrap_or_else(|| constant(a[1].clone())),
+        Some(QUASIQUOTE) => {
+            arity(1).unwrap_or_else(|| analyze(&quasiquote(a[1].clone())))
+        },
+        Some(DEFMACRO) => arity(2).unwrap_or_else(|| defmacro_form(a)),
+        Some(MACROEXPAND) => arity(1).unwrap_or_else(|| {
+            let form = a[1].clone();
+            Rc::new(move |env| step(macroexpand(form.clone(), env.clone())))
+        }),
+        Some(TRY) if a.len() == 2 => analyze(&a[1]),
+        Some(TRY) => arity(2).unwrap_or_else(|| try_form(a)),
+        Some(EVAL) => arity(1).unwrap_or_else(|| {
+            let form = analyze(&a[1]);
+            Rc::new(move |env| {
+                let exp = run_code(&form, env)?;
+                analyze(&exp)(&env_root(env))
+            })
+        }),
+        _ => call(a),
+    }
+}
+
+fn def_form(a: &[MalVal]) -> Code {
+    match *a[1] {
+        Sym(name) => {
+            let sym = a[1].clone();
+            let val = analyze(&a[2]);
+            Rc::new(move |env| {
+                let r = named(run_code(&val, env)?, name);
+                env_set(env, sym.clone(), r.clone());
+                Ok(Step::Done(r))
+            })
+        },
+        _ => error("def! of non-symbol".to_string()),
+    }
+}
+
+fn let_form(a: &[MalVal], names: Option<Rc<Vec<SymbolId>>>) -> Code {
+    let binds = match *a[1] {
+        List(ref binds,_) | Vector(ref binds,_) => binds.to_vec(),
+        _ => return error("let* with non-list bindings".to_string()),
+    };
+    let mut pairs = vec![];
+    for bv in binds.chunks(2).filter(|bv| bv.len() == 2) {
+        match *bv[0] {
+            Sym(_) => pairs.push((bv[0].clone(), analyze(&bv[1]))),
+            _ => return error("let* with non-symbol binding".to_string()),
+        }
+    }
+    let body = analyze(&a[2]);
+    Rc::new(move |env| {
+        let let_env = frame(env, &names);
+        for &(ref sym, ref val) in pairs.iter() {
+            let r = run_code(val, &let_env)?;
+            env_set(&let_env, sym.clone(), r);
+        }
+        body(&let_env)
+    })
+}
+
+fn do_form(a: &[MalVal]) -> Code {
+    if a.len() == 1 {
+        return constant(_nil());
+    }
+    let init = analyze_all(&a[1..a.len()-1]);
+    let last = analyze(&a[a.len()-1]);
+    Rc::new(move |env| {
+        for code in init.iter() {
+            run_code(code, env)?;
+        }
+        last(env)
+    })
+}
+
+fn if_form(a: &[MalVal]) -> Code {
+    let cond = analyze(&a[1]);
+    let then = analyze(&a[2]);
+    let otherwise = if a.len() == 4 { analyze(&a[3]) } else { constant(_nil()) };
+    Rc::new(move |env| match *run_code(&cond, env)? {
+        False | Nil => otherwise(env),
+        _ => then(env),
+    })
+}
+
+fn fn_form(ast: &MalVal, a: &[MalVal], names: Option<Rc<Vec<SymbolId>>>) -> Code {
+    let names = match names {
+        Some(names) => names,
+        None => match resolve::resolve_fn(ast) {
+            Some(resolved) => return analyze(&resolved),
+            // the parameters are malformed, which binding them will report
+            None => {
+                let (params, exp) = (a[1].clone(), a[2].clone());
+                return Rc::new(move |env| {
+                    Ok(Step::Done(malfunc(eval, exp.clone(), env.clone(),
+                                          params.clone(), _nil())))
+                });
+            },
+        },
+    };
+    let (params, exp) = (a[1].clone(), a[2].clone());
+    let body = analyze(&exp);
+    Rc::new(move |env| {
+        Ok(Step::Done(malfuncd(MalFuncData{eval: eval, exp: exp.clone(),
+                                           env: env.clone(), params: params.clone(),
+                                           names: names.clone(), is_macro: false,
+                                           code: Some(body.clone()),
+                                           bytecode: None, name: None,
+                                           pos: source()}, _nil())))
+    })
+}
+
+fn defmacro_form(a: &[MalVal]) -> Code {
+    let sym = a[1].clone();
+    let name = match *sym {
+        Sym(name) => name,
+        _ => return error("def! of non-symbol".to_string()),
+    };
+    let val = analyze(&a[2]);
+    Rc::new(move |env| {
+        let r = run_code(&val, env)?;
+        match *r {
+            MalFunc(ref mfd,_) => {
+                let mut new_mfd = mfd.clone();
+                new_mfd.is_macro = true;
+                let mf = named(malfuncd(new_mfd,_nil()), name);
+                env_set(env, sym.clone(), mf.clone());
+                Ok(Step::Done(mf))
+            },
+            _ => step(err_str("def! of non-symbol")),
+        }
+    })
+}
+
+fn try_form(a: &[MalVal]) -> Code {
+    let body = analyze(&a[1]);
+    // a bad catch* clause is only reported if there is something to catch
+    let bad_catch = |msg: &'static str| -> Code {
+        let body = body.clone();
+        Rc::new(move |env| {
+            run_code(&body, env)?;
+            step(err_str(msg))
+        })
+    };
+    let cat = match *a[2] {
+        List(ref cat,_) => cat,
+        _ => return bad_catch("invalid catch* clause"),
+    };
+    if cat.len() != 3 {
+        return bad_catch("wrong arity to catch* clause");
+    }
+    match *cat[1] {
+        Sym(_) => (),
+        _ => return bad_catch("invalid catch* binding"),
+    }
+    let exc_sym = cat[1].clone();
+    let names = match *cat[0] {
+        Binder(_, ref names) => Some(names.clone()),
+        _ => None,
+    };
+    let handler = analyze(&cat[2]);
+    Rc::new(move |env| {
+        match run_code(&body, env) {
+            Ok(res) => Ok(Step::Done(res)),
+            Err(err) => {
+                let bind_env = frame(env, &names);
+                env_set(&bind_env, exc_sym.clone(), exception(err));
+                handler(&bind_env)
+            },
+        }
+    })
+}
+
+// A function call, or a macro call if the head is a symbol naming a macro
+fn call(a: &[MalVal]) -> Code {
+    let head = analyze(&a[0]);
+    let args = analyze_all(&a[1..]);
+    let may_be_macro = matches!(*a[0], Sym(_));
+    let forms = a[1..].to_vec();
+    // the macro last found at the head and the analyzed expansion it gave
+    let expansion: RefCell<Option<(MalVal,Code)>> = RefCell::new(None);
+    Rc::new(move |env| {
+        let f = run_code(&head, env)?;
+        match *f {
+            MalFunc(ref mf,_) if mf.is_macro && may_be_macro => {
+                let cached = match *expansion.borrow() {
+                    Some((ref m, ref code)) if Rc::ptr_eq(m, &f) => Some(code.clone()),
+                    _ => None,
+                };
+                let code = match cached {
+                    Some(code) => code,
+                    None => {
+                        let exp = f.apply(forms.iter().map(resolve::strip).collect())?;
+                        let code = analyze(&exp);
+                        *expansion.borrow_mut() = Some((f.clone(), code.clone()));
+                        code
+                    },
+                };
+                code(env)
+            },
+            Func(func,_) => step(func(values(&args, env)?)),
+            MalFunc(_,_) => Ok(Step::TailCall(f.clone(), values(&args, env)?)),
+            _ => step(err_str("attempt to call non-function")),
+        }
+    })
+}
@@ -0,0 +1,585 @@
+// Compiling forms to bytecode for vm.rs.
+//
+// Macros are expanded as a form is compiled. Each expansion is run only after
+// checking that its macro hasn't been redefined since (see Expansion), and a
+// call of a global that was no macro when it was compiled is expanded when
+// it's run if the global has become one (see late_call).
+// Special forms are checked as analyze.rs does, and a malformed one compiles
+// to code that raises its error if it is run. So does a macro call whose
+// expansion fails, by raising an error or by nesting too deep to compile, so
+// that a try* around it catches the error as it would in eval.
+//
+// Each variable a function binds (its parameters, let* and catch* bindings
+// and the names it def!s) gets a slot, and references to it are compiled to
+// slot or upvalue accesses. The names a scope def!s are declared when it's
+// entered, as resolve.rs does, so that a closure compiled before the def!
+// refers to the slot it binds. A let* binding used before it is bound, or a
+// def!ed name used before its def! runs, means whatever the name means
+// outside, as it does in eval, so those references are compiled with a
+// fallback: the code for the next binding of the name out, ending in a lookup
+// in the global env.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use types::{MalVal,MalError,ErrString,ErrMalVal,ErrIncomplete,Sym,List,Vector,Hash_Map,MalFunc,Depth,_nil,string,list};
+use env::{Env,env_get};
+use intern::{SymbolId,DEF,LET,DO,IF,FN,QUOTE,QUASIQUOTE,DEFMACRO,MACROEXPAND,TRY,
+             CATCH,EVAL,AMPERSAND};
+use vm::{Op,Capture,Proto,Expansion};
+use resolve::declare_defs;
+use quasiquote;
+
+// Compiles a top-level form into a function of no arguments running it
+pub fn compile(ast: &MalVal, globals: &Env) -> Result<Rc<Proto>,MalError> {
+    let mut c = Compiler{fns: vec![FnState::new(list(vec![]), ast.clone(), 0, false)],
+                         globals: globals.clone()};
+    c.expr(ast, true)?;
+    c.emit(Op::Return);
+    Ok(Rc::new(c.fns.pop().unwrap().proto))
+}
+
+struct Var {
+    name:  SymbolId,
+    slot:  usize,
+    bound: bool, // surely bound in the code compiled from here on
+}
+
+struct FnState {
+    proto:  Proto,
+    scopes: Vec<Vec<Var>>, // the parameters, then each let* and catch* inside
+}
+
+impl FnState {
+    fn new(params: MalVal, exp: MalVal, arity: usize, variadic: bool) -> FnState {
+        FnState{proto: Proto{params: params, exp: exp, arity: arity, variadic: variadic,
+                             slots: vec![], upvals: vec![], code: vec![], consts: vec![],
+                             protos: vec![], expansions: vec![], calls: vec![]},
+                scopes: vec![]}
+    }
+}
+
+enum Place {
+    Local(usize),
+    Upval(usize),
+}
+
+struct Compiler {
+    fns:     Vec<FnState>, // the function being compiled and those around it
+    globals: Env,
+}
+
+impl Compiler {
+    fn cur(&mut self) -> &mut FnState {
+        self.fns.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let code = &mut self.cur().proto.code;
+        code.push(op);
+        code.len() - 1
+    }
+
+    fn here(&mut self) -> usize {
+        self.cur().proto.code.len()
+    }
+
+    // Points the jump at i to where the next op will go
+    fn patch(&mut self, i: usize) {
+        let here = self.here();
+        let op = &mut self.cur().proto.code[i];
+        *op = match *op {
+            Op::Jump(_) => Op::Jump(here),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(here),
+            Op::TryLocal(s, _) => Op::TryLocal(s, here),
+            Op::TryUpval(u, _) => Op::TryUpval(u, here),
+            Op::Try(_, s) => Op::Try(here, s),
+            other => other,
+        };
+    }
+
+    fn constant(&mut self, val: MalVal) -> usize {
+        let consts = &mut self.cur().proto.consts;
+        consts.push(val);
+        consts.len() - 1
+    }
+
+    fn fail(&mut self, msg: &str) -> Result<(),MalError> {
+        let c = self.constant(string(msg.to_string()));
+        self.emit(Op::Fail(c));
+        Ok(())
+    }
+
+    // Compiles code raising e
+    fn raise(&mut self, e: MalError) {
+        let op = match e {
+            ErrString(msg) | ErrIncomplete(msg) => Op::Fail(self.constant(string(msg))),
+            ErrMalVal(v) => Op::Throw(self.constant(v)),
+        };
+        self.emit(op);
+    }
+
+    fn new_slot(&mut self, name: SymbolId) -> usize {
+        let slots = &mut self.cur().proto.slots;
+        slots.push(name);
+        slots.len() - 1
+    }
+
+    // The slot for name in the innermost scope, adding one if it has none
+    fn declare(&mut self, name: SymbolId, bound: bool) -> usize {
+        if let Some(v) = self.cur().scopes.last().unwrap().iter().find(|v| v.name == name) {
+            return v.slot;
+        }
+        let slot = self.new_slot(name);
+        self.cur().scopes.last_mut().unwrap().push(Var{name: name, slot: slot, bound: bound});
+        slot
+    }
+
+    // Declares the names the code in the innermost scope def!s into it
+    fn declare_defs(&mut self, code: &[&MalVal]) {
+        let mut names = vec![];
+        for ast in code.iter() {
+            declare_defs(ast, &mut names);
+        }
+        for name in names {
+            self.declare(name, false);
+        }
+    }
+
+    fn mark_bound(&mut self, name: SymbolId) {
+        for v in self.cur().scopes.last_mut().unwrap().iter_mut() {
+            if v.name == name {
+                v.bound = true;
+            }
+        }
+    }
+
+    // The innermost binding of name outside scope `scope` of function
+    // `level`, as its function, scope, slot and whether it's surely bound
+    fn find(&self, name: SymbolId, level: usize, scope: usize)
+            -> Option<(usize, usize, usize, bool)> {
+        let mut top = scope;
+        for lvl in (0..level+1).rev() {
+            let scopes = &self.fns[lvl].scopes;
+            for s in (0..top).rev() {
+                if let Some(v) = scopes[s].iter().find(|v| v.name == name) {
+                    return Some((lvl, s, v.slot, v.bound));
+                }
+            }
+            if lvl > 0 {
+                top = self.fns[lvl-1].scopes.len();
+            }
+        }
+        None
+    }
+
+    fn is_local(&self, name: SymbolId) -> bool {
+        let level = self.fns.len() - 1;
+        self.find(name, level, self.fns[level].scopes.len()).is_some()
+    }
+
+    // How the current function gets at slot `slot` of function `level`
+    fn place(&mut self, level: usize, slot: usize) -> Place {
+        let cur = self.fns.len() - 1;
+        if level == cur {
+            Place::Local(slot)
+        } else {
+            Place::Upval(self.capture(cur, level, slot))
+        }
+    }
+
+    fn capture(&mut self, at: usize, level: usize, slot: usize) -> usize {
+        let capture = if at - 1 == level {
+            Capture::Local(slot)
+        } else {
+            Capture::Upval(self.capture(at - 1, level, slot))
+        };
+        let name = self.fns[level].proto.slots[slot];
+        let upvals = &mut self.fns[at].proto.upvals;
+        match upvals.iter().position(|&(_, c)| c == capture) {
+            Some(i) => i,
+            None => {
+                upvals.push((name, capture));
+                upvals.len() - 1
+            },
+        }
+    }
+
+    fn symbol(&mut self, ast: &MalVal, name: SymbolId) {
+        let mut level = self.fns.len() - 1;
+        let mut scope = self.fns[level].scopes.len();
+        let mut fallbacks = vec![];
+        loop {
+            match self.find(name, level, scope) {
+                Some((lvl, s, slot, bound)) => {
+                    let op = match (self.place(lvl, slot), bound) {
+                        (Place::Local(i), true) => Op::GetLocal(i),
+                        (Place::Upval(i), true) => Op::GetUpval(i),
+                        (Place::Local(i), false) => Op::TryLocal(i, 0),
+                        (Place::Upval(i), false) => Op::TryUpval(i, 0),
+                    };
+                    let i = self.emit(op);
+                    if bound {
+                        break;
+                    }
+                    fallbacks.push(i);
+                    level = lvl;
+                    scope = s;
+                },
+                None => {
+                    let c = self.constant(ast.clone());
+                    self.emit(Op::GetGlobal(c));
+                    break;
+                },
+            }
+        }
+        for i in fallbacks {
+            self.patch(i);
+        }
+    }
+
+    fn expr(&mut self, ast: &MalVal, tail: bool) -> Result<(),MalError> {
+        let _depth = Depth::enter()?;
+        match **ast {
+            Sym(name) => self.symbol(ast, name),
+            List(ref a,_) if a.len() > 0 => return self.list(&a.to_vec(), tail),
+            Vector(ref a,_) => {
+                for x in a.iter() {
+                    self.expr(x, false)?;
+                }
+                self.emit(Op::Vector(a.len()));
+            },
+            Hash_Map(ref hm,_) => {
+                for (k, v) in hm.iter() {
+                    let c = self.constant(string(k.clone()));
+                    self.emit(Op::Const(c));
+                    self.expr(v, false)?;
+                }
+                self.emit(Op::Map(hm.len()));
+            },
+            _ => {
+                let c = self.constant(ast.clone());
+                self.emit(Op::Const(c));
+            },
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, a: &[MalVal], tail: bool) -> Result<(),MalError> {
+        let head = match *a[0] {
+            Sym(s) => Some(s),
+            _ => None,
+        };
+        let arity = |n: usize| a.len() == n + 1;
+        match head {
+            Some(DEF) if arity(2) => self.def_form(a),
+            Some(LET) if arity(2) => self.let_form(a, tail),
+            Some(DO) => self.do_form(a, tail),
+            Some(IF) if a.len() == 3 || a.len() == 4 => self.if_form(a, tail),
+            Some(FN) if arity(2) => self.fn_form(a),
+            Some(QUOTE) if arity(1) => {
+                let c = self.constant(a[1].clone());
+                self.emit(Op::Const(c));
+                Ok(())
+            },
+            Some(QUASIQUOTE) if arity(1) => self.expr(&quasiquote(a[1].clone()), tail),
+            Some(DEFMACRO) if arity(2) => self.defmacro_form(a),
+            Some(MACROEXPAND) if arity(1) => {
+                let c = self.constant(a[1].clone());
+                self.emit(Op::MacroExpand(c));
+                Ok(())
+            },
+            Some(TRY) if arity(1) => self.expr(&a[1], tail),
+            Some(TRY) if arity(2) => self.try_form(a, tail),
+            Some(EVAL) if arity(1) => {
+                self.expr(&a[1], false)?;
+                self.emit(Op::Compile);
+                self.emit(if tail { Op::TailCall(0) } else { Op::Call(0) });
+                Ok(())
+            },
+            Some(DEF) | Some(LET) | Some(IF) | Some(FN) | Some(QUOTE) | Some(QUASIQUOTE) |
+            Some(DEFMACRO) | Some(MACROEXPAND) | Some(TRY) | Some(EVAL) => {
+                self.fail(&format!("wrong number of arguments to {}", a[0]))
+            },
+            Some(s) => match self.macro_named(s) {
+                Some(mac) => self.expansion(a, mac, tail),
+                None => self.call(a, tail),
+            },
+            None => self.call(a, tail),
+        }
+    }
+
+    // The macro a symbol at the head of a form names, if it names one now
+    fn macro_named(&self, name: SymbolId) -> Option<MalVal> {
+        if self.is_local(name) {
+            return None;
+        }
+        match env_get(self.globals.clone(), Rc::new(Sym(name))) {
+            Ok(f) => match *f {
+                MalFunc(ref mf,_) if mf.is_macro => Some(f.clone()),
+                _ => None,
+            },
+            Err(_) => None,
+        }
+    }
+
+    fn expansion(&mut self, a: &[MalVal], mac: MalVal, tail: bool) -> Result<(),MalError> {
+        let vars = self.visible(&|_| true);
+        let check = self.emit(Op::Expanded(0));
+        let (fns, scopes) = (self.fns.len(), self.cur().scopes.len());
+        if let Err(e) = mac.apply(a[1..].to_vec()).and_then(|exp| self.expr(&exp, tail)) {
+            // drop what was compiled of it, and raise the error instead
+            self.fns.truncate(fns);
+            self.cur().scopes.truncate(scopes);
+            self.cur().proto.code.truncate(check + 1);
+            self.raise(e);
+        }
+        let end = self.here();
+        let expansions = &mut self.cur().proto.expansions;
+        expansions.push(Expansion{sym: a[0].clone(), mac: mac, form: list(a.to_vec()),
+                                  vars: vars, end: end, redone: RefCell::new(None)});
+        let i = expansions.len() - 1;
+        self.cur().proto.code[check] = Op::Expanded(i);
+        Ok(())
+    }
+
+    // Each local that can be seen from here and is wanted, and where to
+    // find it
+    fn visible(&mut self, wanted: &dyn Fn(SymbolId) -> bool) -> Vec<(SymbolId, Capture)> {
+        let mut names: Vec<SymbolId> = vec![];
+        for f in self.fns.iter().rev() {
+            for scope in f.scopes.iter().rev() {
+                for v in scope.iter() {
+                    if wanted(v.name) && !names.contains(&v.name) {
+                        names.push(v.name);
+                    }
+                }
+            }
+        }
+        let level = self.fns.len() - 1;
+        let scope = self.fns[level].scopes.len();
+        names.into_iter().map(|name| {
+            let (lvl, _, slot, _) = self.find(name, level, scope).unwrap();
+            match self.place(lvl, slot) {
+                Place::Local(i) => (name, Capture::Local(i)),
+                Place::Upval(i) => (name, Capture::Upval(i)),
+            }
+        }).collect()
+    }
+
+    fn call(&mut self, a: &[MalVal], tail: bool) -> Result<(),MalError> {
+        let head = self.here();
+        for x in a.iter() {
+            self.expr(x, false)?;
+        }
+        let n = a.len() - 1;
+        self.emit(if tail { Op::TailCall(n) } else { Op::Call(n) });
+        match *a[0] {
+            Sym(name) if !self.is_local(name) => self.late_call(head, a),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Keeps a call of a global, loaded by the GetGlobal at head, to be
+    // expanded if the global turns out to be a macro defined since. Only the
+    // locals its arguments mention are passed in, so closures don't capture
+    // more than they did.
+    fn late_call(&mut self, head: usize, a: &[MalVal]) {
+        let mut names = vec![];
+        for x in a[1..].iter() {
+            symbols(x, &mut names);
+        }
+        let vars = self.visible(&|name| names.contains(&name));
+        let end = self.here();
+        self.cur().proto.calls.push((head, Expansion{sym: a[0].clone(), mac: _nil(),
+                                                     form: list(a.to_vec()), vars: vars,
+                                                     end: end, redone: RefCell::new(None)}));
+    }
+
+    // The slot a def! or defmacro! binds, or None at the top level
+    fn def_slot(&mut self, name: SymbolId) -> Option<usize> {
+        if self.cur().scopes.is_empty() {
+            None
+        } else {
+            Some(self.declare(name, false))
+        }
+    }
+
+    fn def_form(&mut self, a: &[MalVal]) -> Result<(),MalError> {
+        let name = match *a[1] {
+            Sym(name) => name,
+            _ => return self.fail("def! of non-symbol"),
+        };
+        let slot = self.def_slot(name);
+        self.expr(&a[2], false)?;
+        self.store(&a[1], slot);
+        Ok(())
+    }
+
+    fn defmacro_form(&mut self, a: &[MalVal]) -> Result<(),MalError> {
+        let name = match *a[1] {
+            Sym(name) => name,
+            _ => return self.fail("def! of non-symbol"),
+        };
+        let slot = self.def_slot(name);
+        self.expr(&a[2], false)?;
+        self.emit(Op::Macro);
+        self.store(&a[1], slot);
+        Ok(())
+    }
+
+    // Binds the value on top, leaving it there
+    fn store(&mut self, sym: &MalVal, slot: Option<usize>) {
+        let op = match slot {
+            Some(slot) => Op::DefLocal(slot),
+            None => Op::DefGlobal(self.constant(sym.clone())),
+        };
+        self.emit(op);
+    }
+
+    fn let_form(&mut self, a: &[MalVal], tail: bool) -> Result<(),MalError> {
+        let binds = match *a[1] {
+            List(ref binds,_) | Vector(ref binds,_) => binds.to_vec(),
+            _ => return self.fail("let* with non-list bindings"),
+        };
+        let mut pairs = vec![];
+        for bv in binds.chunks(2).filter(|bv| bv.len() == 2) {
+            match *bv[0] {
+                Sym(name) => pairs.push((name, bv[1].clone())),
+                _ => return self.fail("let* with non-symbol binding"),
+            }
+        }
+        self.cur().scopes.push(vec![]);
+        for &(name, _) in pairs.iter() {
+            self.declare(name, false);
+        }
+        let code: Vec<&MalVal> = pairs.iter().map(|&(_, ref val)| val).chain(Some(&a[2])).collect();
+        self.declare_defs(&code);
+        for &(name, ref val) in pairs.iter() {
+            self.expr(val, false)?;
+            let slot = self.declare(name, false);
+            self.emit(Op::SetLocal(slot));
+            self.mark_bound(name);
+        }
+        self.expr(&a[2], tail)?;
+        self.cur().scopes.pop();
+        Ok(())
+    }
+
+    fn do_form(&mut self, a: &[MalVal], tail: bool) -> Result<(),MalError> {
+        if a.len() == 1 {
+            let c = self.constant(_nil());
+            self.emit(Op::Const(c));
+            return Ok(());
+        }
+        for x in a[1..a.len()-1].iter() {
+            self.expr(x, false)?;
+            self.emit(Op::Pop);
+        }
+        self.expr(&a[a.len()-1], tail)
+    }
+
+    fn if_form(&mut self, a: &[MalVal], tail: bool) -> Result<(),MalError> {
+        self.expr(&a[1], false)?;
+        let to_else = self.emit(Op::JumpIfFalse(0));
+        self.expr(&a[2], tail)?;
+        let to_end = self.emit(Op::Jump(0));
+        self.patch(to_else);
+        if a.len() == 4 {
+            self.expr(&a[3], tail)?;
+        } else {
+            let c = self.constant(_nil());
+            self.emit(Op::Const(c));
+        }
+        self.patch(to_end);
+        Ok(())
+    }
+
+    fn fn_form(&mut self, a: &[MalVal]) -> Result<(),MalError> {
+        let params = match *a[1] {
+            List(ref params,_) | Vector(ref params,_) => params,
+            _ => return self.fail("fn* with non-list parameters"),
+        };
+        let mut names = vec![];
+        let mut variadic = false;
+        for (i, p) in params.iter().enumerate() {
+            match **p {
+                Sym(AMPERSAND) if i + 2 == params.len() => variadic = true,
+                Sym(AMPERSAND) => return self.fail("& must be followed by one parameter"),
+                Sym(name) => names.push(name),
+                _ => return self.fail("non-symbol bind"),
+            }
+        }
+        let arity = if variadic { names.len() - 1 } else { names.len() };
+        self.fns.push(FnState::new(a[1].clone(), a[2].clone(), arity, variadic));
+        self.cur().scopes.push(vec![]);
+        for name in names {
+            self.declare(name, true);
+        }
+        self.declare_defs(&[&a[2]]);
+        self.expr(&a[2], true)?;
+        self.emit(Op::Return);
+        let inner = self.fns.pop().unwrap().proto;
+        let protos = &mut self.cur().proto.protos;
+        protos.push(Rc::new(inner));
+        let i = protos.len() - 1;
+        self.emit(Op::Closure(i));
+        Ok(())
+    }
+
+    fn try_form(&mut self, a: &[MalVal], tail: bool) -> Result<(),MalError> {
+        let clause = match *a[2] {
+            List(ref cat,_) if cat.len() == 3 => match *cat[1] {
+                Sym(exc) => Ok((exc, cat[2].clone())),
+                _ => Err("invalid catch* binding"),
+            },
+            List(_,_) => Err("wrong arity to catch* clause"),
+            _ => Err("invalid catch* clause"),
+        };
+        let exc = match clause {
+            Ok((exc, _)) => exc,
+            Err(_) => CATCH,
+        };
+        let slot = self.new_slot(exc);
+        let start = self.emit(Op::Try(0, slot));
+        self.expr(&a[1], false)?;
+        self.emit(Op::EndTry);
+        let to_end = self.emit(Op::Jump(0));
+        self.patch(start);
+        match clause {
+            Ok((exc, handler)) => {
+                self.cur().scopes.push(vec![Var{name: exc, slot: slot, bound: true}]);
+                self.declare_defs(&[&handler]);
+                self.expr(&handler, tail)?;
+                self.cur().scopes.pop();
+            },
+            // a bad catch* clause is only reported if there is something to catch
+            Err(msg) => self.fail(msg)?,
+        }
+        self.patch(to_end);
+        Ok(())
+    }
+}
+
+// Adds the symbols in ast to names
+fn symbols(ast: &MalVal, names: &mut Vec<SymbolId>) {
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => return,
+    };
+    match **ast {
+        Sym(name) if !names.contains(&name) => names.push(name),
+        List(ref a,_) | Vector(ref a,_) => {
+            for x in a.iter() {
+                symbols(x, names);
+            }
+        },
+        Hash_Map(ref hm,_) => {
+            for v in hm.values() {
+                symbols(v, names);
+            }
+        },
+        _ => (),
+    }
+}
@@ -1,24 +1,31 @@
 #![allow(dead_code)]
 
-extern crate time;
 use std::collections::HashMap;
-use std::io::File;
-
-use types::{MalVal,MalRet,err_val,err_str,err_string,
-            Nil,Int,Strn,List,Vector,Hash_Map,Func,MalFunc,Atom,
-            _nil,_true,_false,_int,string,
-            list,vector,listm,vectorm,hash_mapm,func,funcm,malfuncd};
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Ordering;
+use std::cell::RefCell;
+
+use types::{MalVal,MalRet,MalError,ErrString,ErrIncomplete,err_val,err_str,err_string,
+            Nil,Int,Bignum,Rational,Float,Strn,List,Vector,Hash_Map,Func,MalFunc,Atom,
+            _nil,_true,_false,_int,_bigint,_rational,_float,string,
+            list,listm,vectorm,hash_map,hash_mapm,func,funcm,malfuncd};
 use types;
+use pmap::PMap;
 use readline;
 use reader;
 use printer;
+use gc;
+use env::Env;
+use bignum::{BigInt,Ratio};
 
 // General functions
 fn equal_q(a:Vec<MalVal>) -> MalRet {
-    if a.len() != 2 {
-        return err_str("Wrong arity to equal? call");
+    if a.is_empty() {
+        return err_str("Wrong arity to = call");
     }
-    match a[0] == a[1] {
+    match a.windows(2).all(|pair| pair[0] == pair[1]) {
         true => Ok(_true()),
         false => Ok(_false()),
     }
@@ -32,6 +39,18 @@ fn throw(a:Vec<MalVal>) -> MalRet {
     err_val(a[0].clone())
 }
 
+// (ex-trace e) lists the calls the error catch* bound to e came out of,
+// innermost first, or gives nil if e didn't come from catch*
+fn ex_trace(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to ex-trace call");
+    }
+    match types::trace_of(&a[0]) {
+        Some(trace) => Ok(list(trace.iter().map(|f| string(f.to_string())).collect())),
+        None => Ok(_nil()),
+    }
+}
+
 // String routines
 fn pr_str(a:Vec<MalVal>) -> MalRet {
     Ok(string(printer::pr_list(&a, true, "", "", " ")))
@@ -42,18 +61,18 @@ fn str(a:Vec<MalVal>) -> MalRet {
 }
 
 fn prn(a:Vec<MalVal>) -> MalRet {
-    println!("{}", printer::pr_list(&a, true, "", "", " "))
+    println!("{}", printer::pr_list(&a, true, "", "", " "));
     Ok(_nil())
 }
 
 fn println(a:Vec<MalVal>) -> MalRet {
-    println!("{}", printer::pr_list(&a, false, "", "", " "))
+    println!("{}", printer::pr_list(&a, false, "", "", " "));
     Ok(_nil())
 }
 
 fn readline(a:Vec<MalVal>) -> MalRet {
     match *a[0] {
-        Strn(ref a0) => match readline::mal_readline(a0.as_slice()) {
+        Strn(ref a0) => match readline::mal_readline(a0) {
             Some(line) => Ok(string(line)),
             None       => err_val(_nil()),
         },
@@ -68,11 +87,94 @@ fn read_string(a:Vec<MalVal>) -> MalRet {
     }
 }
 
+fn read_all_string(a:Vec<MalVal>) -> MalRet {
+    match *a[0] {
+        Strn(ref a0) => {
+            let forms = reader::read_all(a0)?;
+            Ok(list(forms.into_iter().map(|f| f.value).collect()))
+        },
+        _ => err_str("read_all_string called with non-string"),
+    }
+}
+
+// (load-string source name evaluator) calls evaluator on each form read
+// from source in turn and returns the last result. Errors say which form
+// failed and where, using name as the file name, and functions made by a
+// form take its position as theirs.
+fn load_string(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 3 {
+        return err_str("Wrong arity to load-string call");
+    }
+    match (&*a[0], &*a[1]) {
+        (&Strn(ref source), &Strn(ref name)) => {
+            load_forms(source, name, &|form| a[2].apply(vec![form]))
+        },
+        _ => err_str("load-string called with non-string"),
+    }
+}
+
+fn load_forms(source: &str, name: &str, eval: &dyn Fn(MalVal) -> MalRet) -> MalRet {
+    let forms = match reader::read_all(source) {
+        Ok(forms) => forms,
+        Err(ErrString(s)) | Err(ErrIncomplete(s)) => {
+            return err_string(format!("{}: {}", name, s))
+        },
+        Err(e) => return Err(e),
+    };
+    let mut res = _nil();
+    for (i, form) in forms.into_iter().enumerate() {
+        let pos = format!("{}:{}:{}", name, form.line, form.column);
+        let outer = types::set_source(Some(pos.into()));
+        let r = eval(form.value);
+        types::set_source(outer);
+        res = match r {
+            Ok(v) => v,
+            Err(ErrString(s)) => {
+                return err_string(format!("in form {} at {}:{}:{}: {}",
+                                          i+1, name, form.line, form.column, s))
+            },
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(res)
+}
+
+// The evaluator and environment stepA's load-file runs each form with.
+// Being a builtin rather than a mal function, load-file leaves no frame of
+// its own in the backtrace of an error out of the file.
+type Evaluator = fn(MalVal, Env) -> MalRet;
+
+thread_local! {
+    static LOADER: RefCell<Option<(Evaluator, Env)>> = const { RefCell::new(None) };
+}
+
+pub fn set_loader(evaluator: Evaluator, env: Env) {
+    LOADER.with(|l| *l.borrow_mut() = Some((evaluator, env)));
+}
+
+pub fn load_file(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to load-file call");
+    }
+    let (evaluator, env) = match LOADER.with(|l| l.borrow().clone()) {
+        Some(loader) => loader,
+        None => return err_str("load-file called before set_loader"),
+    };
+    let source = slurp(a.clone())?;
+    match (&*source, &*a[0]) {
+        (&Strn(ref source), &Strn(ref name)) => {
+            load_forms(source, name, &|form| evaluator(form, env.clone()))
+        },
+        _ => err_str("load-file called with non-string"),
+    }
+}
+
 fn slurp(a:Vec<MalVal>) -> MalRet {
     match *a[0] {
         Strn(ref a0) => {
-            match File::open(&Path::new(a0.as_slice())).read_to_string() {
-                Ok(s) => Ok(string(s)),
+            let mut s = String::new();
+            match File::open(a0).and_then(|mut f| f.read_to_string(&mut s)) {
+                Ok(_) => Ok(string(s)),
                 Err(e) => err_string(e.to_string()),
             }
         },
@@ -82,49 +184,250 @@ fn slurp(a:Vec<MalVal>) -> MalRet {
 
 
 // Numeric functions
-fn int_op(f: |i:int,j:int|-> int, a:Vec<MalVal>) -> MalRet {
-    match *a[0] {
-        Int(a0) => match *a[1] {
-            Int(a1) => Ok(_int(f(a0,a1))),
-            _ => err_str("second arg must be an int"),
+// Arithmetic on two Ints is checked. If it overflows, or either arg is a
+// Bignum or Rational, it's redone exactly on Ratios and the result turned
+// back into the simplest type that holds it. If either arg is a Float, both
+// are converted to floats instead.
+fn num_op<F, G, H>(f: F, g: G, h: H, a: &MalVal, b: &MalVal) -> MalRet
+    where F: Fn(isize,isize) -> Option<isize>, G: Fn(&Ratio,&Ratio) -> Ratio,
+          H: Fn(f64,f64) -> f64 {
+    match (&**a, &**b) {
+        (&Int(i), &Int(j)) => {
+            if let Some(r) = f(i,j) { return Ok(_int(r)); }
+        },
+        (&Float(_), _) | (_, &Float(_)) => {
+            return match (to_f64(a), to_f64(b)) {
+                (Some(x), Some(y)) => Ok(_float(h(x,y))),
+                _ => err_str("args must be numbers"),
+            };
         },
-        _ => err_str("first arg must be an int"),
+        _ => (),
+    }
+    match (to_ratio(a), to_ratio(b)) {
+        (Some(i), Some(j)) => Ok(_rational(g(&i,&j))),
+        _ => err_str("args must be numbers"),
+    }
+}
+
+fn to_ratio(a: &MalVal) -> Option<Ratio> {
+    match **a {
+        Int(i) => Some(Ratio::from_bigint(BigInt::from_i64(i as i64))),
+        Bignum(ref b) => Some(Ratio::from_bigint(b.clone())),
+        Rational(ref r) => Some(r.clone()),
+        _ => None,
+    }
+}
+
+fn to_f64(a: &MalVal) -> Option<f64> {
+    match **a {
+        Int(i) => Some(i as f64),
+        Bignum(ref b) => Some(b.to_f64()),
+        Rational(ref r) => Some(r.to_f64()),
+        Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+// Like Clojure, arithmetic folds over any number of args from the left;
+// identity is the result with no args, if that's allowed
+fn fold_op(name: &str, identity: Option<MalVal>,
+           op: fn(&MalVal, &MalVal) -> MalRet, a:Vec<MalVal>) -> MalRet {
+    if a.is_empty() {
+        return match identity {
+            Some(v) => Ok(v),
+            None => err_string(format!("Wrong arity to {} call", name)),
+        };
+    }
+    if to_f64(&a[0]).is_none() { return err_str("args must be numbers"); }
+    let mut acc = a[0].clone();
+    for b in a[1..].iter() {
+        acc = op(&acc, b)?;
+    }
+    Ok(acc)
+}
+
+fn add2(a: &MalVal, b: &MalVal) -> MalRet { num_op(isize::checked_add, |i,j| { i+j }, |x,y| { x+y }, a, b) }
+fn sub2(a: &MalVal, b: &MalVal) -> MalRet { num_op(isize::checked_sub, |i,j| { i-j }, |x,y| { x-y }, a, b) }
+fn mul2(a: &MalVal, b: &MalVal) -> MalRet { num_op(isize::checked_mul, |i,j| { i*j }, |x,y| { x*y }, a, b) }
+
+// ints that don't divide evenly give a Rational
+fn div2(a: &MalVal, b: &MalVal) -> MalRet {
+    // zero is always an Int; floats divide to ##Inf or ##NaN
+    if let (&Int(0), false) = (&**b, is_float(a)) {
+        return err_str("divide by zero");
     }
+    num_op(|i,j| { i.checked_rem(j).and_then(|r| if r == 0 { i.checked_div(j) } else { None }) },
+           |i,j| { i/j }, |x,y| { x/y }, a, b)
+}
+
+pub fn add(a:Vec<MalVal>) -> MalRet { fold_op("+", Some(_int(0)), add2, a) }
+pub fn mul(a:Vec<MalVal>) -> MalRet { fold_op("*", Some(_int(1)), mul2, a) }
+pub fn sub(a:Vec<MalVal>) -> MalRet {
+    // (- x) negates; multiplying keeps the sign of -0.0
+    if a.len() == 1 { return mul2(&_int(-1), &a[0]); }
+    fold_op("-", None, sub2, a)
+}
+pub fn div(a:Vec<MalVal>) -> MalRet {
+    // (/ x) is the reciprocal
+    if a.len() == 1 { return div2(&_int(1), &a[0]); }
+    fold_op("/", None, div2, a)
+}
+
+fn is_float(a: &MalVal) -> bool {
+    matches!(**a, Float(_))
+}
+
+// quot, rem and mod raise divide by zero for floats too, as in Clojure
+fn zero_divisor(b: &MalVal) -> bool {
+    match **b {
+        Int(0) => true,
+        Float(f) => f == 0.0,
+        _ => false,
+    }
+}
+
+fn exact_quot(i: &Ratio, j: &Ratio) -> Ratio {
+    Ratio::from_bigint((i/j).trunc())
+}
+
+fn exact_rem(i: &Ratio, j: &Ratio) -> Ratio {
+    i - &(&exact_quot(i,j) * j)
+}
+
+// division truncating towards zero
+pub fn quot(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 2 { return err_str("Wrong arity to quot call"); }
+    if zero_divisor(&a[1]) { return err_str("divide by zero"); }
+    num_op(isize::checked_div, exact_quot, |x,y| { (x/y).trunc() }, &a[0], &a[1])
+}
+
+// remainder of quot, with the sign of the dividend
+pub fn rem(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 2 { return err_str("Wrong arity to rem call"); }
+    if zero_divisor(&a[1]) { return err_str("divide by zero"); }
+    num_op(isize::checked_rem, exact_rem, |x,y| { x%y }, &a[0], &a[1])
+}
+
+// remainder of flooring division, with the sign of the divisor
+pub fn modulo(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 2 { return err_str("Wrong arity to mod call"); }
+    if zero_divisor(&a[1]) { return err_str("divide by zero"); }
+    num_op(|i,j| {
+               i.checked_rem(j).map(|r| if r != 0 && (r < 0) != (j < 0) { r+j } else { r })
+           },
+           |i,j| {
+               let r = exact_rem(i,j);
+               if !r.is_zero() && r.is_negative() != j.is_negative() { &r+j } else { r }
+           },
+           |x,y| {
+               let r = x%y;
+               if r != 0.0 && (r < 0.0) != (y < 0.0) { r+y } else { r }
+           }, &a[0], &a[1])
 }
 
-fn bool_op(f: |i:int,j:int|-> bool, a:Vec<MalVal>) -> MalRet {
+pub fn int(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 1 { return err_str("Wrong arity to int call"); }
     match *a[0] {
-        Int(a0) => match *a[1] {
-            Int(a1) => {
-                match f(a0,a1) {
-                    true => Ok(_true()),
-                    false => Ok(_false()), 
-                }
-            },
-            _ => err_str("second arg must be an int"),
+        Int(_) | Bignum(_) => Ok(a[0].clone()),
+        Rational(ref r) => Ok(_bigint(r.trunc())),
+        Float(f) if f.is_finite() => Ok(_bigint(BigInt::from_f64(f))),
+        Float(_) => err_string(format!("cannot convert {} to an int", a[0].pr_str(true))),
+        _ => err_str("int called with non-number"),
+    }
+}
+
+pub fn double(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 1 { return err_str("Wrong arity to double call"); }
+    match to_f64(&a[0]) {
+        Some(f) => Ok(_float(f)),
+        None => err_str("double called with non-number"),
+    }
+}
+
+pub fn numerator(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 1 { return err_str("Wrong arity to numerator call"); }
+    match *a[0] {
+        Int(_) | Bignum(_) => Ok(a[0].clone()),
+        Rational(ref r) => Ok(_bigint(r.numer().clone())),
+        _ => err_str("numerator called with non-rational"),
+    }
+}
+
+pub fn denominator(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 1 { return err_str("Wrong arity to denominator call"); }
+    match *a[0] {
+        Int(_) | Bignum(_) => Ok(_int(1)),
+        Rational(ref r) => Ok(_bigint(r.denom().clone())),
+        _ => err_str("denominator called with non-rational"),
+    }
+}
+
+// None if either is NaN
+fn compare(a: &MalVal, b: &MalVal) -> Result<Option<Ordering>,MalError> {
+    match (&**a, &**b) {
+        (&Int(i), &Int(j)) => Ok(Some(i.cmp(&j))),
+        (&Float(_), _) | (_, &Float(_)) => match (to_f64(a), to_f64(b)) {
+            (Some(x), Some(y)) => Ok(x.partial_cmp(&y)),
+            _ => Err(ErrString("args must be numbers".to_string())),
+        },
+        _ => match (to_ratio(a), to_ratio(b)) {
+            (Some(i), Some(j)) => Ok(Some(i.cmp(&j))),
+            _ => Err(ErrString("args must be numbers".to_string())),
         },
-        _ => err_str("first arg must be an int"),
     }
 }
 
-pub fn add(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i+j }, a) }
-pub fn sub(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i-j }, a) }
-pub fn mul(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i*j }, a) }
-pub fn div(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i/j }, a) }
+// Like Clojure, comparisons take one or more args and are true if every
+// adjacent pair is ordered, so (< 1 2 3) is true. Comparisons involving
+// NaN are always false.
+fn bool_op<F: Fn(Ordering) -> bool>(name: &str, f: F, a:Vec<MalVal>) -> MalRet {
+    if a.is_empty() {
+        return err_string(format!("Wrong arity to {} call", name));
+    }
+    for pair in a.windows(2) {
+        match compare(&pair[0], &pair[1])? {
+            Some(o) if f(o) => (),
+            _ => return Ok(_false()),
+        }
+    }
+    Ok(_true())
+}
 
-pub fn lt (a:Vec<MalVal>) -> MalRet { bool_op(|i,j| { i<j }, a) }
-pub fn lte(a:Vec<MalVal>) -> MalRet { bool_op(|i,j| { i<=j }, a) }
-pub fn gt (a:Vec<MalVal>) -> MalRet { bool_op(|i,j| { i>j }, a) }
-pub fn gte(a:Vec<MalVal>) -> MalRet { bool_op(|i,j| { i>=j }, a) }
+pub fn lt (a:Vec<MalVal>) -> MalRet { bool_op("<", |o| { o == Ordering::Less }, a) }
+pub fn lte(a:Vec<MalVal>) -> MalRet { bool_op("<=", |o| { o != Ordering::Greater }, a) }
+pub fn gt (a:Vec<MalVal>) -> MalRet { bool_op(">", |o| { o == Ordering::Greater }, a) }
+pub fn gte(a:Vec<MalVal>) -> MalRet { bool_op(">=", |o| { o != Ordering::Less }, a) }
 
-#[allow(unused_variable)]
+#[allow(unused_variables)]
 pub fn time_ms(a:Vec<MalVal>) -> MalRet {
-    //let x = time::now();
-    let now = time::get_time();
-    let now_ms = (now.sec * 1000).to_int().unwrap() + (now.nsec.to_int().unwrap() / 1000000);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let now_ms = (now.as_secs() * 1000) as isize + now.subsec_millis() as isize;
     Ok(_int(now_ms))
 }
 
+// (gc) frees the cycles of environments and closures that nothing else
+// refers to (see gc.rs) and returns how many objects they held
+fn gc(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 0 {
+        return err_str("Wrong arity to gc call");
+    }
+    Ok(_int(gc::collect() as isize))
+}
+
+// (gc-stats) gives the environments alive as :envs, the times the
+// collector has run as :collections and the objects it has freed as :freed
+fn gc_stats(a:Vec<MalVal>) -> MalRet {
+    if a.len() != 0 {
+        return err_str("Wrong arity to gc-stats call");
+    }
+    let stats = gc::stats();
+    let mut hm = PMap::new();
+    hm.insert("\u{29e}envs".to_string(), _int(stats.envs as isize));
+    hm.insert("\u{29e}collections".to_string(), _int(stats.collections as isize));
+    hm.insert("\u{29e}freed".to_string(), _int(stats.freed as isize));
+    Ok(hash_map(hm))
+}
+
 
 // Hash Map functions
 pub fn assoc(a:Vec<MalVal>) -> MalRet {
@@ -133,10 +436,10 @@ pub fn assoc(a:Vec<MalVal>) -> MalRet {
     }
     match *a[0] {
         Hash_Map(ref hm,_) => {
-            types::_assoc(hm, a.slice(1,a.len()).to_vec())
+            types::_assoc(hm, a[1..a.len()].to_vec())
         },
         Nil => {
-            types::hash_mapv(a.slice(1,a.len()).to_vec())
+            types::hash_mapv(a[1..a.len()].to_vec())
         }
         _ => return err_str("assoc onto non-hash map"),
     }
@@ -148,7 +451,7 @@ pub fn dissoc(a:Vec<MalVal>) -> MalRet {
     }
     match *a[0] {
         Hash_Map(ref hm,_) => {
-            types::_dissoc(hm, a.slice(1,a.len()).to_vec())
+            types::_dissoc(hm, a[1..a.len()].to_vec())
         },
         Nil => {
             Ok(_nil())
@@ -162,14 +465,14 @@ pub fn get(a:Vec<MalVal>) -> MalRet {
         return err_str("Wrong arity to get call");
     }
     let a0 = a[0].clone();
-    let hm: &HashMap<String,MalVal> = match *a0 {
+    let hm: &PMap<MalVal> = match *a0 {
         Hash_Map(ref hm,_) => hm,
         Nil => return Ok(_nil()),
         _ => return err_str("get on non-hash map"),
     };
     match *a[1] {
         Strn(ref key) => {
-            match hm.find_copy(key) {
+            match hm.get(key).cloned() {
                 Some(v) => Ok(v),
                 None    => Ok(_nil()),
             }
@@ -183,7 +486,7 @@ pub fn contains_q(a:Vec<MalVal>) -> MalRet {
         return err_str("Wrong arity to contains? call");
     }
     let a0 = a[0].clone();
-    let hm: &HashMap<String,MalVal> = match *a0 {
+    let hm: &PMap<MalVal> = match *a0 {
         Hash_Map(ref hm,_) => hm,
         Nil => return Ok(_false()),
         _ => return err_str("contains? on non-hash map"),
@@ -204,7 +507,7 @@ pub fn keys(a:Vec<MalVal>) -> MalRet {
         return err_str("Wrong arity to keys call");
     }
     let a0 = a[0].clone();
-    let hm: &HashMap<String,MalVal> = match *a0 {
+    let hm: &PMap<MalVal> = match *a0 {
         Hash_Map(ref hm,_) => hm,
         Nil => return Ok(_nil()),
         _ => return err_str("contains? on non-hash map"),
@@ -222,7 +525,7 @@ pub fn vals(a:Vec<MalVal>) -> MalRet {
         return err_str("Wrong arity to values call");
     }
     let a0 = a[0].clone();
-    let hm: &HashMap<String,MalVal> = match *a0 {
+    let hm: &PMap<MalVal> = match *a0 {
         Hash_Map(ref hm,_) => hm,
         Nil => return Ok(_nil()),
         _ => return err_str("contains? on non-hash map"),
@@ -241,8 +544,8 @@ pub fn cons(a:Vec<MalVal>) -> MalRet {
     match *a[1] {
         List(ref v,_) | Vector(ref v,_) => {
             let mut new_v = v.clone();
-            new_v.insert(0, a[0].clone());
-            Ok(list(new_v))
+            new_v.push_front(a[0].clone());
+            Ok(listm(new_v,_nil()))
         },
         _ => err_str("Second arg to cons not a sequence"),
     }
@@ -253,7 +556,7 @@ pub fn concat(a:Vec<MalVal>) -> MalRet {
     for lst in a.iter() {
         match **lst {
             List(ref l,_) | Vector(ref l,_) => {
-                new_v.push_all(l.as_slice());
+                new_v.extend(l.iter().cloned());
             },
             _ => return err_str("concat called with non-sequence"),
         }
@@ -273,10 +576,10 @@ pub fn nth(a:Vec<MalVal>) -> MalRet {
     };
     let idx = match *a1 {
         Int(i) => {
-            match i.to_uint() {
-                Some(ui) => ui,
-                None => return Ok(_nil()),
+            if i < 0 {
+                return Ok(_nil());
             }
+            i as usize
         },
         _ => return err_str("nth called with non-integer index"),
     };
@@ -312,11 +615,9 @@ pub fn rest(a:Vec<MalVal>) -> MalRet {
         List(ref v,_) | Vector(ref v,_) => v,
         _ => return err_str("rest called with non-sequence"),
     };
-    if seq.len() == 0 {
-        Ok(list(vec![]))
-    } else {
-        Ok(list(seq.slice(1,seq.len()).to_vec()))
-    }
+    let mut new_v = seq.clone();
+    new_v.pop_front();
+    Ok(listm(new_v,_nil()))
 }
 
 pub fn empty_q(a:Vec<MalVal>) -> MalRet {
@@ -340,7 +641,7 @@ pub fn count(a:Vec<MalVal>) -> MalRet {
     }
     match *a[0].clone() {
         List(ref v,_) | Vector(ref v,_) => {
-            Ok(_int(v.len().to_int().unwrap()))
+            Ok(_int(v.len() as isize))
         },
         Nil => Ok(_int(0)),
         _ => err_str("count called on non-sequence"),
@@ -352,10 +653,10 @@ pub fn apply(a:Vec<MalVal>) -> MalRet {
         return err_str("apply call needs 2 or more arguments");
     }
     let ref f = a[0];
-    let mut args = a.slice(1,a.len()-1).to_vec();
+    let mut args = a[1..a.len()-1].to_vec();
     match *a[a.len()-1] {
         List(ref v,_) | Vector(ref v,_) => {
-            args.push_all(v.as_slice());
+            args.extend(v.iter().cloned());
             f.apply(args)
         },
         _ => err_str("apply call with non-sequence"),
@@ -387,21 +688,20 @@ pub fn conj(a:Vec<MalVal>) -> MalRet {
     if a.len() < 2 {
         return err_str("Wrong arity to conj call");
     }
-    let mut new_v:Vec<MalVal> = vec![];
     match *a[0].clone() {
         List(ref l,_) => {
-            new_v.push_all(l.as_slice());
+            let mut new_v = l.clone();
             for mv in a.iter().skip(1) {
-                new_v.insert(0,mv.clone());
+                new_v.push_front(mv.clone());
             }
-            Ok(list(new_v))
+            Ok(listm(new_v,_nil()))
         },
         Vector(ref l,_) => {
-            new_v.push_all(l.as_slice());
+            let mut new_v = l.clone();
             for mv in a.iter().skip(1) {
                 new_v.push(mv.clone());
             }
-            Ok(vector(new_v))
+            Ok(vectorm(new_v,_nil()))
         },
         _ => return err_str("conj called with non-sequence"),
     }
@@ -460,9 +760,7 @@ fn reset_bang(a:Vec<MalVal>) -> MalRet {
     let a1 = a[1].clone();
     match *a[0].clone() {
         Atom(ref val) => {
-            let mut val_cell = val.borrow_mut();
-            let atm_mv = val_cell.deref_mut();
-            *atm_mv = a1.clone();
+            *val.borrow_mut() = a1.clone();
             Ok(a1)
         },
         _ => err_str("reset! called on non-atom"),
@@ -476,13 +774,11 @@ fn swap_bang(a:Vec<MalVal>) -> MalRet {
     let f = a[1].clone();
     match *a[0].clone() {
         Atom(ref val) => {
-            let mut val_cell = val.borrow_mut();
-            let atm_mv = val_cell.deref_mut();
-            let mut args = a.slice(2,a.len()).to_vec();
-            args.insert(0, atm_mv.clone());
+            let mut args = a[2..a.len()].to_vec();
+            args.insert(0, val.borrow().clone());
             match f.apply(args) {
                 Ok(new_mv) => {
-                    *atm_mv = new_mv.clone();
+                    *val.borrow_mut() = new_mv.clone();
                     Ok(new_mv)
                 }
                 Err(e) => Err(e),
@@ -494,10 +790,11 @@ fn swap_bang(a:Vec<MalVal>) -> MalRet {
 
 
 pub fn ns() -> HashMap<String,MalVal> {
-    let mut ns: HashMap<String,MalVal> = HashMap::new();;
+    let mut ns: HashMap<String,MalVal> = HashMap::new();
 
     ns.insert("=".to_string(), func(equal_q));
     ns.insert("throw".to_string(), func(throw));
+    ns.insert("ex-trace".to_string(), func(ex_trace));
     ns.insert("nil?".to_string(), func(types::nil_q));
     ns.insert("true?".to_string(), func(types::true_q));
     ns.insert("false?".to_string(), func(types::false_q));
@@ -512,6 +809,8 @@ pub fn ns() -> HashMap<String,MalVal> {
     ns.insert("println".to_string(), func(println));
     ns.insert("readline".to_string(), func(readline));
     ns.insert("read-string".to_string(), func(read_string));
+    ns.insert("read-all-string".to_string(), func(read_all_string));
+    ns.insert("load-string".to_string(), func(load_string));
     ns.insert("slurp".to_string(), func(slurp));
 
     ns.insert("<".to_string(),  func(lt));
@@ -522,7 +821,16 @@ pub fn ns() -> HashMap<String,MalVal> {
     ns.insert("-".to_string(), func(sub));
     ns.insert("*".to_string(), func(mul));
     ns.insert("/".to_string(), func(div));
+    ns.insert("quot".to_string(), func(quot));
+    ns.insert("rem".to_string(), func(rem));
+    ns.insert("mod".to_string(), func(modulo));
+    ns.insert("int".to_string(), func(int));
+    ns.insert("double".to_string(), func(double));
+    ns.insert("numerator".to_string(), func(numerator));
+    ns.insert("denominator".to_string(), func(denominator));
     ns.insert("time-ms".to_string(), func(time_ms));
+    ns.insert("gc".to_string(), func(gc));
+    ns.insert("gc-stats".to_string(), func(gc_stats));
 
     ns.insert("list".to_string(), func(types::listv));
     ns.insert("list?".to_string(), func(types::list_q));
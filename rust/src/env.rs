@@ -2,20 +2,76 @@
 
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::fmt;
 
-use types::{MalVal,MalRet,Sym,List,Vector,_nil,list,err_string};
+use types::{MalVal,MalRet,Sym,List,Vector,list,err_string};
+use intern::{SymbolId,SymbolMap,AMPERSAND};
+use gc;
+use gc::{Ref,push_val};
 
-struct EnvType {
-    data: HashMap<String,MalVal>,
+pub struct EnvType {
+    // a frame has a slot for each of names, which is shared by every frame
+    // made for the same fn* or let* so that resolved code (see resolve.rs)
+    // can refer to a slot by its index
+    names: Rc<Vec<SymbolId>>,
+    slots: Vec<Option<MalVal>>,
+    // the root environment, and anything set that the frame has no slot for
+    data: SymbolMap<MalVal>,
     outer: Option<Env>,
 }
 
+impl EnvType {
+    // What the frame refers to, for the collector
+    pub fn trace(&self, refs: &mut Vec<Ref>) {
+        for v in self.slots.iter().flatten().chain(self.data.values()) {
+            push_val(v, refs);
+        }
+        if let Some(ref outer) = self.outer {
+            refs.push(Ref::Env(outer.clone()));
+        }
+    }
+
+    // Empties the frame to break a cycle it's in, giving back what it held
+    pub fn clear(&mut self) -> EnvType {
+        let slots = vec![None; self.names.len()];
+        std::mem::replace(self, EnvType{names: self.names.clone(), slots: slots,
+                                        data: SymbolMap::default(), outer: None})
+    }
+
+    fn lookup(&self, k: SymbolId) -> Option<MalVal> {
+        match self.names.iter().position(|&n| n == k) {
+            Some(i) if self.slots[i].is_some() => self.slots[i].clone(),
+            _ => self.data.get(&k).cloned(),
+        }
+    }
+}
+
 pub type Env = Rc<RefCell<EnvType>>;
 
 pub fn env_new(outer: Option<Env>) -> Env {
-    Rc::new(RefCell::new(EnvType{data: HashMap::new(), outer: outer}))
+    env_frame(outer, Rc::new(vec![]))
+}
+
+pub fn env_frame(outer: Option<Env>, names: Rc<Vec<SymbolId>>) -> Env {
+    let slots = vec![None; names.len()];
+    let env = Rc::new(RefCell::new(EnvType{names: names, slots: slots,
+                                           data: SymbolMap::default(), outer: outer}));
+    gc::track_env(&env);
+    env
+}
+
+// The slot names for a function's frames: its parameters, less the &
+pub fn param_names(mbinds: &MalVal) -> Rc<Vec<SymbolId>> {
+    let names = match **mbinds {
+        List(ref binds,_) | Vector(ref binds,_) => {
+            binds.iter().filter_map(|b| match **b {
+                Sym(s) if s != AMPERSAND => Some(s),
+                _ => None,
+            }).collect()
+        },
+        _ => vec![],
+    };
+    Rc::new(names)
 }
 
 pub fn env_bind(env: &Env,
@@ -26,11 +82,20 @@ pub fn env_bind(env: &Env,
         List(ref binds,_) | Vector(ref binds,_) => {
             match *mexprs {
                 List(ref exprs,_) | Vector(ref exprs,_) => {
+                    // each param before any & needs an argument, worded as
+                    // the VM (see vm.rs) words it
+                    let arity = binds.iter()
+                        .position(|b| match **b { Sym(s) => s == AMPERSAND, _ => false })
+                        .unwrap_or(binds.len());
+                    if exprs.len() < arity {
+                        return Err(format!("wrong number of arguments to {}: expected {}, got {}",
+                                           mbinds, arity, exprs.len()));
+                    }
                     let mut it = binds.iter().enumerate();
-                    for (i, b) in it {
+                    for (i, b) in it.by_ref() {
                         match **b {
-                            Sym(ref strn) => {
-                                if *strn == "&".to_string() {
+                            Sym(s) => {
+                                if s == AMPERSAND {
                                     variadic = true;
                                     break;
                                 } else {
@@ -41,10 +106,13 @@ pub fn env_bind(env: &Env,
                         }
                     }
                     if variadic {
-                        let (i, sym) = it.next().unwrap();
+                        let (i, sym) = match (it.next(), it.next()) {
+                            (Some(param), None) => param,
+                            _ => return Err("& must be followed by one parameter".to_string()),
+                        };
                         match **sym {
                             Sym(_) => {
-                                let rest = exprs.slice(i-1,exprs.len()).to_vec();
+                                let rest = exprs.slice(i-1..exprs.len());
                                 env_set(env, sym.clone(), list(rest));
                             }
                             _ => return Err("& bind to non-symbol".to_string()),
@@ -61,8 +129,8 @@ pub fn env_bind(env: &Env,
 
 pub fn env_find(env: Env, key: MalVal) -> Option<Env> {
     match *key {
-        Sym(ref k) => {
-            if env.borrow().data.contains_key(k) {
+        Sym(k) => {
+            if env.borrow().lookup(k).is_some() {
                 Some(env)
             } else {
                 match env.borrow().outer {
@@ -84,8 +152,12 @@ pub fn env_root(env: &Env) -> Env {
 
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) {
     match *key {
-        Sym(ref k) => {
-            env.borrow_mut().data.insert(k.to_string(), val.clone());
+        Sym(k) => {
+            let e = &mut *env.borrow_mut();
+            match e.names.iter().position(|&n| n == k) {
+                Some(i) => e.slots[i] = Some(val),
+                None => { e.data.insert(k, val); },
+            }
         },
         _ => {},
     }
@@ -93,26 +165,45 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) {
 
 pub fn env_get(env: Env, key: MalVal) -> MalRet {
     match *key {
-        Sym(ref k) => {
-            match env_find(env, key.clone()) {
-                Some(e) => {
-                    match e.borrow().data.find_copy(k) {
-                        Some(v) => Ok(v),
-                        None => Ok(_nil()),
+        Sym(k) => {
+            let mut e = env;
+            loop {
+                let outer = {
+                    let eb = e.borrow();
+                    if let Some(v) = eb.lookup(k) {
+                        return Ok(v);
                     }
-                },
-                None    => err_string("'".to_string() + k.to_string() + "' not found".to_string()),
+                    match eb.outer {
+                        Some(ref o) => o.clone(),
+                        None => return err_string(format!("'{}' not found", k)),
+                    }
+                };
+                e = outer;
             }
         }
         _ => err_string("env_get called with non-symbol key".to_string()),
     }
 }
 
-impl fmt::Show for EnvType {
+// The value in slot of the frame depth levels out, or None if that slot
+// hasn't been bound yet (a def! that hasn't run)
+pub fn env_get_local(env: &Env, depth: usize, slot: usize) -> Option<MalVal> {
+    let e = env.borrow();
+    if depth == 0 {
+        return e.slots.get(slot).cloned().and_then(|v| v);
+    }
+    match e.outer {
+        Some(ref o) => env_get_local(o, depth - 1, slot),
+        None => None,
+    }
+}
+
+impl fmt::Debug for EnvType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let slots: Vec<_> = self.names.iter().zip(self.slots.iter()).collect();
         match self.outer {
-            Some(ref o) => write!(f, "[{}/outer:{}]", self.data, o.borrow()),
-            _ => write!(f, "{}", self.data)
+            Some(ref o) => write!(f, "[{:?}{:?}/outer:{:?}]", slots, self.data, o.borrow()),
+            _ => write!(f, "{:?}", self.data)
         }
     }
 }
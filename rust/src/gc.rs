@@ -0,0 +1,310 @@
+#![allow(dead_code)]
+
+// Collecting the reference cycles Rc can't free.
+//
+// A function keeps the environment it was made in, and that environment
+// (or one inside it) often keeps the function: a let* binding a closure, a
+// def! inside a function body, a closure the VM stores in a boxed local.
+// Nothing ever drops such a cycle, however unreachable it becomes.
+//
+// Every environment, atom and VM upvalue cell is tracked from when it's
+// made, since any cycle has to pass through one of them: the other values
+// are immutable and can only refer to what existed before them. collect
+// walks everything reachable from the tracked objects and counts, for each
+// object it finds, the references to it from the others it found. An
+// object whose strong count is higher than that is referred to from
+// somewhere the walk can't see (the Rust stack, a compiled closure, a value
+// being built), so it's live, as is everything reachable from it. The rest
+// are only referred to by each other and are garbage. Emptying the
+// environments, atoms and cells among them breaks their cycles, and Rc
+// then frees them all.
+//
+// Persistent collections share the nodes of their tries (see pvec.rs and
+// pmap.rs), so each node is an object of its own here, like an environment,
+// and the references to what it holds are counted once however many
+// collections share it.
+//
+// Anything the walk can't look inside only makes what it refers to look
+// live, so the collector can fail to free a cycle but can't free a value
+// still in use. collect runs on its own once enough objects have been
+// tracked since the last run, or when (gc) is called.
+
+use std::rc::{Rc,Weak};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use types::{MalVal,MalType,Bytecode,List,Vector,Hash_Map,MalFunc,Atom,_nil};
+use env::{Env,EnvType};
+
+// A variable the VM has boxed so closures can share it (see vm.rs)
+pub type Upval = Rc<RefCell<Option<MalVal>>>;
+
+// A node of a persistent collection, which other collections may share
+pub trait Shared {
+    // what it refers to
+    fn trace(&self, refs: &mut Vec<Ref>);
+}
+
+// A strong reference the collector follows
+pub enum Ref {
+    Env(Env),
+    Val(MalVal),
+    Bytecode(Rc<dyn Bytecode>),
+    Shared(Rc<dyn Shared>),
+    Upvals(Rc<Vec<Upval>>),
+    Upval(Upval),
+}
+
+enum Tracked {
+    Env(Weak<RefCell<EnvType>>),
+    Atom(Weak<MalType>),
+    Upval(Weak<RefCell<Option<MalVal>>>),
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Ref> {
+        match *self {
+            Tracked::Env(ref w) => w.upgrade().map(Ref::Env),
+            Tracked::Atom(ref w) => w.upgrade().map(Ref::Val),
+            Tracked::Upval(ref w) => w.upgrade().map(Ref::Upval),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        match *self {
+            Tracked::Env(ref w) => w.strong_count() > 0,
+            Tracked::Atom(ref w) => w.strong_count() > 0,
+            Tracked::Upval(ref w) => w.strong_count() > 0,
+        }
+    }
+}
+
+// collect runs once this many objects are tracked, dead ones included
+const MIN_THRESHOLD: usize = 10000;
+
+struct Heap {
+    tracked:     Vec<Tracked>,
+    threshold:   usize,
+    collections: usize,
+    freed:       usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap{tracked: vec![], threshold: MIN_THRESHOLD, collections: 0, freed: 0})
+    };
+}
+
+fn track(t: Tracked) {
+    let full = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.tracked.push(t);
+        heap.tracked.len() >= heap.threshold
+    });
+    if full {
+        collect();
+    }
+}
+
+pub fn track_env(env: &Env) {
+    track(Tracked::Env(Rc::downgrade(env)));
+}
+
+pub fn track_atom(atom: &MalVal) {
+    track(Tracked::Atom(Rc::downgrade(atom)));
+}
+
+pub fn track_upval(upval: &Upval) {
+    track(Tracked::Upval(Rc::downgrade(upval)));
+}
+
+// Pushes v if it can refer to anything
+pub fn push_val(v: &MalVal, refs: &mut Vec<Ref>) {
+    match **v {
+        List(..) | Vector(..) | Hash_Map(..) | MalFunc(..) | Atom(_) => refs.push(Ref::Val(v.clone())),
+        _ => (),
+    }
+}
+
+impl Ref {
+    fn addr(&self) -> *const () {
+        match *self {
+            Ref::Env(ref e) => Rc::as_ptr(e) as *const (),
+            Ref::Val(ref v) => Rc::as_ptr(v) as *const (),
+            Ref::Bytecode(ref b) => Rc::as_ptr(b) as *const (),
+            Ref::Shared(ref n) => Rc::as_ptr(n) as *const (),
+            Ref::Upvals(ref u) => Rc::as_ptr(u) as *const (),
+            Ref::Upval(ref u) => Rc::as_ptr(u) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Ref::Env(ref e) => Rc::strong_count(e),
+            Ref::Val(ref v) => Rc::strong_count(v),
+            Ref::Bytecode(ref b) => Rc::strong_count(b),
+            Ref::Shared(ref n) => Rc::strong_count(n),
+            Ref::Upvals(ref u) => Rc::strong_count(u),
+            Ref::Upval(ref u) => Rc::strong_count(u),
+        }
+    }
+
+    // Pushes what this refers to, or returns false if it's being changed
+    // and can't be looked at
+    fn refs(&self, refs: &mut Vec<Ref>) -> bool {
+        match *self {
+            Ref::Env(ref e) => match e.try_borrow() {
+                Ok(e) => e.trace(refs),
+                Err(_) => return false,
+            },
+            Ref::Val(ref v) => match **v {
+                List(ref items, ref meta) | Vector(ref items, ref meta) => {
+                    items.trace(refs);
+                    push_val(meta, refs);
+                },
+                Hash_Map(ref hm, ref meta) => {
+                    hm.trace(refs);
+                    push_val(meta, refs);
+                },
+                MalFunc(ref mf, ref meta) => {
+                    refs.push(Ref::Env(mf.env.clone()));
+                    if let Some(ref b) = mf.bytecode {
+                        refs.push(Ref::Bytecode(b.clone()));
+                    }
+                    push_val(meta, refs);
+                },
+                Atom(ref a) => match a.try_borrow() {
+                    Ok(v) => push_val(&v, refs),
+                    Err(_) => return false,
+                },
+                _ => (),
+            },
+            Ref::Bytecode(ref b) => b.trace(refs),
+            Ref::Shared(ref n) => n.trace(refs),
+            Ref::Upvals(ref upvals) => {
+                for u in upvals.iter() {
+                    refs.push(Ref::Upval(u.clone()));
+                }
+            },
+            Ref::Upval(ref u) => match u.try_borrow() {
+                Ok(v) => if let Some(ref v) = *v {
+                    push_val(v, refs);
+                },
+                Err(_) => return false,
+            },
+        }
+        true
+    }
+
+    // Empties this if it's one of the mutable objects, to break the cycles
+    // through it
+    fn clear(&self) {
+        match *self {
+            Ref::Env(ref e) => {
+                let old = e.try_borrow_mut().map(|mut e| e.clear());
+                drop(old);
+            },
+            Ref::Val(ref v) => if let Atom(ref a) = **v {
+                let old = a.try_borrow_mut().map(|mut a| std::mem::replace(&mut *a, _nil()));
+                drop(old);
+            },
+            Ref::Upval(ref u) => {
+                let old = u.try_borrow_mut().map(|mut u| u.take());
+                drop(old);
+            },
+            _ => (),
+        }
+    }
+}
+
+struct Node {
+    obj:      Ref,
+    refs:     Vec<usize>, // the nodes it refers to, by index
+    internal: usize,      // references to it from other nodes
+    opaque:   bool,       // it couldn't be looked into
+}
+
+// Frees the cycles nothing else refers to and returns how many objects
+// they held
+pub fn collect() -> usize {
+    let tracked: Vec<Ref> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.tracked.retain(|t| t.alive());
+        heap.tracked.iter().filter_map(|t| t.upgrade()).collect()
+    });
+
+    // Find everything the tracked objects reach, keeping one reference to
+    // each object in its node
+    let mut index: HashMap<*const (), usize> = HashMap::new();
+    let mut nodes: Vec<Node> = vec![];
+    for obj in tracked {
+        index.entry(obj.addr()).or_insert_with(|| {
+            nodes.push(Node{obj: obj, refs: vec![], internal: 0, opaque: false});
+            nodes.len() - 1
+        });
+    }
+    let mut i = 0;
+    while i < nodes.len() {
+        let mut refs = vec![];
+        nodes[i].opaque = !nodes[i].obj.refs(&mut refs);
+        for r in refs {
+            let j = *index.entry(r.addr()).or_insert_with(|| {
+                nodes.push(Node{obj: r, refs: vec![], internal: 0, opaque: false});
+                nodes.len() - 1
+            });
+            nodes[j].internal += 1;
+            nodes[i].refs.push(j);
+        }
+        i += 1;
+    }
+
+    // Whatever is referred to from outside the nodes is live, and so is
+    // everything it reaches
+    let mut live = vec![false; nodes.len()];
+    let mut stack: Vec<usize> = (0..nodes.len())
+        .filter(|&i| nodes[i].opaque || nodes[i].obj.strong_count() > nodes[i].internal + 1)
+        .collect();
+    while let Some(i) = stack.pop() {
+        if !live[i] {
+            live[i] = true;
+            stack.extend(nodes[i].refs.iter().filter(|&&j| !live[j]).cloned());
+        }
+    }
+
+    // The nodes hold on to the garbage while its cycles are broken, and
+    // it's all freed when they're dropped
+    let mut garbage = 0;
+    for (node, _) in nodes.iter().zip(live.iter()).filter(|&(_, &l)| !l) {
+        node.obj.clear();
+        garbage += 1;
+    }
+    let kept = nodes.len() - garbage;
+    drop(nodes);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.tracked.retain(|t| t.alive());
+        // wait for the live objects to double, and for long enough that
+        // walking them again costs little per object tracked meanwhile
+        heap.threshold = MIN_THRESHOLD.max(2 * heap.tracked.len()).max(kept);
+        heap.collections += 1;
+        heap.freed += garbage;
+    });
+    garbage
+}
+
+pub struct Stats {
+    pub envs:        usize, // environments alive now
+    pub collections: usize,
+    pub freed:       usize, // objects collect has freed in all
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        let envs = heap.tracked.iter()
+            .filter(|t| match **t { Tracked::Env(ref w) => w.strong_count() > 0, _ => false })
+            .count();
+        Stats{envs: envs, collections: heap.collections, freed: heap.freed}
+    })
+}
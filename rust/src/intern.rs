@@ -0,0 +1,121 @@
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+// A symbol is a small integer standing for its name. Each name is given the
+// next id the first time it's interned and keeps it for the rest of the run,
+// so comparing and hashing symbols, which environments and the evaluator do
+// all the time, never looks at the name.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(u32);
+
+// The special forms, and the symbols quasiquote and fn* look for, are
+// interned first and in this order, so that they have ids eval can match on
+const PREINTERNED: &[&str] = &[
+    "def!", "let*", "do", "if", "fn*", "quote", "quasiquote", "unquote",
+    "splice-unquote", "defmacro!", "macroexpand", "try*", "catch*", "eval",
+    "&",
+];
+
+pub const DEF: SymbolId = SymbolId(0);
+pub const LET: SymbolId = SymbolId(1);
+pub const DO: SymbolId = SymbolId(2);
+pub const IF: SymbolId = SymbolId(3);
+pub const FN: SymbolId = SymbolId(4);
+pub const QUOTE: SymbolId = SymbolId(5);
+pub const QUASIQUOTE: SymbolId = SymbolId(6);
+pub const UNQUOTE: SymbolId = SymbolId(7);
+pub const SPLICE_UNQUOTE: SymbolId = SymbolId(8);
+pub const DEFMACRO: SymbolId = SymbolId(9);
+pub const MACROEXPAND: SymbolId = SymbolId(10);
+pub const TRY: SymbolId = SymbolId(11);
+pub const CATCH: SymbolId = SymbolId(12);
+pub const EVAL: SymbolId = SymbolId(13);
+pub const AMPERSAND: SymbolId = SymbolId(14);
+
+struct Interner {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, SymbolId>
+}
+
+impl Interner {
+    fn new() -> Interner {
+        let mut interner = Interner { names: Vec::new(), ids: HashMap::new() };
+        for name in PREINTERNED {
+            interner.intern(name);
+        }
+        interner
+    }
+
+    fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = SymbolId(self.names.len() as u32);
+        let name: Rc<str> = Rc::from(name);
+        self.names.push(name.clone());
+        self.ids.insert(name, id);
+        id
+    }
+}
+
+// Values aren't shared between threads, so neither is the table
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::new());
+}
+
+impl SymbolId {
+    pub fn intern(name: &str) -> SymbolId {
+        INTERNER.with(|interner| interner.borrow_mut().intern(name))
+    }
+
+    pub fn name(self) -> Rc<str> {
+        INTERNER.with(|interner| interner.borrow().names[self.0 as usize].clone())
+    }
+}
+
+impl<'a> From<&'a str> for SymbolId {
+    fn from(name: &'a str) -> SymbolId {
+        SymbolId::intern(name)
+    }
+}
+
+impl fmt::Display for SymbolId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl fmt::Debug for SymbolId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+// Ids are already distinct small integers, so a map keyed on them can use the
+// id as its hash rather than running SipHash over it
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8) | u64::from(byte);
+        }
+    }
+
+    fn write_u32(&mut self, id: u32) {
+        self.0 = u64::from(id);
+    }
+
+    fn finish(&self) -> u64 {
+        // multiplying spreads consecutive ids over all the bits of the hash
+        self.0.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+    }
+}
+
+pub type SymbolMap<V> = HashMap<SymbolId, V, BuildHasherDefault<SymbolHasher>>;
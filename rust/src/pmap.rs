@@ -0,0 +1,328 @@
+#![allow(dead_code)]
+
+// A persistent map from strings, the map behind mal hash-maps.
+//
+// Like PVec (see pvec.rs), cloning one is cheap and changing a clone copies
+// only what it changes. It's a hash array mapped trie: each node has a slot
+// for each 5 bits of a key's hash at its level, and a bitmap of the slots
+// in use so that it only stores those. A slot holds an entry, or the node
+// below it if more than one key hashes to that slot so far down. Keys whose
+// hashes are equal in all 64 bits end up together in a collision node.
+
+use std::rc::Rc;
+use std::fmt;
+use std::slice;
+use std::iter::FromIterator;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
+
+use types::MalVal;
+use gc::{Ref,Shared,push_val};
+
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+#[derive(Clone)]
+enum Entry<V> {
+    Leaf(u64, String, V),
+    Node(Rc<Node<V>>),
+}
+
+#[derive(Clone)]
+enum Node<V> {
+    // the entries for the bitmap's set bits, in order
+    Branch(u32, Vec<Entry<V>>),
+    // leaves whose keys all have the same hash
+    Collision(Vec<Entry<V>>),
+}
+
+#[derive(Clone)]
+pub struct PMap<V> {
+    len:  usize,
+    root: Rc<Node<V>>,
+}
+
+fn hash(key: &str) -> u64 {
+    let mut h = DefaultHasher::new();
+    key.hash(&mut h);
+    h.finish()
+}
+
+// The bit for hash in a branch at shift, and its entry's index there
+fn slot(bitmap: u32, shift: u32, hash: u64) -> (u32, usize) {
+    let bit = 1u32 << ((hash >> shift) & MASK);
+    (bit, (bitmap & (bit - 1)).count_ones() as usize)
+}
+
+impl<V: Clone> PMap<V> {
+    pub fn new() -> PMap<V> {
+        PMap{len: 0, root: Rc::new(Node::Branch(0, vec![]))}
+    }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        let hash = hash(key);
+        let mut node = &*self.root;
+        let mut shift = 0;
+        loop {
+            match *node {
+                Node::Branch(bitmap, ref entries) => {
+                    let (bit, i) = slot(bitmap, shift, hash);
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+                    match entries[i] {
+                        Entry::Leaf(h, ref k, ref v) => {
+                            return if h == hash && k == key { Some(v) } else { None };
+                        },
+                        Entry::Node(ref n) => node = n,
+                    }
+                    shift += BITS;
+                },
+                Node::Collision(ref entries) => {
+                    return entries.iter().filter_map(|e| match *e {
+                        Entry::Leaf(_, ref k, ref v) if k == key => Some(v),
+                        _ => None,
+                    }).next();
+                },
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Sets key to val, and returns what it was set to before
+    pub fn insert(&mut self, key: String, val: V) -> Option<V> {
+        let hash = hash(&key);
+        let old = insert(Rc::make_mut(&mut self.root), 0, hash, key, val);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        // don't copy the path to a key that isn't there
+        if !self.contains_key(key) {
+            return None;
+        }
+        let old = remove(Rc::make_mut(&mut self.root), 0, hash(key), key);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter{stack: vec![self.root.entries().iter()], left: self.len}
+    }
+
+    pub fn keys<'a>(&'a self) -> impl Iterator<Item=&'a String> + 'a {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values<'a>(&'a self) -> impl Iterator<Item=&'a V> + 'a {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl PMap<MalVal> {
+    // What the map refers to, for the collector (see gc.rs): its root,
+    // which other maps may share
+    pub fn trace(&self, refs: &mut Vec<Ref>) {
+        refs.push(Ref::Shared(self.root.clone()));
+    }
+}
+
+impl Shared for Node<MalVal> {
+    fn trace(&self, refs: &mut Vec<Ref>) {
+        for entry in self.entries().iter() {
+            match *entry {
+                Entry::Leaf(_, _, ref v) => push_val(v, refs),
+                Entry::Node(ref n) => refs.push(Ref::Shared(n.clone())),
+            }
+        }
+    }
+}
+
+impl<V> Node<V> {
+    fn entries(&self) -> &[Entry<V>] {
+        match *self {
+            Node::Branch(_, ref entries) | Node::Collision(ref entries) => entries,
+        }
+    }
+
+    // The entry left if it's the only one, for its parent to hold instead
+    fn take_lone_leaf(&mut self) -> Option<Entry<V>> {
+        match *self {
+            Node::Branch(_, ref mut entries) | Node::Collision(ref mut entries) => {
+                match entries[..] {
+                    [Entry::Leaf(..)] => entries.pop(),
+                    _ => None,
+                }
+            },
+        }
+    }
+}
+
+fn insert<V: Clone>(node: &mut Node<V>, shift: u32, hash: u64, key: String, val: V) -> Option<V> {
+    match *node {
+        Node::Branch(ref mut bitmap, ref mut entries) => {
+            let (bit, i) = slot(*bitmap, shift, hash);
+            if *bitmap & bit == 0 {
+                *bitmap |= bit;
+                entries.insert(i, Entry::Leaf(hash, key, val));
+                return None;
+            }
+            match entries[i] {
+                Entry::Node(ref mut n) => return insert(Rc::make_mut(n), shift + BITS, hash, key, val),
+                Entry::Leaf(h, ref k, ref mut v) => if h == hash && *k == key {
+                    return Some(std::mem::replace(v, val));
+                },
+            }
+            // another key has this slot, so both go in a node below it
+            let other = entries.remove(i);
+            let below = split(shift + BITS, other, Entry::Leaf(hash, key, val));
+            entries.insert(i, Entry::Node(Rc::new(below)));
+            None
+        },
+        Node::Collision(ref mut entries) => {
+            for entry in entries.iter_mut() {
+                if let Entry::Leaf(_, ref k, ref mut v) = *entry {
+                    if *k == key {
+                        return Some(std::mem::replace(v, val));
+                    }
+                }
+            }
+            entries.push(Entry::Leaf(hash, key, val));
+            None
+        },
+    }
+}
+
+// A node at shift holding two leaves with different keys
+fn split<V>(shift: u32, a: Entry<V>, b: Entry<V>) -> Node<V> {
+    let (ha, hb) = match (&a, &b) {
+        (&Entry::Leaf(ha, _, _), &Entry::Leaf(hb, _, _)) => (ha, hb),
+        _ => unreachable!(),
+    };
+    if shift >= 64 {
+        return Node::Collision(vec![a, b]);
+    }
+    let (sa, sb) = ((ha >> shift) & MASK, (hb >> shift) & MASK);
+    if sa == sb {
+        Node::Branch(1 << sa, vec![Entry::Node(Rc::new(split(shift + BITS, a, b)))])
+    } else if sa < sb {
+        Node::Branch(1 << sa | 1 << sb, vec![a, b])
+    } else {
+        Node::Branch(1 << sa | 1 << sb, vec![b, a])
+    }
+}
+
+fn remove<V: Clone>(node: &mut Node<V>, shift: u32, hash: u64, key: &str) -> Option<V> {
+    match *node {
+        Node::Branch(ref mut bitmap, ref mut entries) => {
+            let (bit, i) = slot(*bitmap, shift, hash);
+            if *bitmap & bit == 0 {
+                return None;
+            }
+            if let Entry::Node(ref mut n) = entries[i] {
+                let n = Rc::make_mut(n);
+                let old = remove(n, shift + BITS, hash, key);
+                if let Some(leaf) = n.take_lone_leaf() {
+                    entries[i] = leaf;
+                }
+                return old;
+            }
+            match entries[i] {
+                Entry::Leaf(h, ref k, _) if h == hash && k == key => (),
+                _ => return None,
+            }
+            *bitmap &= !bit;
+            match entries.remove(i) {
+                Entry::Leaf(_, _, v) => Some(v),
+                _ => None,
+            }
+        },
+        Node::Collision(ref mut entries) => {
+            let i = entries.iter().position(|e| match *e {
+                Entry::Leaf(_, ref k, _) => k == key,
+                _ => false,
+            })?;
+            match entries.remove(i) {
+                Entry::Leaf(_, _, v) => Some(v),
+                _ => None,
+            }
+        },
+    }
+}
+
+impl<V: Clone> Default for PMap<V> {
+    fn default() -> PMap<V> { PMap::new() }
+}
+
+impl<V: Clone> FromIterator<(String, V)> for PMap<V> {
+    fn from_iter<I: IntoIterator<Item=(String, V)>>(iter: I) -> PMap<V> {
+        let mut m = PMap::new();
+        for (k, v) in iter {
+            m.insert(k, v);
+        }
+        m
+    }
+}
+
+impl<V: Clone + PartialEq> PartialEq for PMap<V> {
+    fn eq(&self, other: &PMap<V>) -> bool {
+        self.len == other.len && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<V: Clone + fmt::Debug> fmt::Debug for PMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+pub struct Iter<'a, V: 'a> {
+    stack: Vec<slice::Iter<'a, Entry<V>>>,
+    left:  usize,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a String, &'a V);
+
+    fn next(&mut self) -> Option<(&'a String, &'a V)> {
+        loop {
+            let entry = match self.stack.last_mut() {
+                Some(entries) => entries.next(),
+                None => return None,
+            };
+            match entry {
+                Some(&Entry::Leaf(_, ref k, ref v)) => {
+                    self.left -= 1;
+                    return Some((k, v));
+                },
+                Some(&Entry::Node(ref n)) => self.stack.push(n.entries().iter()),
+                None => { self.stack.pop(); },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Iter<'a, V> {}
+
+impl<'a, V: Clone> IntoIterator for &'a PMap<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> { self.iter() }
+}
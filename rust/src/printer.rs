@@ -3,8 +3,8 @@ use types::MalVal;
 pub fn escape_str(s: &str) -> String {
     let mut escaped = String::new();
     escaped.push('"');
-    for c in s.as_slice().chars() {
-        let _ = match c {
+    for c in s.chars() {
+        match c {
           '"' => escaped.push_str("\\\""),
           '\\' => escaped.push_str("\\\\"),
           '\x08' => escaped.push_str("\\b"),
@@ -22,23 +22,38 @@ pub fn escape_str(s: &str) -> String {
 }
 
 pub fn unescape_str(s: &str) -> String {
-    let re1 = regex!(r#"\\""#);
-    let re2 = regex!(r#"\n"#);
-    re2.replace_all(re1.replace_all(s.as_slice(), "\"").as_slice(), "\n")
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('b') => unescaped.push('\x08'),
+            Some('f') => unescaped.push('\x0c'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
 }
 
-pub fn pr_list(lst: &Vec<MalVal>, pr: bool,
+pub fn pr_list<'a, I: IntoIterator<Item=&'a MalVal>>(lst: I, pr: bool,
                start: &str , end: &str, join: &str) -> String {
     let mut first = true;
     let mut res = String::new();
     res.push_str(start);
-    for mv in lst.iter() {
+    for mv in lst {
         if first {
             first = false;
         } else {
             res.push_str(join);
         }
-        res.push_str(mv.pr_str(pr).as_slice());
+        res.push_str(&mv.pr_str(pr));
     }
     res.push_str(end);
     res
@@ -0,0 +1,447 @@
+#![allow(dead_code)]
+
+// A persistent vector, the sequence behind mal lists and vectors.
+//
+// Cloning one is cheap, and changing a clone leaves the others as they were
+// while sharing everything it didn't change. The items are kept, as in
+// Clojure's vectors, in a tree of nodes holding 32 each, with the last 32 or
+// fewer outside it in the tail. The path down the tree to an index is its
+// bits, 5 at a time from the top, so a vector of a million items is four
+// levels deep. Nodes are shared through Rc and copied by Rc::make_mut only
+// when one is about to change while something else still refers to it, so
+// push changes a vector nothing shares in place and otherwise copies just
+// the tail and the path to where the new items go.
+//
+// Lists are taken apart from the start by rest and built up there by cons,
+// which the tree can't do without copying it. So the items consed on are
+// pushed onto a second vector, the front, which holds them last first, and
+// rest takes an item off the front or, once it's empty, skips the first
+// item still in the tree and tail. Skipped items stay in memory until
+// nothing shares the tree any more.
+
+use std::rc::Rc;
+use std::mem;
+use std::fmt;
+use std::ops::{Index,RangeBounds,Bound};
+use std::iter::FromIterator;
+
+use types::MalVal;
+use gc::{Ref,Shared,push_val};
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum Node<T> {
+    Branch(Vec<Rc<Node<T>>>),
+    Leaf(Vec<T>),
+}
+
+#[derive(Clone)]
+pub struct PVec<T> {
+    size:  usize, // items in the tree and tail, skipped ones included
+    shift: usize, // how far to shift an index to get its slot in the root
+    root:  Option<Rc<Node<T>>>, // the items before the tail, in full leaves
+    tail:  Vec<T>,
+    skip:  usize, // items at the start of the tree and tail rest has dropped
+    // the items consed on before the tree and tail, last first; it never
+    // has a front or skipped items of its own
+    front: Option<Rc<PVec<T>>>,
+}
+
+impl<T: Clone> PVec<T> {
+    pub fn new() -> PVec<T> {
+        PVec{size: 0, shift: BITS, root: None, tail: vec![], skip: 0, front: None}
+    }
+
+    fn front_len(&self) -> usize {
+        match self.front {
+            Some(ref front) => front.size,
+            None => 0,
+        }
+    }
+
+    pub fn len(&self) -> usize { self.front_len() + self.size - self.skip }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    fn tail_start(&self) -> usize { self.size - self.tail.len() }
+
+    // The leaf, or the tail, holding the item at index j of the tree and
+    // tail, and the index it starts at
+    fn chunk(&self, j: usize) -> (usize, &[T]) {
+        let tail_start = self.tail_start();
+        if j >= tail_start {
+            return (tail_start, &self.tail);
+        }
+        let mut node = self.root.as_ref().unwrap();
+        let mut shift = self.shift;
+        loop {
+            match **node {
+                Node::Branch(ref children) => {
+                    node = &children[(j >> shift) & MASK];
+                    shift -= BITS;
+                },
+                Node::Leaf(ref items) => return (j & !MASK, items),
+            }
+        }
+    }
+
+    // The item at index j of the tree and tail
+    fn stored(&self, j: usize) -> Option<&T> {
+        let tail_start = self.tail_start();
+        if j >= tail_start {
+            return self.tail.get(j - tail_start);
+        }
+        let (start, items) = self.chunk(j);
+        Some(&items[j - start])
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        match self.front {
+            Some(ref front) if i < front.size => front.stored(front.size - 1 - i),
+            Some(ref front) => self.stored(self.skip + i - front.size),
+            None => self.stored(self.skip + i),
+        }
+    }
+
+    pub fn first(&self) -> Option<&T> { self.get(0) }
+
+    pub fn last(&self) -> Option<&T> {
+        if self.size > self.skip {
+            self.tail.last()
+        } else {
+            self.front.as_ref().and_then(|front| front.stored(0))
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.tail.len() == WIDTH {
+            let tail = mem::replace(&mut self.tail, Vec::with_capacity(WIDTH));
+            let leaf = Rc::new(Node::Leaf(tail));
+            let start = self.size - WIDTH;
+            self.root = Some(match self.root.take() {
+                None => Rc::new(Node::Branch(vec![leaf])),
+                Some(root) => if start >> self.shift >= WIDTH {
+                    // the tree is full, so it becomes the first child of a
+                    // new root a level higher
+                    let path = new_path(self.shift, leaf);
+                    self.shift += BITS;
+                    Rc::new(Node::Branch(vec![root, path]))
+                } else {
+                    let mut root = root;
+                    push_leaf(&mut root, self.shift, start, leaf);
+                    root
+                },
+            });
+        }
+        self.tail.push(item);
+        self.size += 1;
+    }
+
+    // Takes the last item off the tree and tail
+    fn pop(&mut self) -> Option<T> {
+        let item = self.tail.pop()?;
+        self.size -= 1;
+        if self.tail.is_empty() && self.size > 0 {
+            // the last leaf becomes the tail
+            let mut root = self.root.take().unwrap();
+            let leaf = pop_leaf(&mut root, self.shift, self.size - 1);
+            self.tail = match Rc::try_unwrap(leaf) {
+                Ok(Node::Leaf(items)) => items,
+                Err(ref leaf) => match **leaf {
+                    Node::Leaf(ref items) => items.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+            // and a root left with one child gives way to it
+            let child = match *root {
+                Node::Branch(ref children) if children.len() == 1 && self.shift > BITS => {
+                    Some(children[0].clone())
+                },
+                _ => None,
+            };
+            self.root = match child {
+                Some(child) => {
+                    self.shift -= BITS;
+                    Some(child)
+                },
+                None if self.size > WIDTH => Some(root),
+                None => None,
+            };
+        }
+        Some(item)
+    }
+
+    // Adds item at the start, as cons does
+    pub fn push_front(&mut self, item: T) {
+        match self.front {
+            Some(ref mut front) => Rc::make_mut(front).push(item),
+            None => {
+                let mut front = PVec::new();
+                front.push(item);
+                self.front = Some(Rc::new(front));
+            },
+        }
+    }
+
+    // Takes the first item off, as rest does
+    pub fn pop_front(&mut self) -> Option<T> {
+        if let Some(mut front) = self.front.take() {
+            let item = Rc::make_mut(&mut front).pop();
+            if front.size > 0 {
+                self.front = Some(front);
+            }
+            return item;
+        }
+        let item = self.stored(self.skip).cloned()?;
+        self.skip += 1;
+        if self.skip == self.size {
+            // let go of the tree once all of it has been skipped
+            *self = PVec::new();
+        }
+        Some(item)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter{vec: self, front: 0, back: self.len(),
+             front_chunk: Chunk::new(), back_chunk: Chunk::new()}
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.slice(..)
+    }
+
+    // Copies the items in range out to a Vec, as slicing a Vec and calling
+    // to_vec would
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> Vec<T> {
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Included(&s) => s,
+            Bound::Excluded(&s) => s + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&e) => e + 1,
+            Bound::Excluded(&e) => e,
+            Bound::Unbounded => len,
+        };
+        if start > end || end > len {
+            panic!("range {}..{} out of bounds for length {}", start, end, len);
+        }
+        if self.root.is_none() && self.front.is_none() {
+            return self.tail[self.skip + start..self.skip + end].to_vec();
+        }
+        self.iter().skip(start).take(end - start).cloned().collect()
+    }
+}
+
+// A node at the given shift whose only leaf is leaf
+fn new_path<T>(shift: usize, leaf: Rc<Node<T>>) -> Rc<Node<T>> {
+    if shift == 0 {
+        leaf
+    } else {
+        Rc::new(Node::Branch(vec![new_path(shift - BITS, leaf)]))
+    }
+}
+
+// Adds leaf to the tree under node as the leaf for the items from index i
+fn push_leaf<T: Clone>(node: &mut Rc<Node<T>>, shift: usize, i: usize, leaf: Rc<Node<T>>) {
+    if let Node::Branch(ref mut children) = *Rc::make_mut(node) {
+        let slot = (i >> shift) & MASK;
+        if shift == BITS {
+            children.push(leaf);
+        } else if slot < children.len() {
+            push_leaf(&mut children[slot], shift - BITS, i, leaf);
+        } else {
+            children.push(new_path(shift - BITS, leaf));
+        }
+    }
+}
+
+// Takes the last leaf, the one holding index i, out of the tree under node,
+// along with any branch that leaves empty
+fn pop_leaf<T: Clone>(node: &mut Rc<Node<T>>, shift: usize, i: usize) -> Rc<Node<T>> {
+    match *Rc::make_mut(node) {
+        Node::Branch(ref mut children) => {
+            if shift == BITS {
+                return children.pop().unwrap();
+            }
+            let slot = (i >> shift) & MASK;
+            let leaf = pop_leaf(&mut children[slot], shift - BITS, i);
+            if let Node::Branch(ref below) = *children[slot] {
+                if below.is_empty() {
+                    children.pop();
+                }
+            }
+            leaf
+        },
+        Node::Leaf(_) => unreachable!(),
+    }
+}
+
+impl PVec<MalVal> {
+    // What the vector refers to, for the collector (see gc.rs): the items
+    // in its tail, those rest has skipped included, and its front and the
+    // nodes of its tree, which other vectors may share
+    pub fn trace(&self, refs: &mut Vec<Ref>) {
+        if let Some(ref front) = self.front {
+            refs.push(Ref::Shared(front.clone()));
+        }
+        if let Some(ref root) = self.root {
+            refs.push(Ref::Shared(root.clone()));
+        }
+        for item in self.tail.iter() {
+            push_val(item, refs);
+        }
+    }
+}
+
+impl Shared for PVec<MalVal> {
+    fn trace(&self, refs: &mut Vec<Ref>) { PVec::trace(self, refs) }
+}
+
+impl Shared for Node<MalVal> {
+    fn trace(&self, refs: &mut Vec<Ref>) {
+        match *self {
+            Node::Branch(ref children) => for child in children.iter() {
+                refs.push(Ref::Shared(child.clone()));
+            },
+            Node::Leaf(ref items) => for item in items.iter() {
+                push_val(item, refs);
+            },
+        }
+    }
+}
+
+impl<T: Clone> Default for PVec<T> {
+    fn default() -> PVec<T> { PVec::new() }
+}
+
+impl<T: Clone> From<Vec<T>> for PVec<T> {
+    fn from(v: Vec<T>) -> PVec<T> {
+        if v.len() <= WIDTH {
+            PVec{size: v.len(), shift: BITS, root: None, tail: v, skip: 0, front: None}
+        } else {
+            v.into_iter().collect()
+        }
+    }
+}
+
+impl<T: Clone> FromIterator<T> for PVec<T> {
+    fn from_iter<I: IntoIterator<Item=T>>(iter: I) -> PVec<T> {
+        let mut v = PVec::new();
+        for item in iter {
+            v.push(item);
+        }
+        v
+    }
+}
+
+impl<T: Clone> Extend<T> for PVec<T> {
+    fn extend<I: IntoIterator<Item=T>>(&mut self, iter: I) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<T: Clone> Index<usize> for PVec<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        match self.get(i) {
+            Some(item) => item,
+            None => panic!("index {} out of bounds for length {}", i, self.len()),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> PartialEq for PVec<T> {
+    fn eq(&self, other: &PVec<T>) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for PVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// The leaf an iterator last read from, and the index it starts at
+struct Chunk<'a, T: 'a> {
+    start: usize,
+    items: &'a [T],
+}
+
+impl<'a, T: Clone> Chunk<'a, T> {
+    fn new() -> Chunk<'a, T> { Chunk{start: 0, items: &[]} }
+
+    // The item at index j of vec's tree and tail
+    fn get(&mut self, vec: &'a PVec<T>, j: usize) -> &'a T {
+        if j < self.start || j - self.start >= self.items.len() {
+            let (start, items) = vec.chunk(j);
+            self.start = start;
+            self.items = items;
+        }
+        &self.items[j - self.start]
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    vec:   &'a PVec<T>,
+    front: usize,
+    back:  usize,
+    front_chunk: Chunk<'a, T>,
+    back_chunk:  Chunk<'a, T>,
+}
+
+impl<'a, T: Clone> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.front >= self.back {
+            return None;
+        }
+        let i = self.front;
+        self.front += 1;
+        let vec = self.vec;
+        Some(match vec.front {
+            Some(ref front) if i < front.size => self.front_chunk.get(front, front.size - 1 - i),
+            Some(ref front) => self.back_chunk.get(vec, vec.skip + i - front.size),
+            None => self.back_chunk.get(vec, vec.skip + i),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.back - self.front;
+        (n, Some(n))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<&'a T> {
+        self.front = self.back.min(self.front + n);
+        self.next()
+    }
+}
+
+impl<'a, T: Clone> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        if self.front >= self.back {
+            return None;
+        }
+        self.back -= 1;
+        self.vec.get(self.back)
+    }
+}
+
+impl<'a, T: Clone> ExactSizeIterator for Iter<'a, T> {}
+
+impl<'a, T: Clone> IntoIterator for &'a PVec<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> { self.iter() }
+}
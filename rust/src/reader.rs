@@ -1,59 +1,188 @@
-//#![feature(phase)]
-//#[phase(plugin)]
-//extern crate regex_macros;
-//extern crate regex;
+#![allow(dead_code)]
 
-extern crate pcre;
+use std::iter::Peekable;
+use std::str::CharIndices;
 
-use types::{MalVal,MalRet,ErrString,ErrMalVal,
-            _nil,_true,_false,_int,symbol,string,list,vector,hash_mapv,
+use types::{MalVal,MalRet,MalError,ErrString,ErrIncomplete,Depth,
+            _nil,_true,_false,_int,_bigint,_rational,_float,symbol,string,list,vector,hash_mapv,
             err_str,err_string,err_val};
-use self::pcre::Pcre;
 use super::printer::unescape_str;
+use super::bignum::{BigInt,Ratio};
 
-#[deriving(Show, Clone)]
+#[derive(Debug, Clone)]
+struct Token {
+    text   : String,
+    offset : usize, // in bytes, from the start of the input
+    line   : usize,
+    column : usize,
+}
+
+#[derive(Debug, Clone)]
 struct Reader {
-    tokens   : Vec<String>,
-    position : uint,
+    tokens   : Vec<Token>,
+    position : usize,
 }
 
 impl Reader {
     fn next(&mut self) -> Option<String> {
         if self.position < self.tokens.len() {
             self.position += 1;
-            Some(self.tokens[self.position-1].to_string())
+            Some(self.tokens[self.position-1].text.to_string())
         } else {
             None
         }
     }
     fn peek(&self) -> Option<String> {
         if self.position < self.tokens.len() {
-            Some(self.tokens[self.position].to_string())
+            Some(self.tokens[self.position].text.to_string())
         } else {
             None
         }
     }
+    // "line:column" of the next token, for error messages
+    fn location(&self) -> String {
+        match self.tokens.get(self.position) {
+            Some(t) => format!("{}:{}", t.line, t.column),
+            None => "EOF".to_string(),
+        }
+    }
 }
 
-fn tokenize(str :String) -> Vec<String> {
-    let mut results = vec![];
+struct Lexer<'a> {
+    input  : &'a str,
+    chars  : Peekable<CharIndices<'a>>,
+    line   : usize,
+    column : usize,
+}
 
-    let re = match Pcre::compile(r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"|;.*|[^\s\[\]{}('"`,;)]*)"###) {
-        Err(_) => { fail!("failed to compile regex") },
-        Ok(re) => re
-    };
+impl<'a> Lexer<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+    fn offset(&mut self) -> usize {
+        let len = self.input.len();
+        self.chars.peek().map_or(len, |&(i, _)| i)
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next().map(|(_, c)| c);
+        match c {
+            Some('\n') => { self.line += 1; self.column = 1; },
+            Some(_) => self.column += 1,
+            None => (),
+        }
+        c
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "[]{}()'\"`,;".contains(c)
+}
+
+fn tokenize(str :&str) -> Result<Vec<Token>,String> {
+    let mut results = vec![];
+    let mut lexer = Lexer{input: str, chars: str.char_indices().peekable(),
+                          line: 1, column: 1};
 
-    let mut it = re.matches(str.as_slice());
     loop {
-        let opt_m = it.next();
-        if opt_m.is_none() { break; }
-        let m = opt_m.unwrap();
-        if m.group(1) == "" { break; }
-        if m.group(1).starts_with(";") { continue; }
+        while let Some(c) = lexer.peek() {
+            if !c.is_whitespace() && c != ',' { break; }
+            lexer.bump();
+        }
+
+        let offset = lexer.offset();
+        let (line, column) = (lexer.line, lexer.column);
+        let c = match lexer.bump() {
+            Some(c) => c,
+            None => break,
+        };
+        let text = match c {
+            ';' => {
+                while let Some(c) = lexer.peek() {
+                    if c == '\n' { break; }
+                    lexer.bump();
+                }
+                continue;
+            },
+            '~' if lexer.peek() == Some('@') => {
+                lexer.bump();
+                "~@".to_string()
+            },
+            '[' | ']' | '{' | '}' | '(' | ')' | '\'' | '`' | '~' | '^' | '@' => {
+                c.to_string()
+            },
+            '"' => {
+                let mut text = c.to_string();
+                loop {
+                    match lexer.bump() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            match lexer.bump() {
+                                Some(c) => text.push(c),
+                                None => return Err(format!("unterminated string at {}:{}", line, column)),
+                            }
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(format!("unterminated string at {}:{}", line, column)),
+                    }
+                }
+                text.push('"');
+                text
+            },
+            _ => {
+                let mut text = c.to_string();
+                while let Some(c) = lexer.peek() {
+                    if is_delimiter(c) { break; }
+                    text.push(c);
+                    lexer.bump();
+                }
+                text
+            },
+        };
 
-        results.push((*m.group(1)).to_string());
+        results.push(Token{text: text, offset: offset, line: line, column: column});
     }
-    results
+    Ok(results)
+}
+
+fn is_int(token: &str) -> bool {
+    let digits = token.strip_prefix('-').unwrap_or(token);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+// numerator and denominator of a ratio like 1/3 or -22/7
+fn split_ratio(token: &str) -> Option<(BigInt,BigInt)> {
+    let slash = token.find('/')?;
+    let (n, d) = (&token[..slash], &token[slash+1..]);
+    if !is_int(n) || d.is_empty() || !d.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((BigInt::parse(n)?, BigInt::parse(d)?))
+}
+
+// Digits with a fraction, an exponent or both, like 1.5 or -2e10. Rust's
+// float parsing alone would also take symbols like inf and nan.
+fn is_float(token: &str) -> bool {
+    fn digits(s: &str) -> usize {
+        s.chars().take_while(|c| c.is_ascii_digit()).count()
+    }
+    let s = token.strip_prefix('-').unwrap_or(token);
+    let int_len = digits(s);
+    if int_len == 0 { return false; }
+    let mut rest = &s[int_len..];
+    let whole = rest.is_empty();
+    if let Some(frac) = rest.strip_prefix('.') {
+        let frac_len = digits(frac);
+        if frac_len == 0 { return false; }
+        rest = &frac[frac_len..];
+    }
+    if let Some(exp) = rest.strip_prefix('e').or_else(|| rest.strip_prefix('E')) {
+        let exp = exp.strip_prefix('-').or_else(|| exp.strip_prefix('+')).unwrap_or(exp);
+        let exp_len = digits(exp);
+        if exp_len == 0 { return false; }
+        rest = &exp[exp_len..];
+    }
+    !whole && rest.is_empty()
 }
 
 fn read_atom(rdr : &mut Reader) -> MalRet {
@@ -61,15 +190,30 @@ fn read_atom(rdr : &mut Reader) -> MalRet {
     //println!("read_atom: {}", otoken);
     if otoken.is_none() { return err_str("read_atom underflow"); }
     let stoken = otoken.unwrap();
-    let token = stoken.as_slice();
-    if regex!(r"^-?[0-9]+$").is_match(token) {
-        let num : Option<int> = from_str(token);
-        Ok(_int(num.unwrap()))
-    } else if regex!(r#"^".*"$"#).is_match(token) {
-        let new_str = token.slice(1,token.len()-1);
+    let token = &stoken[..];
+    if is_int(token) {
+        match token.parse() {
+            Ok(num) => Ok(_int(num)),
+            Err(_) => Ok(_bigint(BigInt::parse(token).unwrap())),
+        }
+    } else if let Some((n, d)) = split_ratio(token) {
+        match Ratio::new(n, d) {
+            Some(r) => Ok(_rational(r)),
+            None => err_string(format!("divide by zero in ratio {}", token)),
+        }
+    } else if is_float(token) {
+        Ok(_float(token.parse().unwrap()))
+    } else if token == "##Inf" {
+        Ok(_float(f64::INFINITY))
+    } else if token == "##-Inf" {
+        Ok(_float(f64::NEG_INFINITY))
+    } else if token == "##NaN" {
+        Ok(_float(f64::NAN))
+    } else if token.starts_with('"') {
+        let new_str = &token[1..token.len()-1];
         Ok(string(unescape_str(new_str)))
-    } else if regex!(r#"^:"#).is_match(token) {
-        Ok(string("\u029e".to_string() + token.slice(1,token.len())))
+    } else if token.starts_with(':') {
+        Ok(string("\u{29e}".to_string() + &token[1..token.len()]))
     } else if token == "nil" {
         Ok(_nil())
     } else if token == "true" {
@@ -81,31 +225,32 @@ fn read_atom(rdr : &mut Reader) -> MalRet {
     }
 }
 
-fn read_seq(rdr : &mut Reader, start: &str, end: &str) -> Result<Vec<MalVal>,String> {
+fn read_seq(rdr : &mut Reader, start: &str, end: &str) -> Result<Vec<MalVal>,MalError> {
+    // where start is, for when end never comes
+    let opened = rdr.location();
     let otoken = rdr.next();
     if otoken.is_none() {
-        return Err("read_atom underflow".to_string());
+        return Err(ErrString("read_atom underflow".to_string()));
     }
     let stoken = otoken.unwrap();
-    let token = stoken.as_slice();
+    let token = &stoken[..];
     if token != start {
-        return Err("expected '".to_string() + start.to_string() + "'".to_string());
+        return Err(ErrString("expected '".to_string() + start + "'"));
     }
 
     let mut ast_vec : Vec<MalVal> = vec![];
     loop {
         let otoken = rdr.peek();
         if otoken.is_none() {
-            return Err("expected '".to_string() + end.to_string() + "', got EOF".to_string());
+            return Err(ErrIncomplete(format!("unclosed '{}' at {}", start, opened)));
         }
         let stoken = otoken.unwrap();
-        let token = stoken.as_slice();
+        let token = &stoken[..];
         if token == end { break; }
 
         match read_form(rdr) {
             Ok(mv) => ast_vec.push(mv),
-            Err(ErrString(es)) => return Err(es),
-            Err(ErrMalVal(_)) => return Err("read_seq exception".to_string()),
+            Err(e) => return Err(e),
         }
     }
     rdr.next();
@@ -116,29 +261,33 @@ fn read_seq(rdr : &mut Reader, start: &str, end: &str) -> Result<Vec<MalVal>,Str
 fn read_list(rdr : &mut Reader) -> MalRet {
     match read_seq(rdr, "(", ")") {
         Ok(seq) => Ok(list(seq)),
-        Err(es) => err_string(es),
+        Err(e) => Err(e),
     }
 }
 
 fn read_vector(rdr : &mut Reader) -> MalRet {
     match read_seq(rdr, "[", "]") {
         Ok(seq) => Ok(vector(seq)),
-        Err(es) => err_string(es),
+        Err(e) => Err(e),
     }
 }
 
 fn read_hash_map(rdr : &mut Reader) -> MalRet {
     match read_seq(rdr, "{", "}") {
         Ok(seq) => hash_mapv(seq),
-        Err(es) => err_string(es),
+        Err(e) => Err(e),
     }
 }
 
 fn read_form(rdr : &mut Reader) -> MalRet {
+    let _depth = Depth::enter()?;
     let otoken = rdr.peek();
     //println!("read_form: {}", otoken);
-    let stoken = otoken.unwrap();
-    let token = stoken.as_slice();
+    let stoken = match otoken {
+        Some(stoken) => stoken,
+        None => return Err(ErrIncomplete("unexpected EOF".to_string())),
+    };
+    let token = &stoken[..];
     match token {
         "'" => {
             let _ = rdr.next();
@@ -188,21 +337,48 @@ fn read_form(rdr : &mut Reader) -> MalRet {
             }
         },
 
-        ")" => err_str("unexected ')'"),
+        ")" => err_string(format!("unexpected ')' at {}", rdr.location())),
         "(" => read_list(rdr),
 
-        "]" => err_str("unexected ']'"),
+        "]" => err_string(format!("unexpected ']' at {}", rdr.location())),
         "[" => read_vector(rdr),
 
-        "}" => err_str("unexected '}'"),
+        "}" => err_string(format!("unexpected '}}' at {}", rdr.location())),
         "{" => read_hash_map(rdr),
 
         _   => read_atom(rdr)
     }
 }
 
+// A top-level form and where it starts in the input
+pub struct Form {
+    pub value  : MalVal,
+    pub line   : usize,
+    pub column : usize,
+}
+
+// Reads every form in the input, rather than just the first like read_str
+pub fn read_all(str :&str) -> Result<Vec<Form>,MalError> {
+    let tokens = match tokenize(str) {
+        Ok(tokens) => tokens,
+        Err(e) => return Err(ErrIncomplete(e)),
+    };
+    let rdr = &mut Reader{tokens: tokens, position: 0};
+    let mut forms = vec![];
+    while rdr.position < rdr.tokens.len() {
+        let (line, column) = (rdr.tokens[rdr.position].line,
+                              rdr.tokens[rdr.position].column);
+        let value = read_form(rdr)?;
+        forms.push(Form{value: value, line: line, column: column});
+    }
+    Ok(forms)
+}
+
 pub fn read_str(str :String) -> MalRet {
-    let tokens = tokenize(str);
+    let tokens = match tokenize(&str) {
+        Ok(tokens) => tokens,
+        Err(e) => return Err(ErrIncomplete(e)),
+    };
     if tokens.len() == 0 {
         // any malval as the error slot means empty line
         return err_val(_nil())
@@ -1,65 +1,75 @@
 // Based on: https://github.com/shaleh/rust-readline (MIT)
-extern crate libc;
-
-use std::c_str;
-
-use std::io::{File, Append, Write};
-use std::io::BufferedReader;
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 mod ext_readline {
-    extern crate libc;
-    use self::libc::c_char;
+    use std::os::raw::c_char;
     #[link(name = "readline")]
-    extern {
+    extern "C" {
         pub fn add_history(line: *const c_char);
-        pub fn readline(p: *const c_char) -> *const c_char;
+        pub fn readline(p: *const c_char) -> *mut c_char;
+    }
+    extern "C" {
+        pub fn free(ptr: *mut c_char);
     }
 }
 
 pub fn add_history(line: &str) {
+    let cline = CString::new(line).unwrap();
     unsafe {
-        ext_readline::add_history(line.to_c_str().as_ptr());
+        ext_readline::add_history(cline.as_ptr());
     }
 }
 
 pub fn readline(prompt: &str) -> Option<String> {
-    let cprmt = prompt.to_c_str();
+    let cprmt = CString::new(prompt).unwrap();
     unsafe {
         let ret = ext_readline::readline(cprmt.as_ptr());
         if ret.is_null() {  // user pressed Ctrl-D
             None
         }
         else {
-            c_str::CString::new(ret, true).as_str().map(|ret| ret.to_string())
+            let line = CStr::from_ptr(ret).to_string_lossy().into_owned();
+            ext_readline::free(ret);
+            Some(line)
         }
     }
 }
 
 // --------------------------------------------
 
-static mut history_loaded : bool = false;
-static HISTORY_FILE : &'static str = "/home/joelm/.mal-history";
+static HISTORY_LOADED : AtomicBool = AtomicBool::new(false);
+static HISTORY_FILE : &str = ".mal-history";
+
+fn history_path() -> PathBuf {
+    let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home).join(HISTORY_FILE)
+}
 
 fn load_history() {
-    unsafe {
-        if history_loaded { return; }
-        history_loaded = true;
-    }
+    if HISTORY_LOADED.swap(true, Ordering::SeqCst) { return; }
 
-    let path = Path::new(HISTORY_FILE);
-    let mut file = BufferedReader::new(File::open(&path));
+    let file = match File::open(history_path()) {
+        Ok(file) => BufReader::new(file),
+        Err(_) => return,
+    };
     for line in file.lines() {
         let rt: &[_] = &['\r', '\n'];
-        let line2 = line.unwrap();
-        let line3 = line2.as_slice().trim_right_chars(rt);
+        let line2 = match line { Ok(line) => line, Err(_) => break };
+        let line3 = line2.trim_end_matches(rt);
         add_history(line3);
     }
 }
 
 fn append_to_history(line: &str) {
-    let path = Path::new("/home/joelm/.mal-history");
-    let mut file = File::open_mode(&path, Append, Write);
-    let _ = file.write_line(line);
+    let file = OpenOptions::new().append(true).create(true).open(history_path());
+    if let Ok(mut file) = file {
+        let _ = writeln!(file, "{}", line);
+    }
 }
 
 pub fn mal_readline (prompt: &str) -> Option<String> {
@@ -68,8 +78,8 @@ pub fn mal_readline (prompt: &str) -> Option<String> {
     match line {
         None => None,
         _ => {
-            add_history(line.clone().unwrap().as_slice());
-            append_to_history(line.clone().unwrap().as_slice());
+            add_history(&line.clone().unwrap());
+            append_to_history(&line.clone().unwrap());
             line
         }
     }
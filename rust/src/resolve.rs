@@ -0,0 +1,281 @@
+// Lexical addressing for the stepA evaluator.
+//
+// The first time a fn* form is evaluated its body is walked once, and each
+// symbol bound by a fn*, let* or catch* inside it becomes a Local giving how
+// many frames out the binding is and its slot in that frame. Evaluating one
+// is then a short walk and an index instead of a string lookup in every
+// frame on the way to the root. Anything else, globals included, stays a
+// symbol and is looked up by name as before.
+//
+// The frames a binding form makes get one slot per parameter or let*
+// binding, plus one for each name def!'d directly in its body. Those are all
+// declared before the body is resolved, so a reference that comes before a
+// def!, say in a closure made earlier, still stops at the frame the def!
+// will bind in. A slot that isn't bound yet, such as a def! that hasn't run,
+// is looked up by name instead, so the result is always what the plain
+// lookup would have found.
+//
+// Quoted and quasiquoted forms are data and are left alone. So are the
+// arguments to macros: they may be resolved, since we can't tell a macro
+// call from a function call ahead of time, but strip turns them back into
+// plain code before the macro sees them.
+
+use std::rc::Rc;
+
+use types::{MalVal,Sym,List,Vector,Hash_Map,Local,Binder,Depth,
+            listm,vectorm,hash_mapm};
+use intern::{SymbolId,DEF,DEFMACRO,FN,LET,TRY,CATCH,QUOTE,QUASIQUOTE,
+             MACROEXPAND,AMPERSAND};
+use pvec::PVec;
+use pmap::PMap;
+
+// the slot names of the frames around the form being resolved, innermost
+// last
+type Scopes = Vec<Vec<SymbolId>>;
+
+// Resolves a fn* form that is being evaluated for the first time, which
+// includes any fn* and let* forms nested in it. Frames outside it are not
+// known, so what it doesn't bind itself stays a symbol. Returns None if
+// the form isn't a well-formed fn*.
+pub fn resolve_fn(ast: &MalVal) -> Option<MalVal> {
+    match **ast {
+        List(ref a, ref meta) => {
+            fn_form(a, &mut vec![]).map(|a| listm(a.into(), meta.clone()))
+        },
+        _ => None,
+    }
+}
+
+fn resolve(ast: &MalVal, scopes: &mut Scopes) -> MalVal {
+    // too deep a form is left as it is, to be looked up by name
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => return ast.clone(),
+    };
+    match **ast {
+        Sym(name) => local(name, scopes).unwrap_or_else(|| ast.clone()),
+        List(ref a, ref meta) => {
+            let head = match a.first().map(|h| &**h) {
+                Some(&Sym(s)) => Some(s),
+                _ => None,
+            };
+            let resolved = match head {
+                Some(QUOTE) | Some(QUASIQUOTE) | Some(MACROEXPAND) => None,
+                Some(DEF) | Some(DEFMACRO) => def_form(a, scopes),
+                Some(FN) => fn_form(a, scopes),
+                Some(LET) => let_form(a, scopes),
+                Some(TRY) => try_form(a, scopes),
+                _ => Some(resolve_all(a, scopes)),
+            };
+            match resolved {
+                Some(a) => listm(a.into(), meta.clone()),
+                None => ast.clone(),
+            }
+        },
+        Vector(ref a, ref meta) => vectorm(resolve_all(a, scopes).into(), meta.clone()),
+        Hash_Map(ref hm, ref meta) => {
+            let new_hm: PMap<MalVal> = hm.iter()
+                .map(|(k, v)| (k.clone(), resolve(v, scopes)))
+                .collect();
+            hash_mapm(new_hm, meta.clone())
+        },
+        _ => ast.clone(),
+    }
+}
+
+fn resolve_all(a: &PVec<MalVal>, scopes: &mut Scopes) -> Vec<MalVal> {
+    a.iter().map(|x| resolve(x, scopes)).collect()
+}
+
+fn local(name: SymbolId, scopes: &Scopes) -> Option<MalVal> {
+    for (depth, names) in scopes.iter().rev().enumerate() {
+        if let Some(slot) = names.iter().position(|&n| n == name) {
+            return Some(Rc::new(Local(name, depth, slot)));
+        }
+    }
+    None
+}
+
+fn declare(name: SymbolId, names: &mut Vec<SymbolId>) {
+    if !names.contains(&name) {
+        names.push(name);
+    }
+}
+
+// Declares the names ast def!s into the frame it's evaluated in, leaving out
+// the forms that make frames of their own (compiler.rs finds its slots with
+// this too)
+pub fn declare_defs(ast: &MalVal, names: &mut Vec<SymbolId>) {
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(_) => return,
+    };
+    let a = match **ast {
+        List(ref a,_) | Vector(ref a,_) => a,
+        Hash_Map(ref hm,_) => {
+            for v in hm.values() {
+                declare_defs(v, names);
+            }
+            return;
+        },
+        _ => return,
+    };
+    match a.first().map(|h| &**h) {
+        Some(&Sym(QUOTE)) | Some(&Sym(MACROEXPAND)) | Some(&Sym(FN)) | Some(&Sym(LET)) => return,
+        Some(&Sym(DEF)) | Some(&Sym(DEFMACRO)) if a.len() == 3 => {
+            if let Sym(name) = *a[1] {
+                declare(name, names);
+            }
+        },
+        // the catch* clause binds in a frame of its own
+        Some(&Sym(TRY)) if a.len() == 3 => return declare_defs(&a[1], names),
+        _ => (),
+    }
+    for x in a.iter() {
+        declare_defs(x, names);
+    }
+}
+
+// The forms below return None when malformed, which leaves them as they
+// were for eval to report on
+
+fn def_form(a: &PVec<MalVal>, scopes: &mut Scopes) -> Option<Vec<MalVal>> {
+    if a.len() != 3 {
+        return None;
+    }
+    match *a[1] {
+        Sym(name) => {
+            // declared before the value is resolved so that a local
+            // function can call itself
+            if let Some(names) = scopes.last_mut() {
+                declare(name, names);
+            }
+            Some(vec![a[0].clone(), a[1].clone(), resolve(&a[2], scopes)])
+        },
+        _ => None,
+    }
+}
+
+fn fn_form(a: &PVec<MalVal>, scopes: &mut Scopes) -> Option<Vec<MalVal>> {
+    if a.len() != 3 {
+        return None;
+    }
+    let mut names = vec![];
+    match *a[1] {
+        List(ref binds,_) | Vector(ref binds,_) => {
+            for b in binds.iter() {
+                match **b {
+                    Sym(AMPERSAND) => (),
+                    Sym(s) => declare(s, &mut names),
+                    _ => return None,
+                }
+            }
+        },
+        _ => return None,
+    }
+    declare_defs(&a[2], &mut names);
+    scopes.push(names);
+    let body = resolve(&a[2], scopes);
+    let names = scopes.pop().unwrap();
+    Some(vec![binder(FN, names), a[1].clone(), body])
+}
+
+fn let_form(a: &PVec<MalVal>, scopes: &mut Scopes) -> Option<Vec<MalVal>> {
+    if a.len() != 3 {
+        return None;
+    }
+    let binds = match *a[1] {
+        List(ref binds,_) | Vector(ref binds,_) if binds.len() % 2 == 0 => binds,
+        _ => return None,
+    };
+    let mut names = vec![];
+    for b in binds.iter().step_by(2) {
+        match **b {
+            Sym(s) => declare(s, &mut names),
+            _ => return None,
+        }
+    }
+    for b in binds.iter().skip(1).step_by(2) {
+        declare_defs(b, &mut names);
+    }
+    declare_defs(&a[2], &mut names);
+    // the values are evaluated in the new frame, as each can see the
+    // bindings before it
+    scopes.push(names);
+    let new_binds: PVec<MalVal> = binds.iter().enumerate()
+        .map(|(i, b)| if i % 2 == 0 { b.clone() } else { resolve(b, scopes) })
+        .collect();
+    let body = resolve(&a[2], scopes);
+    let names = scopes.pop().unwrap();
+    let new_binds = match *a[1] {
+        List(_, ref meta) => listm(new_binds, meta.clone()),
+        Vector(_, ref meta) => vectorm(new_binds, meta.clone()),
+        _ => unreachable!(),
+    };
+    Some(vec![binder(LET, names), new_binds, body])
+}
+
+fn try_form(a: &PVec<MalVal>, scopes: &mut Scopes) -> Option<Vec<MalVal>> {
+    if a.len() == 2 {
+        return Some(resolve_all(a, scopes));
+    }
+    if a.len() != 3 {
+        return None;
+    }
+    let (cat, meta) = match *a[2] {
+        List(ref cat, ref meta) if cat.len() == 3 => (cat, meta),
+        _ => return None,
+    };
+    match (&*cat[0], &*cat[1]) {
+        (&Sym(CATCH), &Sym(exc)) => {
+            let expr = resolve(&a[1], scopes);
+            let mut names = vec![exc];
+            declare_defs(&cat[2], &mut names);
+            scopes.push(names);
+            let handler = resolve(&cat[2], scopes);
+            let names = scopes.pop().unwrap();
+            let cat = listm(vec![binder(CATCH, names), cat[1].clone(), handler].into(),
+                            meta.clone());
+            Some(vec![a[0].clone(), expr, cat])
+        },
+        _ => None,
+    }
+}
+
+fn binder(form: SymbolId, names: Vec<SymbolId>) -> MalVal {
+    Rc::new(Binder(form, Rc::new(names)))
+}
+
+// Turns resolved code back into what was read, for handing to a macro
+pub fn strip(ast: &MalVal) -> MalVal {
+    strip_changed(ast).unwrap_or_else(|| ast.clone())
+}
+
+// None if there was nothing to strip, so unresolved code isn't copied
+fn strip_changed(ast: &MalVal) -> Option<MalVal> {
+    match **ast {
+        Local(name,_,_) | Binder(name,_) => Some(Rc::new(Sym(name))),
+        List(ref a, ref meta) => strip_all(a).map(|a| listm(a.into(), meta.clone())),
+        Vector(ref a, ref meta) => strip_all(a).map(|a| vectorm(a.into(), meta.clone())),
+        Hash_Map(ref hm, ref meta) => {
+            if hm.values().all(|v| strip_changed(v).is_none()) {
+                return None;
+            }
+            let new_hm: PMap<MalVal> = hm.iter()
+                .map(|(k, v)| (k.clone(), strip(v)))
+                .collect();
+            Some(hash_mapm(new_hm, meta.clone()))
+        },
+        _ => None,
+    }
+}
+
+fn strip_all(a: &PVec<MalVal>) -> Option<Vec<MalVal>> {
+    let stripped: Vec<Option<MalVal>> = a.iter().map(strip_changed).collect();
+    if stripped.iter().all(Option::is_none) {
+        return None;
+    }
+    Some(stripped.into_iter().zip(a.iter())
+         .map(|(s, v)| s.unwrap_or_else(|| v.clone()))
+         .collect())
+}
@@ -1,13 +1,12 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal};
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete};
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod env;
+mod gc;
 mod reader;
 mod printer;
 
@@ -40,13 +39,17 @@ fn rep(str: String) -> Result<String,MalError> {
 }
 
 fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
     loop {
         let line = readline::mal_readline("user> ");
         match line { None => break, _ => () }
         match rep(line.unwrap()) {
             Ok(str)  => println!("{}", str),
             Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ErrString(s)) | Err(ErrIncomplete(s)) => println!("Error: {}", s),
         }
     }
 }
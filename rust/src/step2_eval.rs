@@ -1,19 +1,21 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
 
-use std::collections::HashMap;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,err_str,
-            Int,Sym,List,Vector,Hash_Map,
-            _nil,_int,list,vector,hash_map,func};
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete,err_str,
+            Sym,List,Vector,Hash_Map,
+            _nil,list,vector,hash_map,func};
+use core::{add,sub,mul,div};
+use intern::{SymbolId,SymbolMap};
+use pmap::PMap;
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod reader;
 mod printer;
 mod env; // because types uses env
+mod gc;
+mod core;
 
 // read
 fn read(str: String) -> MalRet {
@@ -21,10 +23,10 @@ fn read(str: String) -> MalRet {
 }
 
 // eval
-fn eval_ast(ast: MalVal, env: &HashMap<String,MalVal>) -> MalRet {
+fn eval_ast(ast: MalVal, env: &SymbolMap<MalVal>) -> MalRet {
     match *ast {
-        Sym(ref sym) => {
-            match env.find_copy(sym) {
+        Sym(sym) => {
+            match env.get(&sym).cloned() {
                 Some(mv) => Ok(mv),
                 None     => Ok(_nil()),
             }
@@ -41,7 +43,7 @@ fn eval_ast(ast: MalVal, env: &HashMap<String,MalVal>) -> MalRet {
                             _         => vector(ast_vec) })
         },
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm: PMap<MalVal> = PMap::new();
             for (key, value) in hm.iter() {
                 match eval(value.clone(), env) {
                     Ok(mv) => { new_hm.insert(key.to_string(), mv); },
@@ -56,7 +58,7 @@ fn eval_ast(ast: MalVal, env: &HashMap<String,MalVal>) -> MalRet {
     }
 }
 
-fn eval(ast: MalVal, env: &HashMap<String,MalVal>) -> MalRet {
+fn eval(ast: MalVal, env: &SymbolMap<MalVal>) -> MalRet {
     let ast2 = ast.clone();
     match *ast2 {
         List(_,_) => (),  // continue
@@ -70,7 +72,7 @@ fn eval(ast: MalVal, env: &HashMap<String,MalVal>) -> MalRet {
             match *el {
                 List(ref args,_) => {
                     let ref f = args.clone()[0];
-                    f.apply(args.slice(1,args.len()).to_vec())
+                    f.apply(args.slice(1..args.len()))
                 }
                 _ => err_str("Invalid apply"),
             }
@@ -83,7 +85,7 @@ fn print(exp: MalVal) -> String {
     exp.pr_str(true)
 }
 
-fn rep(str: &str, env: &HashMap<String,MalVal>) -> Result<String,MalError> {
+fn rep(str: &str, env: &SymbolMap<MalVal>) -> Result<String,MalError> {
     match read(str.to_string()) {
         Err(e) => Err(e),
         Ok(ast) => {
@@ -96,34 +98,24 @@ fn rep(str: &str, env: &HashMap<String,MalVal>) -> Result<String,MalError> {
     }
 }
 
-fn int_op(f: |i:int,j:int|-> int, a:Vec<MalVal>) -> MalRet {
-    match *a[0] {
-        Int(a0) => match *a[1] {
-            Int(a1) => Ok(_int(f(a0,a1))),
-            _ => err_str("second arg must be an int"),
-        },
-        _ => err_str("first arg must be an int"),
-    }
+fn main() {
+    types::run_deep(repl);
 }
-fn add(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i+j }, a) }
-fn sub(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i-j }, a) }
-fn mul(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i*j }, a) }
-fn div(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i/j }, a) }
 
-fn main() {
-    let mut repl_env : HashMap<String,MalVal> = HashMap::new();
-    repl_env.insert("+".to_string(), func(add));
-    repl_env.insert("-".to_string(), func(sub));
-    repl_env.insert("*".to_string(), func(mul));
-    repl_env.insert("/".to_string(), func(div));
+fn repl() {
+    let mut repl_env : SymbolMap<MalVal> = SymbolMap::default();
+    repl_env.insert(SymbolId::intern("+"), func(add));
+    repl_env.insert(SymbolId::intern("-"), func(sub));
+    repl_env.insert(SymbolId::intern("*"), func(mul));
+    repl_env.insert(SymbolId::intern("/"), func(div));
 
     loop {
         let line = readline::mal_readline("user> ");
         match line { None => break, _ => () }
-        match rep(line.unwrap().as_slice(), &repl_env) {
+        match rep(&line.unwrap(), &repl_env) {
             Ok(str)  => println!("{}", str),
             Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ErrString(s)) | Err(ErrIncomplete(s)) => println!("Error: {}", s),
         }
     }
 }
@@ -1,20 +1,22 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
 
-use std::collections::HashMap;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,err_str,
-            Int,Sym,List,Vector,Hash_Map,
-            symbol,_int,list,vector,hash_map,func};
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete,err_str,
+            Sym,List,Vector,Hash_Map,
+            symbol,list,vector,hash_map,func};
+use core::{add,sub,mul,div};
 use env::{Env,env_new,env_set,env_get};
+use intern::{DEF,LET};
+use pmap::PMap;
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod reader;
 mod printer;
 mod env;
+mod gc;
+mod core;
 
 // read
 fn read(str: String) -> MalRet {
@@ -42,7 +44,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
                             _         => vector(ast_vec) })
         },
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm: PMap<MalVal> = PMap::new();
             for (key, value) in hm.iter() {
                 match eval(value.clone(), env.clone()) {
                     Ok(mv) => { new_hm.insert(key.to_string(), mv); },
@@ -79,15 +81,15 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             }
             let ref a0 = *args[0];
             match *a0 {
-                Sym(ref a0sym) => (args, a0sym.as_slice()),
-                _ => (args, "__<fn*>__"),
+                Sym(a0sym) => (args, Some(a0sym)),
+                _ => (args, None),
             }
         },
         _ => return err_str("Expected list"),
     };
 
     match a0sym {
-        "def!" => {
+        Some(DEF) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             let res = eval(a2, env.clone());
@@ -106,7 +108,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "let*" => {
+        Some(LET) => {
             let let_env = env_new(Some(env.clone()));
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
@@ -146,7 +148,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                         _ => return err_str("Invalid apply"),
                     };
                     let ref f = args.clone()[0];
-                    f.apply(args.slice(1,args.len()).to_vec())
+                    f.apply(args.slice(1..args.len()))
                 }
             };
         },
@@ -171,21 +173,11 @@ fn rep(str: &str, env: Env) -> Result<String,MalError> {
     }
 }
 
-fn int_op(f: |i:int,j:int|-> int, a:Vec<MalVal>) -> MalRet {
-    match *a[0] {
-        Int(a0) => match *a[1] {
-            Int(a1) => Ok(_int(f(a0,a1))),
-            _ => err_str("second arg must be an int"),
-        },
-        _ => err_str("first arg must be an int"),
-    }
+fn main() {
+    types::run_deep(repl);
 }
-fn add(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i+j }, a) }
-fn sub(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i-j }, a) }
-fn mul(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i*j }, a) }
-fn div(a:Vec<MalVal>) -> MalRet { int_op(|i,j| { i/j }, a) }
 
-fn main() {
+fn repl() {
     let repl_env = env_new(None);
     env_set(&repl_env, symbol("+"), func(add));
     env_set(&repl_env, symbol("-"), func(sub));
@@ -195,10 +187,10 @@ fn main() {
     loop {
         let line = readline::mal_readline("user> ");
         match line { None => break, _ => () }
-        match rep(line.unwrap().as_slice(), repl_env.clone()) {
+        match rep(&line.unwrap(), repl_env.clone()) {
             Ok(str)  => println!("{}", str),
             Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ErrString(s)) | Err(ErrIncomplete(s)) => println!("Error: {}", s),
         }
     }
 }
@@ -1,20 +1,20 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
 
-use std::collections::HashMap;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,err_str,
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete,err_str,
             Nil,False,Sym,List,Vector,Hash_Map,
             symbol,_nil,list,vector,hash_map,malfunc};
 use env::{Env,env_new,env_set,env_get};
+use intern::{DEF,LET,DO,IF,FN};
+use pmap::PMap;
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod reader;
 mod printer;
 mod env;
+mod gc;
 mod core;
 
 // read
@@ -43,7 +43,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
                             _         => vector(ast_vec) })
         },
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm: PMap<MalVal> = PMap::new();
             for (key, value) in hm.iter() {
                 match eval(value.clone(), env.clone()) {
                     Ok(mv) => { new_hm.insert(key.to_string(), mv); },
@@ -80,15 +80,15 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             }
             let ref a0 = *args[0];
             match *a0 {
-                Sym(ref a0sym) => (args, a0sym.as_slice()),
-                _ => (args, "__<fn*>__"),
+                Sym(a0sym) => (args, Some(a0sym)),
+                _ => (args, None),
             }
         },
         _ => return err_str("Expected list"),
     };
 
     match a0sym {
-        "def!" => {
+        Some(DEF) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             let res = eval(a2, env.clone());
@@ -107,7 +107,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "let*" => {
+        Some(LET) => {
             let let_env = env_new(Some(env.clone()));
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
@@ -138,9 +138,9 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             }
             return eval(a2, let_env.clone());
         },
-        "do" => {
-            let el = list(args.slice(1,args.len()).to_vec());
-            return match eval_ast(el, env.clone()) {
+        Some(DO) => {
+            let el = list(args.slice(1..args.len()));
+            match eval_ast(el, env.clone()) {
                 Err(e) => return Err(e),
                 Ok(el) => {
                     match *el {
@@ -153,7 +153,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 },
             };
         },
-        "if" => {
+        Some(IF) => {
             let a1 = (*args)[1].clone();
             let cond = eval(a1, env.clone());
             match cond {
@@ -174,7 +174,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 }
             }
         },
-        "fn*" => {
+        Some(FN) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             return Ok(malfunc(eval, a2, env.clone(), a1, _nil()));
@@ -188,7 +188,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                         _ => return err_str("Invalid apply"),
                     };
                     let ref f = args.clone()[0];
-                    f.apply(args.slice(1,args.len()).to_vec())
+                    f.apply(args.slice(1..args.len()))
                 }
             };
         },
@@ -214,10 +214,14 @@ fn rep(str: &str, env: Env) -> Result<String,MalError> {
 }
 
 fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns().into_iter() {
-        env_set(&repl_env, symbol(k.as_slice()), v);
+        env_set(&repl_env, symbol(&k), v);
     }
 
     // core.mal: defined using the language itself
@@ -226,10 +230,10 @@ fn main() {
     loop {
         let line = readline::mal_readline("user> ");
         match line { None => break, _ => () }
-        match rep(line.unwrap().as_slice(), repl_env.clone()) {
+        match rep(&line.unwrap(), repl_env.clone()) {
             Ok(str)  => println!("{}", str),
             Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ErrString(s)) | Err(ErrIncomplete(s)) => println!("Error: {}", s),
         }
     }
 }
@@ -1,20 +1,20 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
 
-use std::collections::HashMap;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,err_str,
-            Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete,err_str,
+            Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,Depth,
             symbol,_nil,list,vector,hash_map,malfunc};
-use env::{Env,env_new,env_bind,env_set,env_get};
+use env::{Env,env_new,env_frame,env_bind,env_set,env_get};
+use intern::{DEF,LET,DO,IF,FN};
+use pmap::PMap;
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod reader;
 mod printer;
 mod env;
+mod gc;
 mod core;
 
 // read
@@ -43,7 +43,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
                             _         => vector(ast_vec) })
         },
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm: PMap<MalVal> = PMap::new();
             for (key, value) in hm.iter() {
                 match eval(value.clone(), env.clone()) {
                     Ok(mv) => { new_hm.insert(key.to_string(), mv); },
@@ -59,6 +59,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
 }
 
 fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+    let _depth = Depth::enter()?;
     'tco: loop {
 
     //println!("eval: {}, {}", ast, env.borrow());
@@ -83,15 +84,15 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             }
             let ref a0 = *args[0];
             match *a0 {
-                Sym(ref a0sym) => (args, a0sym.as_slice()),
-                _ => (args, "__<fn*>__"),
+                Sym(a0sym) => (args, Some(a0sym)),
+                _ => (args, None),
             }
         },
         _ => return err_str("Expected list"),
     };
 
     match a0sym {
-        "def!" => {
+        Some(DEF) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             let res = eval(a2, env.clone());
@@ -110,7 +111,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "let*" => {
+        Some(LET) => {
             let let_env = env_new(Some(env.clone()));
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
@@ -143,8 +144,8 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             env = let_env.clone();
             continue 'tco;
         },
-        "do" => {
-            let el = list(args.slice(1,args.len()-1).to_vec());
+        Some(DO) => {
+            let el = list(args.slice(1..args.len()-1));
             match eval_ast(el, env.clone()) {
                 Err(e) => return Err(e),
                 Ok(_) => {
@@ -154,7 +155,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 },
             }
         },
-        "if" => {
+        Some(IF) => {
             let a1 = (*args)[1].clone();
             let cond = eval(a1, env.clone());
             match cond {
@@ -179,7 +180,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
             }
         },
-        "fn*" => {
+        Some(FN) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             return Ok(malfunc(eval, a2, env.clone(), a1, _nil()));
@@ -193,18 +194,18 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         _ => return err_str("Invalid apply"),
                     };
                     match *args.clone()[0] {
-                        Func(f,_) => f(args.slice(1,args.len()).to_vec()),
+                        Func(f,_) => f(args.slice(1..args.len())),
                         MalFunc(ref mf,_) => {
                             let mfc = mf.clone();
-                            let alst = list(args.slice(1,args.len()).to_vec());
-                            let new_env = env_new(Some(mfc.env.clone()));
+                            let alst = list(args.slice(1..args.len()));
+                            let new_env = env_frame(Some(mfc.env.clone()), mfc.names.clone());
                             match env_bind(&new_env, mfc.params, alst) {
                                 Ok(_) => {
                                     ast = mfc.exp;
                                     env = new_env;
                                     continue 'tco;
                                 },
-                                Err(e) => err_str(e.as_slice()),
+                                Err(e) => err_str(&e),
                             }
                         },
                         _ => err_str("attempt to call non-function"),
@@ -236,10 +237,14 @@ fn rep(str: &str, env: Env) -> Result<String,MalError> {
 }
 
 fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns().into_iter() {
-        env_set(&repl_env, symbol(k.as_slice()), v);
+        env_set(&repl_env, symbol(&k), v);
     }
 
     // core.mal: defined using the language itself
@@ -248,10 +253,10 @@ fn main() {
     loop {
         let line = readline::mal_readline("user> ");
         match line { None => break, _ => () }
-        match rep(line.unwrap().as_slice(), repl_env.clone()) {
+        match rep(&line.unwrap(), repl_env.clone()) {
             Ok(str)  => println!("{}", str),
             Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ErrString(s)) | Err(ErrIncomplete(s)) => println!("Error: {}", s),
         }
     }
 }
@@ -1,21 +1,21 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
+use std::process;
 
-use std::collections::HashMap;
-use std::os;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,err_str,
-            Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete,err_str,
+            Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,Depth,
             symbol,_nil,string,list,vector,hash_map,malfunc,malfuncd};
-use env::{Env,env_new,env_bind,env_root,env_find,env_set,env_get};
+use env::{Env,env_new,env_frame,env_bind,env_root,env_find,env_set,env_get};
+use intern::{DEF,LET,DO,IF,FN,QUOTE,QUASIQUOTE,UNQUOTE,SPLICE_UNQUOTE,DEFMACRO,MACROEXPAND,EVAL};
+use pmap::PMap;
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod reader;
 mod printer;
 mod env;
+mod gc;
 mod core;
 
 // read
@@ -40,8 +40,8 @@ fn quasiquote(ast: MalVal) -> MalVal {
         List(ref args,_) | Vector(ref args,_) => {
             let ref a0 = args[0];
             match **a0 {
-                Sym(ref s) => {
-                    if s.to_string() == "unquote".to_string() {
+                Sym(s) => {
+                    if s == UNQUOTE {
                         let ref a1 = args[1];
                         return a1.clone();
                     }
@@ -53,11 +53,11 @@ fn quasiquote(ast: MalVal) -> MalVal {
                     List(ref a0args,_) | Vector(ref a0args,_) => {
                         let a00 = a0args[0].clone();
                         match *a00 {
-                            Sym(ref s) => {
-                                if s.to_string() == "splice-unquote".to_string() {
+                            Sym(s) => {
+                                if s == SPLICE_UNQUOTE {
                                     return list(vec![symbol("concat"),
                                                      a0args[1].clone(),
-                                                     quasiquote(list(args.slice(1,args.len()).to_vec()))])
+                                                     quasiquote(list(args.slice(1..args.len())))])
                                 }
                             },
                             _ => (),
@@ -66,7 +66,7 @@ fn quasiquote(ast: MalVal) -> MalVal {
                     _ => (),
                 }
             }
-            let rest = list(args.slice(1,args.len()).to_vec());
+            let rest = list(args.slice(1..args.len()));
             return list(vec![symbol("cons"),
                              quasiquote(a0.clone()),
                              quasiquote(rest)])
@@ -122,7 +122,7 @@ fn macroexpand(mut ast: MalVal, env: Env) -> MalRet {
         };
         match *mf {
             MalFunc(_,_) => {
-                match mf.apply(args.slice(1,args.len()).to_vec()) {
+                match mf.apply(args.slice(1..args.len())) {
                     Ok(r) => ast = r,
                     Err(e) => return Err(e),
                 }
@@ -153,7 +153,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
                             _         => vector(ast_vec) })
         },
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm: PMap<MalVal> = PMap::new();
             for (key, value) in hm.iter() {
                 match eval(value.clone(), env.clone()) {
                     Ok(mv) => { new_hm.insert(key.to_string(), mv); },
@@ -169,6 +169,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
 }
 
 fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+    let _depth = Depth::enter()?;
     'tco: loop {
 
     //println!("eval: {}, {}", ast, env.borrow());
@@ -199,15 +200,15 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             }
             let ref a0 = *args[0];
             match *a0 {
-                Sym(ref a0sym) => (args, a0sym.as_slice()),
-                _ => (args, "__<fn*>__"),
+                Sym(a0sym) => (args, Some(a0sym)),
+                _ => (args, None),
             }
         },
         _ => return err_str("Expected list"),
     };
 
     match a0sym {
-        "def!" => {
+        Some(DEF) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             let res = eval(a2, env.clone());
@@ -226,7 +227,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "let*" => {
+        Some(LET) => {
             let let_env = env_new(Some(env.clone()));
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
@@ -259,15 +260,15 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             env = let_env.clone();
             continue 'tco;
         },
-        "quote" => {
+        Some(QUOTE) => {
             return Ok((*args)[1].clone());
         },
-        "quasiquote" => {
+        Some(QUASIQUOTE) => {
             let a1 = (*args)[1].clone();
             ast = quasiquote(a1);
             continue 'tco;
         },
-        "defmacro!" => {
+        Some(DEFMACRO) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             match eval(a2, env.clone()) {
@@ -291,12 +292,12 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "macroexpand" => {
+        Some(MACROEXPAND) => {
             let a1 = (*args)[1].clone();
             return macroexpand(a1, env.clone())
         },
-        "do" => {
-            let el = list(args.slice(1,args.len()-1).to_vec());
+        Some(DO) => {
+            let el = list(args.slice(1..args.len()-1));
             match eval_ast(el, env.clone()) {
                 Err(e) => return Err(e),
                 Ok(_) => {
@@ -306,7 +307,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 },
             }
         },
-        "if" => {
+        Some(IF) => {
             let a1 = (*args)[1].clone();
             let cond = eval(a1, env.clone());
             match cond {
@@ -331,12 +332,12 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
             }
         },
-        "fn*" => {
+        Some(FN) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             return Ok(malfunc(eval, a2, env.clone(), a1, _nil()));
         },
-        "eval" => {
+        Some(EVAL) => {
             let a1 = (*args)[1].clone();
             match eval(a1, env.clone()) {
                 Ok(exp) => {
@@ -356,18 +357,18 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         _ => return err_str("Invalid apply"),
                     };
                     match *args.clone()[0] {
-                        Func(f,_) => f(args.slice(1,args.len()).to_vec()),
+                        Func(f,_) => f(args.slice(1..args.len())),
                         MalFunc(ref mf,_) => {
                             let mfc = mf.clone();
-                            let alst = list(args.slice(1,args.len()).to_vec());
-                            let new_env = env_new(Some(mfc.env.clone()));
+                            let alst = list(args.slice(1..args.len()));
+                            let new_env = env_frame(Some(mfc.env.clone()), mfc.names.clone());
                             match env_bind(&new_env, mfc.params, alst) {
                                 Ok(_) => {
                                     ast = mfc.exp;
                                     env = new_env;
                                     continue 'tco;
                                 },
-                                Err(e) => err_str(e.as_slice()),
+                                Err(e) => err_str(&e),
                             }
                         },
                         _ => err_str("attempt to call non-function"),
@@ -399,37 +400,39 @@ fn rep(str: &str, env: Env) -> Result<String,MalError> {
 }
 
 fn main() {
+    types::run_deep(repl);
+}
+
+fn repl() {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns().into_iter() {
-        env_set(&repl_env, symbol(k.as_slice()), v);
+        env_set(&repl_env, symbol(&k), v);
     }
     // see eval() for definition of "eval"
-    env_set(&repl_env, symbol("*ARGV*".as_slice()), list(vec![]));
+    env_set(&repl_env, symbol("*ARGV*"), list(vec![]));
 
     // core.mal: defined using the language itself
     let _ = rep("(def! not (fn* (a) (if a false true)))", repl_env.clone());
-    let _ = rep("(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \")\")))))", repl_env.clone());
+    let _ = rep("(def! load-file (fn* (f) (load-string (slurp f) f (fn* (form) (eval form)))))", repl_env.clone());
     let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", repl_env.clone());
     let _ = rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or_FIXME ~(first xs)) (if or_FIXME or_FIXME (or ~@(rest xs))))))))", repl_env.clone());
 
     // Invoked with command line arguments
-    let args = os::args();
+    let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
-        let mv_args = args.slice(2,args.len()).iter()
+        let mv_args = args[2..args.len()].iter()
             .map(|a| string(a.to_string()))
             .collect::<Vec<MalVal>>();
-        env_set(&repl_env, symbol("*ARGV*".as_slice()), list(mv_args));
-        let lf = "(load-file \"".to_string() + args[1] + "\")".to_string();
-        match rep(lf.as_slice(), repl_env.clone()) {
+        env_set(&repl_env, symbol("*ARGV*"), list(mv_args));
+        let lf = "(load-file \"".to_string() + &args[1] + "\")";
+        match rep(&lf, repl_env.clone()) {
             Ok(_) => {
-                os::set_exit_status(0);
-                return;
+                process::exit(0);
             },
             Err(str) => {
-                println!("Error: {}", str);
-                os::set_exit_status(1);
-                return;
+                println!("Error: {:?}", str);
+                process::exit(1);
             },
         }
     }
@@ -438,10 +441,10 @@ fn main() {
     loop {
         let line = readline::mal_readline("user> ");
         match line { None => break, _ => () }
-        match rep(line.unwrap().as_slice(), repl_env.clone()) {
+        match rep(&line.unwrap(), repl_env.clone()) {
             Ok(str)  => println!("{}", str),
             Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(ErrString(s)) | Err(ErrIncomplete(s)) => println!("Error: {}", s),
         }
     }
 }
@@ -1,26 +1,31 @@
-// support precompiled regexes in reader.rs
-#![feature(phase)]
-#[phase(plugin)]
-extern crate regex_macros;
-extern crate regex;
+use std::process;
+use std::rc::Rc;
 
-use std::collections::HashMap;
-use std::os;
-
-use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,err_str,
-            Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,
-            symbol,_nil,string,list,vector,hash_map,malfunc,malfuncd};
-use env::{Env,env_new,env_bind,env_root,env_find,env_set,env_get};
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,ErrIncomplete,err_str,
+            MalFuncData,Nil,False,Sym,List,Vector,Hash_Map,Func,MalFunc,Local,Binder,Call,Depth,
+            symbol,_nil,string,list,vector,hash_map,malfunc,malfuncd,named,source,exception};
+use env::{Env,env_new,env_frame,env_bind,env_root,env_find,env_set,env_get,env_get_local};
+use intern::{DEF,LET,DO,IF,FN,QUOTE,QUASIQUOTE,UNQUOTE,SPLICE_UNQUOTE,DEFMACRO,MACROEXPAND,TRY,EVAL};
+use pmap::PMap;
 mod readline;
 mod types;
+mod intern;
+mod bignum;
+mod pvec;
+mod pmap;
 mod reader;
 mod printer;
 mod env;
+mod gc;
 mod core;
+mod resolve;
+mod analyze;
+mod compiler;
+mod vm;
 
 // read
-fn read(str: String) -> MalRet {
-    reader::read_str(str)
+fn read(str: &str) -> Result<Vec<reader::Form>,MalError> {
+    reader::read_all(str)
 }
 
 // eval
@@ -40,8 +45,8 @@ fn quasiquote(ast: MalVal) -> MalVal {
         List(ref args,_) | Vector(ref args,_) => {
             let ref a0 = args[0];
             match **a0 {
-                Sym(ref s) => {
-                    if s.to_string() == "unquote".to_string() {
+                Sym(s) => {
+                    if s == UNQUOTE {
                         let ref a1 = args[1];
                         return a1.clone();
                     }
@@ -53,11 +58,11 @@ fn quasiquote(ast: MalVal) -> MalVal {
                     List(ref a0args,_) | Vector(ref a0args,_) => {
                         let a00 = a0args[0].clone();
                         match *a00 {
-                            Sym(ref s) => {
-                                if s.to_string() == "splice-unquote".to_string() {
+                            Sym(s) => {
+                                if s == SPLICE_UNQUOTE {
                                     return list(vec![symbol("concat"),
                                                      a0args[1].clone(),
-                                                     quasiquote(list(args.slice(1,args.len()).to_vec()))])
+                                                     quasiquote(list(args.slice(1..args.len())))])
                                 }
                             },
                             _ => (),
@@ -66,7 +71,7 @@ fn quasiquote(ast: MalVal) -> MalVal {
                     _ => (),
                 }
             }
-            let rest = list(args.slice(1,args.len()).to_vec());
+            let rest = list(args.slice(1..args.len()));
             return list(vec![symbol("cons"),
                              quasiquote(a0.clone()),
                              quasiquote(rest)])
@@ -80,20 +85,23 @@ fn is_macro_call(ast: MalVal, env: Env) -> bool {
         List(ref lst,_) => {
             match *lst[0] {
                 Sym(_) => {
-                    if env_find(env.clone(), lst[0].clone()).is_some() {
-                        match env_get(env, lst[0].clone()) {
-                            Ok(f) => {
-                                match *f {
-                                    MalFunc(ref mfd,_) => {
-                                        mfd.is_macro
-                                    },
-                                    _ => false,
-                                }
-                            },
-                            _ => false,
-                        }
-                    } else {
-                        false
+                    // get from the frame it was found in rather than
+                    // walking out to it a second time
+                    match env_find(env, lst[0].clone()) {
+                        Some(e) => {
+                            match env_get(e, lst[0].clone()) {
+                                Ok(f) => {
+                                    match *f {
+                                        MalFunc(ref mfd,_) => {
+                                            mfd.is_macro
+                                        },
+                                        _ => false,
+                                    }
+                                },
+                                _ => false,
+                            }
+                        },
+                        None => false,
                     }
                 },
                 _ => false,
@@ -122,7 +130,7 @@ fn macroexpand(mut ast: MalVal, env: Env) -> MalRet {
         };
         match *mf {
             MalFunc(_,_) => {
-                match mf.apply(args.slice(1,args.len()).to_vec()) {
+                match mf.apply(args.iter().skip(1).map(resolve::strip).collect()) {
                     Ok(r) => ast = r,
                     Err(e) => return Err(e),
                 }
@@ -140,6 +148,12 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
         Sym(_) => {
             env_get(env.clone(), ast)
         },
+        Local(name, depth, slot) => {
+            match env_get_local(&env, depth, slot) {
+                Some(v) => Ok(v),
+                None => env_get(env.clone(), Rc::new(Sym(name))),
+            }
+        },
         List(ref a,_) | Vector(ref a,_) => {
             let mut ast_vec : Vec<MalVal> = vec![];
             for mv in a.iter() {
@@ -153,7 +167,7 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
                             _         => vector(ast_vec) })
         },
         Hash_Map(ref hm,_) => {
-            let mut new_hm: HashMap<String,MalVal> = HashMap::new();
+            let mut new_hm: PMap<MalVal> = PMap::new();
             for (key, value) in hm.iter() {
                 match eval(value.clone(), env.clone()) {
                     Ok(mv) => { new_hm.insert(key.to_string(), mv); },
@@ -168,7 +182,15 @@ fn eval_ast(ast: MalVal, env: Env) -> MalRet {
     }
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    let _depth = Depth::enter()?;
+    // the call stack entry of the functions eval_tail goes on to run
+    let mut call = Call::new();
+    let res = eval_tail(ast, env, &mut call);
+    call.exit(res)
+}
+
+fn eval_tail(mut ast: MalVal, mut env: Env, call: &mut Call) -> MalRet {
     'tco: loop {
 
     //println!("eval: {}, {}", ast, env.borrow());
@@ -199,22 +221,23 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             }
             let ref a0 = *args[0];
             match *a0 {
-                Sym(ref a0sym) => (args, a0sym.as_slice()),
-                _ => (args, "__<fn*>__"),
+                Sym(a0sym) | Binder(a0sym,_) => (args, Some(a0sym)),
+                _ => (args, None),
             }
         },
         _ => return err_str("Expected list"),
     };
 
     match a0sym {
-        "def!" => {
+        Some(DEF) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             let res = eval(a2, env.clone());
             match res {
                 Ok(r) => {
                     match *a1 {
-                        Sym(_) => {
+                        Sym(name) => {
+                            let r = named(r, name);
                             env_set(&env.clone(), a1.clone(), r.clone());
                             return Ok(r);
                         },
@@ -226,8 +249,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "let*" => {
-            let let_env = env_new(Some(env.clone()));
+        Some(LET) => {
+            let let_env = match *args[0] {
+                Binder(_, ref names) => env_frame(Some(env.clone()), names.clone()),
+                _ => env_new(Some(env.clone())),
+            };
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             match *a1 {
@@ -259,15 +285,15 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
             env = let_env.clone();
             continue 'tco;
         },
-        "quote" => {
+        Some(QUOTE) => {
             return Ok((*args)[1].clone());
         },
-        "quasiquote" => {
+        Some(QUASIQUOTE) => {
             let a1 = (*args)[1].clone();
             ast = quasiquote(a1);
             continue 'tco;
         },
-        "defmacro!" => {
+        Some(DEFMACRO) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
             match eval(a2, env.clone()) {
@@ -275,10 +301,10 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     match *r {
                         MalFunc(ref mfd,_) => {
                             match *a1 {
-                                Sym(_) => {
+                                Sym(name) => {
                                     let mut new_mfd = mfd.clone();
                                     new_mfd.is_macro = true;
-                                    let mf = malfuncd(new_mfd,_nil());
+                                    let mf = named(malfuncd(new_mfd,_nil()), name);
                                     env_set(&env.clone(), a1.clone(), mf.clone());
                                     return Ok(mf);
                                 },
@@ -291,11 +317,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 Err(e) => return Err(e),
             }
         },
-        "macroexpand" => {
+        Some(MACROEXPAND) => {
             let a1 = (*args)[1].clone();
             return macroexpand(a1, env.clone())
         },
-        "try*" => {
+        Some(TRY) => {
             let a1 = (*args)[1].clone();
             match eval(a1, env.clone()) {
                 Ok(res) => return Ok(res),
@@ -314,19 +340,19 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         Sym(_) => {},
                         _ => return err_str("invalid catch* binding"),
                     };
-                    let exc = match err {
-                        ErrMalVal(mv) => mv,
-                        ErrString(s) => string(s),
+                    let exc = exception(err);
+                    let bind_env = match *cat[0] {
+                        Binder(_, ref names) => env_frame(Some(env.clone()), names.clone()),
+                        _ => env_new(Some(env.clone())),
                     };
-                    let bind_env = env_new(Some(env.clone()));
                     env_set(&bind_env, c1.clone(), exc);
                     let c2 = (*cat)[2].clone();
                     return eval(c2, bind_env);
                 },
             };
         }
-        "do" => {
-            let el = list(args.slice(1,args.len()-1).to_vec());
+        Some(DO) => {
+            let el = list(args.slice(1..args.len()-1));
             match eval_ast(el, env.clone()) {
                 Err(e) => return Err(e),
                 Ok(_) => {
@@ -336,7 +362,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 },
             }
         },
-        "if" => {
+        Some(IF) => {
             let a1 = (*args)[1].clone();
             let cond = eval(a1, env.clone());
             match cond {
@@ -361,12 +387,27 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
             }
         },
-        "fn*" => {
+        Some(FN) => {
             let a1 = (*args)[1].clone();
             let a2 = (*args)[2].clone();
-            return Ok(malfunc(eval, a2, env.clone(), a1, _nil()));
+            let names = match *args[0] {
+                Binder(_, ref names) => names.clone(),
+                _ => match resolve::resolve_fn(&ast3) {
+                    // resolved the first time it's evaluated
+                    Some(resolved) => {
+                        ast = resolved;
+                        continue 'tco;
+                    },
+                    None => return Ok(malfunc(eval, a2, env.clone(), a1, _nil())),
+                },
+            };
+            return Ok(malfuncd(MalFuncData{eval: eval, exp: a2, env: env.clone(),
+                                           params: a1, names: names,
+                                           is_macro: false, code: None,
+                                           bytecode: None, name: None,
+                                           pos: source()}, _nil()));
         },
-        "eval" => {
+        Some(EVAL) => {
             let a1 = (*args)[1].clone();
             match eval(a1, env.clone()) {
                 Ok(exp) => {
@@ -386,18 +427,19 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         _ => return err_str("Invalid apply"),
                     };
                     match *args.clone()[0] {
-                        Func(f,_) => f(args.slice(1,args.len()).to_vec()),
+                        Func(f,_) => f(args.slice(1..args.len())),
                         MalFunc(ref mf,_) => {
+                            call.enter(mf);
                             let mfc = mf.clone();
-                            let alst = list(args.slice(1,args.len()).to_vec());
-                            let new_env = env_new(Some(mfc.env.clone()));
+                            let alst = list(args.slice(1..args.len()));
+                            let new_env = env_frame(Some(mfc.env.clone()), mfc.names.clone());
                             match env_bind(&new_env, mfc.params, alst) {
                                 Ok(_) => {
                                     ast = mfc.exp;
                                     env = new_env;
                                     continue 'tco;
                                 },
-                                Err(e) => err_str(e.as_slice()),
+                                Err(e) => err_str(&e),
                             }
                         },
                         _ => err_str("attempt to call non-function"),
@@ -415,65 +457,127 @@ fn print(exp: MalVal) -> String {
     exp.pr_str(true)
 }
 
-fn rep(str: &str, env: Env) -> Result<String,MalError> {
-    match read(str.to_string()) {
-        Err(e) => Err(e),
-        Ok(ast) => {
-            //println!("read: {}", ast);
-            match eval(ast, env) {
-                Err(e)  => Err(e),
-                Ok(exp) => Ok(print(exp)),
-            }
+// Every form in str is evaluated in turn, as load-file does, and the last
+// one's value printed
+fn rep(str: &str, env: Env, evaluator: fn(MalVal, Env) -> MalRet) -> Result<String,MalError> {
+    let mut exp = _nil();
+    for form in read(str)? {
+        exp = evaluator(form.value, env.clone())?;
+    }
+    Ok(print(exp))
+}
+
+// Prints an error nothing caught, and the calls it came out of
+fn print_error(e: MalError) {
+    match e {
+        ErrString(s) | ErrIncomplete(s) => println!("Error: {}", s),
+        ErrMalVal(mv) => println!("Error: {}", mv.pr_str(true)),
+    }
+    // a runaway recursion has thousands of frames, most of them the same
+    let trace = types::take_trace();
+    for (i, frame) in trace.iter().enumerate() {
+        if i == 10 && trace.len() > 20 {
+            println!("  ... {} more", trace.len() - 20);
+        }
+        if i < 10 || i >= trace.len() - 10 {
+            println!("  in {}", frame);
         }
     }
 }
 
 fn main() {
+    // --eval=closure or --eval=vm, ahead of any file name, runs everything
+    // through the closure compiler in analyze.rs or the bytecode VM in vm.rs
+    // instead of eval. --max-depth=N sets how deep evaluation may nest.
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut evaluator: fn(MalVal, Env) -> MalRet = eval;
+    while args.len() > 1 {
+        let arg = args[1].clone();
+        evaluator = match arg.as_str() {
+            "--eval=tree" => eval,
+            "--eval=closure" => analyze::eval,
+            "--eval=vm" => vm::eval,
+            a if a.starts_with("--eval=") => {
+                println!("Error: unknown evaluator {}, expected tree, closure or vm", &a[7..]);
+                process::exit(2);
+            },
+            a if a.starts_with("--max-depth=") => match a[12..].parse() {
+                Ok(max) if max > 0 => {
+                    types::set_max_depth(max);
+                    evaluator
+                },
+                _ => {
+                    println!("Error: bad depth {}, expected a positive number", &a[12..]);
+                    process::exit(2);
+                },
+            },
+            _ => break,
+        };
+        args.remove(1);
+    }
+    types::run_deep(move || repl(args, evaluator));
+}
+
+fn repl(args: Vec<String>, evaluator: fn(MalVal, Env) -> MalRet) {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns().into_iter() {
-        env_set(&repl_env, symbol(k.as_slice()), v);
+        env_set(&repl_env, symbol(&k), v);
     }
+    env_set(&repl_env, symbol("disassemble"), types::func(vm::disassemble));
+    core::set_loader(evaluator, repl_env.clone());
+    env_set(&repl_env, symbol("load-file"), types::func(core::load_file));
     // see eval() for definition of "eval"
-    env_set(&repl_env, symbol("*ARGV*".as_slice()), list(vec![]));
+    env_set(&repl_env, symbol("*ARGV*"), list(vec![]));
 
     // core.mal: defined using the language itself
-    let _ = rep("(def! *host-language* \"rust\")", repl_env.clone());
-    let _ = rep("(def! not (fn* (a) (if a false true)))", repl_env.clone());
-    let _ = rep("(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \")\")))))", repl_env.clone());
-    let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", repl_env.clone());
-    let _ = rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or_FIXME ~(first xs)) (if or_FIXME or_FIXME (or ~@(rest xs))))))))", repl_env.clone());
+    let _ = rep("(def! *host-language* \"rust\")", repl_env.clone(), evaluator);
+    let _ = rep("(def! not (fn* (a) (if a false true)))", repl_env.clone(), evaluator);
+    let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", repl_env.clone(), evaluator);
+    let _ = rep("(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or_FIXME ~(first xs)) (if or_FIXME or_FIXME (or ~@(rest xs))))))))", repl_env.clone(), evaluator);
 
     // Invoked with command line arguments
-    let args = os::args();
     if args.len() > 1 {
-        let mv_args = args.slice(2,args.len()).iter()
+        let mv_args = args[2..args.len()].iter()
             .map(|a| string(a.to_string()))
             .collect::<Vec<MalVal>>();
-        env_set(&repl_env, symbol("*ARGV*".as_slice()), list(mv_args));
-        let lf = "(load-file \"".to_string() + args[1] + "\")".to_string();
-        match rep(lf.as_slice(), repl_env.clone()) {
+        env_set(&repl_env, symbol("*ARGV*"), list(mv_args));
+        let lf = "(load-file \"".to_string() + &args[1] + "\")";
+        match rep(&lf, repl_env.clone(), evaluator) {
             Ok(_) => {
-                os::set_exit_status(0);
-                return;
+                process::exit(0);
             },
-            Err(str) => {
-                println!("Error: {}", str);
-                os::set_exit_status(1);
-                return;
+            Err(e) => {
+                print_error(e);
+                process::exit(1);
             },
         }
     }
 
     // repl loop
-    let _  = rep("(println (str \"Mal [\" *host-language* \"]\"))", repl_env.clone());
+    let _  = rep("(println (str \"Mal [\" *host-language* \"]\"))", repl_env.clone(), evaluator);
+    let mut input = String::new();
     loop {
-        let line = readline::mal_readline("user> ");
+        let prompt = if input.is_empty() { "user> " } else { "   ... " };
+        let line = readline::mal_readline(prompt);
         match line { None => break, _ => () }
-        match rep(line.unwrap().as_slice(), repl_env.clone()) {
+        input.push_str(&line.unwrap());
+        match read(&input) {
+            // keep reading lines until the forms are balanced
+            Err(ErrIncomplete(_)) => {
+                input.push('\n');
+                continue;
+            },
+            Ok(ref forms) if forms.is_empty() => {  // Blank line
+                input.clear();
+                continue;
+            },
+            _ => (),
+        }
+        match rep(&input, repl_env.clone(), evaluator) {
             Ok(str)  => println!("{}", str),
-            Err(ErrMalVal(_)) => (),  // Blank line
-            Err(ErrString(s)) => println!("Error: {}", s),
+            Err(e) => print_error(e),
         }
+        input.clear();
     }
 }
@@ -1,37 +1,60 @@
 #![allow(dead_code)]
 
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::rc::{Rc,Weak};
+use std::any::Any;
+use std::cell::{Cell,RefCell};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::{process,thread};
 use std::fmt;
+use std::convert::TryFrom;
 use super::printer::{escape_str,pr_list};
-use super::env::{Env,env_new,env_bind};
-
-#[deriving(Clone)]
+use super::env::{Env,env_frame,env_bind,param_names};
+use super::bignum::{BigInt,Ratio};
+use super::pvec::PVec;
+use super::pmap::PMap;
+use super::intern::SymbolId;
+use super::gc;
+use super::gc::Ref;
+
+pub use self::MalType::*;
+pub use self::MalError::*;
+
+#[derive(Clone)]
 #[allow(non_camel_case_types)]
 pub enum MalType {
     Nil,
     True,
     False,
-    Int(int),
+    Int(isize),
+    Bignum(BigInt), // only for values that don't fit in an Int, see _bigint
+    Rational(Ratio), // never has a denominator of one, see _rational
+    Float(f64),
     Strn(String),
-    Sym(String),
-    List(Vec<MalVal>, MalVal),
-    Vector(Vec<MalVal>, MalVal),
-    Hash_Map(HashMap<String, MalVal>, MalVal),
+    Sym(SymbolId),
+    List(PVec<MalVal>, MalVal),
+    Vector(PVec<MalVal>, MalVal),
+    Hash_Map(PMap<MalVal>, MalVal),
     Func(fn(Vec<MalVal>) -> MalRet, MalVal),
     //Func(fn(&[MalVal]) -> MalRet),
     //Func(|Vec<MalVal>|:'a -> MalRet),
     MalFunc(MalFuncData, MalVal),
     Atom(RefCell<MalVal>),
+    // only in code rewritten by resolve.rs: a local symbol with how many
+    // frames out it is bound and its slot there, and the head of a fn*, let*
+    // or catch* form with the names of the slots in the frame it makes
+    Local(SymbolId, usize, usize),
+    Binder(SymbolId, Rc<Vec<SymbolId>>),
 }
 
 pub type MalVal = Rc<MalType>;
 
-#[deriving(Show)]
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum MalError {
     ErrString(String),
     ErrMalVal(MalVal),
+    // the reader hit the end of input inside a form; more input may finish it
+    ErrIncomplete(String),
 }
 
 pub type MalRet = Result<MalVal,MalError>;
@@ -58,13 +81,281 @@ pub enum MalRet {
 */
 
 
-#[deriving(Clone)]
+#[derive(Clone)]
 pub struct MalFuncData {
     pub eval:     fn(MalVal, Env) -> MalRet,
     pub exp:      MalVal,
     pub env:      Env,
     pub params:   MalVal,
+    pub names:    Rc<Vec<SymbolId>>, // slots of the frame for each call
     pub is_macro: bool,
+    pub code:     Option<Code>, // exp compiled by analyze.rs, run in its place
+    pub bytecode: Option<Rc<dyn Bytecode>>, // exp compiled by vm.rs, likewise
+    pub name:     Option<SymbolId>, // given by def!
+    pub pos:      Option<Rc<str>>, // where it was made, see source
+}
+
+// A function compiled to bytecode (see vm.rs), which apply hands its
+// arguments to directly rather than binding them in an Env
+pub trait Bytecode {
+    fn call(&self, args: Vec<MalVal>, call: Call) -> MalRet;
+    fn as_any(&self) -> &dyn Any;
+    // what it refers to, for the collector (see gc.rs)
+    fn trace(&self, refs: &mut Vec<Ref>);
+}
+
+// What running compiled code gives: its value, or the function and
+// arguments of a call in tail position, which run_code makes in a loop
+// rather than by recursing so that tail calls don't grow the stack
+pub enum Step {
+    Done(MalVal),
+    TailCall(MalVal, Vec<MalVal>),
+}
+
+pub type Code = Rc<dyn Fn(&Env) -> Result<Step,MalError>>;
+
+pub fn run_code(code: &Code, env: &Env) -> MalRet {
+    run_call(code, env, Call::new())
+}
+
+// run_code as the body of a call, which the tail calls it makes replace
+fn run_call(code: &Code, env: &Env, mut call: Call) -> MalRet {
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(e) => return call.exit(Err(e)),
+    };
+    let mut step = match code(env) {
+        Ok(step) => step,
+        Err(e) => return call.exit(Err(e)),
+    };
+    loop {
+        let (f, args) = match step {
+            Step::Done(v) => return call.exit(Ok(v)),
+            Step::TailCall(f, args) => (f, args),
+        };
+        let mfc = match *f {
+            MalFunc(ref mf,_) if mf.code.is_some() => mf.clone(),
+            _ => {
+                call.leave();
+                return f.apply(args);
+            },
+        };
+        call.enter(&mfc);
+        let new_env = env_frame(Some(mfc.env.clone()), mfc.names.clone());
+        step = match env_bind(&new_env, mfc.params, list(args)) {
+            Ok(_) => match (mfc.code.unwrap())(&new_env) {
+                Ok(step) => step,
+                Err(e) => return call.exit(Err(e)),
+            },
+            Err(e) => return call.exit(err_string(e)),
+        }
+    }
+}
+
+// A mal function call as a backtrace shows it: the name def! gave the
+// function, or else its parameters, and where it was made if that's known
+#[derive(Clone)]
+pub struct Frame {
+    pub name:   Option<SymbolId>,
+    pub params: MalVal,
+    pub pos:    Option<Rc<str>>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "(fn* {} ...)", self.params.pr_str(true))?,
+        }
+        match self.pos {
+            Some(ref pos) => write!(f, " ({})", pos),
+            None => Ok(()),
+        }
+    }
+}
+
+// The calls being made, innermost last. The frames of the calls an error
+// returns out of are left above depth, so that whatever handles the error
+// can see where it came from, until the next call replaces them.
+struct CallStack {
+    frames: Vec<Frame>,
+    depth:  usize,
+}
+
+// An error catch* has turned into a value, and the trace it had
+type Trace = (Weak<MalType>, Rc<Vec<Frame>>);
+
+thread_local! {
+    static CALLS: RefCell<CallStack> = const { RefCell::new(CallStack{frames: vec![], depth: 0}) };
+    static SOURCE: RefCell<Option<Rc<str>>> = const { RefCell::new(None) };
+    static TRACES: RefCell<Vec<Trace>> = const { RefCell::new(vec![]) };
+}
+
+// The call stack entry of a mal function being run. The first call made
+// through it pushes a frame and each one after replaces it, so a chain of
+// tail calls takes up one frame.
+#[derive(Default)]
+pub struct Call {
+    entered: bool,
+}
+
+impl Call {
+    pub fn new() -> Call {
+        Call{entered: false}
+    }
+
+    pub fn enter(&mut self, mf: &MalFuncData) {
+        let frame = Frame{name: mf.name, params: mf.params.clone(), pos: mf.pos.clone()};
+        CALLS.with(|calls| {
+            let mut calls = calls.borrow_mut();
+            let depth = calls.depth;
+            calls.frames.truncate(depth);
+            if self.entered {
+                calls.frames[depth-1] = frame;
+            } else {
+                calls.frames.push(frame);
+                calls.depth += 1;
+            }
+        });
+        self.entered = true;
+    }
+
+    // Pops the frame, leaving it in place if res is an error coming out of it
+    pub fn exit(self, res: MalRet) -> MalRet {
+        self.pop(res.is_ok());
+        res
+    }
+
+    // Pops the frame for a tail call that will push its own
+    pub fn leave(self) {
+        self.pop(true);
+    }
+
+    fn pop(&self, ok: bool) {
+        if self.entered {
+            CALLS.with(|calls| {
+                let mut calls = calls.borrow_mut();
+                calls.depth -= 1;
+                if ok {
+                    let depth = calls.depth;
+                    calls.frames.truncate(depth);
+                }
+            });
+        }
+    }
+}
+
+// The calls the last error came out of, innermost first, and the ones it
+// was made in below them
+pub fn take_trace() -> Vec<Frame> {
+    CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        let trace = calls.frames.iter().rev().cloned().collect();
+        let depth = calls.depth;
+        calls.frames.truncate(depth);
+        trace
+    })
+}
+
+// The value catch* binds for an error, which ex-trace can find its trace by
+pub fn exception(err: MalError) -> MalVal {
+    let exc = match err {
+        ErrMalVal(mv) => mv,
+        ErrString(s) | ErrIncomplete(s) => string(s),
+    };
+    let trace = Rc::new(take_trace());
+    TRACES.with(|traces| {
+        let mut traces = traces.borrow_mut();
+        traces.retain(|&(ref e, _)| e.strong_count() > 0);
+        traces.push((Rc::downgrade(&exc), trace));
+    });
+    exc
+}
+
+// The trace exception recorded for exc, if it came from an error
+pub fn trace_of(exc: &MalVal) -> Option<Rc<Vec<Frame>>> {
+    TRACES.with(|traces| {
+        traces.borrow().iter().rev()
+            .find(|&&(ref e, _)| e.strong_count() > 0 && e.as_ptr() == Rc::as_ptr(exc))
+            .map(|&(_, ref trace)| trace.clone())
+    })
+}
+
+// "file:line:column" of the top-level form load-string is running, if any,
+// which the functions made while it runs take as their pos
+pub fn source() -> Option<Rc<str>> {
+    SOURCE.with(|s| s.borrow().clone())
+}
+
+// Sets source, giving back what it was
+pub fn set_source(pos: Option<Rc<str>>) -> Option<Rc<str>> {
+    SOURCE.with(|s| s.replace(pos))
+}
+
+// How deep evaluation and reading may nest, counting each eval (or run of
+// compiled code, or form read) started while another is still going. Going
+// deeper fails with a "stack overflow" error that catch* can handle, where
+// it would otherwise overflow the native stack and kill the process.
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(10000);
+
+// The native stack each level of depth is given, with room to spare for
+// the deepest path between two checks in a debug build
+const STACK_PER_LEVEL: usize = 32 * 1024;
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn set_max_depth(max: usize) {
+    MAX_DEPTH.store(max, Ordering::Relaxed);
+}
+
+// A level of depth, given back when it's dropped
+pub struct Depth(());
+
+impl Depth {
+    pub fn enter() -> Result<Depth,MalError> {
+        DEPTH.with(|depth| {
+            if depth.get() >= MAX_DEPTH.load(Ordering::Relaxed) {
+                return Err(ErrString("stack overflow".to_string()));
+            }
+            depth.set(depth.get() + 1);
+            Ok(Depth(()))
+        })
+    }
+}
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+// Runs main on a thread with stack enough for the max depth, and exits
+// the way it does
+pub fn run_deep<F: FnOnce() + Send + 'static>(main: F) {
+    let stack = MAX_DEPTH.load(Ordering::Relaxed).saturating_mul(STACK_PER_LEVEL);
+    match thread::Builder::new().stack_size(stack).spawn(main) {
+        Ok(t) => if t.join().is_err() {
+            process::exit(101);  // it panicked, which has been reported
+        },
+        Err(e) => {
+            println!("Error: can't start the interpreter: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+// v, or if it's a function def! hasn't named yet, a copy of it called name
+pub fn named(v: MalVal, name: SymbolId) -> MalVal {
+    match *v {
+        MalFunc(ref mfd, ref meta) if mfd.name.is_none() => {
+            let mut new_mfd = mfd.clone();
+            new_mfd.name = Some(name);
+            malfuncd(new_mfd, meta.clone())
+        },
+        _ => v.clone(),
+    }
 }
 
 impl MalType {
@@ -75,16 +366,19 @@ impl MalType {
             Nil => res.push_str("nil"),
             True => res.push_str("true"),
             False => res.push_str("false"),
-            Int(v) => res.push_str(v.to_string().as_slice()),
-            Sym(ref v) => res.push_str((*v).as_slice()),
+            Int(v) => res.push_str(&v.to_string()),
+            Bignum(ref v) => res.push_str(&v.to_string()),
+            Rational(ref v) => res.push_str(&v.to_string()),
+            Float(v) => res.push_str(&float_str(v)),
+            Sym(v) | Local(v,_,_) | Binder(v,_) => res.push_str(&v.name()),
             Strn(ref v) => {
-                if v.as_slice().starts_with("\u029e") {
+                if v.starts_with("\u{29e}") {
                     res.push_str(":");
-                    res.push_str(v.as_slice().slice(2,v.len()))
+                    res.push_str(&v[2..v.len()])
                 } else if print_readably {
-                    res.push_str(escape_str((*v).as_slice()).as_slice())
+                    res.push_str(&escape_str(v))
                 } else {
-                    res.push_str(v.as_slice())
+                    res.push_str(v)
                 }
             },
             List(ref v,_) => {
@@ -98,25 +392,25 @@ impl MalType {
                 res.push_str("{");
                 for (key, value) in v.iter() {
                     if first { first = false; } else { res.push_str(" "); }
-                    if key.as_slice().starts_with("\u029e") {
+                    if key.starts_with("\u{29e}") {
                         res.push_str(":");
-                        res.push_str(key.as_slice().slice(2,key.len()))
+                        res.push_str(&key[2..key.len()])
                     } else if print_readably {
-                        res.push_str(escape_str(key.as_slice()).as_slice())
+                        res.push_str(&escape_str(key))
                     } else {
-                        res.push_str(key.as_slice())
+                        res.push_str(key)
                     }
                     res.push_str(" ");
-                    res.push_str(value.pr_str(_r).as_slice());
+                    res.push_str(&value.pr_str(_r));
                 }
                 res.push_str("}")
             },
             // TODO: better native function representation
             Func(_,_) => {
-                res.push_str(format!("#<function ...>").as_slice())
+                res.push_str("#<function ...>")
             },
             MalFunc(ref mf,_) => {
-                res.push_str(format!("(fn* {} {})", mf.params, mf.exp).as_slice())
+                res.push_str(&format!("(fn* {} {})", mf.params, mf.exp))
             },
             Atom(ref v) => {
                 res = format!("(atom {})", v.borrow());
@@ -129,12 +423,20 @@ impl MalType {
         match *self {
             Func(f,_) => f(args),
             MalFunc(ref mf,_) => {
+                let mut call = Call::new();
+                call.enter(mf);
+                if let Some(ref bytecode) = mf.bytecode {
+                    return bytecode.call(args, call);
+                }
                 let mfc = mf.clone();
                 let alst = list(args);
-                let new_env = env_new(Some(mfc.env.clone()));
+                let new_env = env_frame(Some(mfc.env.clone()), mfc.names.clone());
                 match env_bind(&new_env, mfc.params, alst) {
-                    Ok(_) => (mfc.eval)(mfc.exp, new_env),
-                    Err(e) => err_string(e),
+                    Ok(_) => match mfc.code {
+                        Some(ref code) => run_call(code, &new_env, call),
+                        None => call.exit((mfc.eval)(mfc.exp, new_env)),
+                    },
+                    Err(e) => call.exit(err_string(e)),
                 }
             },
             _ => err_str("attempt to call non-function"),
@@ -143,6 +445,20 @@ impl MalType {
     }
 }
 
+// Debug formatting gives the shortest string that reads back as the same
+// float, always with a . or an exponent so it doesn't read as an int
+fn float_str(f: f64) -> String {
+    if f.is_nan() {
+        "##NaN".to_string()
+    } else if f == f64::INFINITY {
+        "##Inf".to_string()
+    } else if f == f64::NEG_INFINITY {
+        "##-Inf".to_string()
+    } else {
+        format!("{:?}", f)
+    }
+}
+
 impl PartialEq for MalType {
     fn eq(&self, other: &MalType) -> bool {
         match (self, other) {
@@ -150,6 +466,9 @@ impl PartialEq for MalType {
             (&True, &True) |
             (&False, &False) => true,
             (&Int(ref a), &Int(ref b)) => a == b,
+            (&Bignum(ref a), &Bignum(ref b)) => a == b,
+            (&Rational(ref a), &Rational(ref b)) => a == b,
+            (&Float(a), &Float(b)) => a == b,
             (&Strn(ref a), &Strn(ref b)) => a == b,
             (&Sym(ref a), &Sym(ref b)) => a == b,
             (&List(ref a,_), &List(ref b,_)) |
@@ -165,7 +484,13 @@ impl PartialEq for MalType {
     }
 }
 
-impl fmt::Show for MalType {
+impl fmt::Display for MalType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pr_str(true))
+    }
+}
+
+impl fmt::Debug for MalType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.pr_str(true))
     }
@@ -206,18 +531,39 @@ pub fn false_q(a:Vec<MalVal>) -> MalRet {
     }
 }
 
-pub fn _int(i: int) -> MalVal { Rc::new(Int(i)) }
+pub fn _int(i: isize) -> MalVal { Rc::new(Int(i)) }
+
+// BigInt results go back to being Ints when they fit, so that each number
+// has exactly one representation
+pub fn _bigint(b: BigInt) -> MalVal {
+    match b.to_i64().and_then(|i| isize::try_from(i).ok()) {
+        Some(i) => _int(i),
+        None => Rc::new(Bignum(b)),
+    }
+}
+
+
+// likewise Ratios with a denominator of one go back to being ints
+pub fn _rational(r: Ratio) -> MalVal {
+    if r.is_integer() {
+        _bigint(r.numer().clone())
+    } else {
+        Rc::new(Rational(r))
+    }
+}
+
+pub fn _float(f: f64) -> MalVal { Rc::new(Float(f)) }
 
 
 // Symbols
-pub fn symbol(strn: &str) -> MalVal { Rc::new(Sym(strn.to_string())) }
+pub fn symbol(strn: &str) -> MalVal { Rc::new(Sym(SymbolId::intern(strn))) }
 pub fn _symbol(a: Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to symbol call");
     }
     match *a[0].clone() {
         Strn(ref s) => {
-            Ok(Rc::new(Sym(s.to_string())))
+            Ok(symbol(s))
         },
         _ => return err_str("symbol called on non-string"),
     }
@@ -239,7 +585,7 @@ pub fn _keyword(a: Vec<MalVal>) -> MalRet {
     }
     match *a[0].clone() {
         Strn(ref s) => {
-            Ok(Rc::new(Strn("\u029e".to_string() + s.to_string())))
+            Ok(Rc::new(Strn("\u{29e}".to_string() + s)))
         },
         _ => return err_str("keyword called on non-string"),
     }
@@ -250,7 +596,7 @@ pub fn keyword_q(a:Vec<MalVal>) -> MalRet {
     }
     match *a[0].clone() {
         Strn(ref s) => {
-            if s.as_slice().starts_with("\u029e") {
+            if s.starts_with("\u{29e}") {
                 Ok(_true())
             } else {
                 Ok(_false())
@@ -266,8 +612,8 @@ pub fn strn(strn: &str) -> MalVal { Rc::new(Strn(strn.to_string())) }
 pub fn string(strn: String) -> MalVal { Rc::new(Strn(strn)) }
 
 // Lists
-pub fn list(seq: Vec<MalVal>) -> MalVal { Rc::new(List(seq,_nil())) }
-pub fn listm(seq: Vec<MalVal>, meta: MalVal) -> MalVal {
+pub fn list(seq: Vec<MalVal>) -> MalVal { Rc::new(List(PVec::from(seq),_nil())) }
+pub fn listm(seq: PVec<MalVal>, meta: MalVal) -> MalVal {
     Rc::new(List(seq,meta))
 }
 pub fn listv(seq:Vec<MalVal>) -> MalRet { Ok(list(seq)) }
@@ -282,8 +628,8 @@ pub fn list_q(a:Vec<MalVal>) -> MalRet {
 }
 
 // Vectors
-pub fn vector(seq: Vec<MalVal>) -> MalVal { Rc::new(Vector(seq,_nil())) }
-pub fn vectorm(seq: Vec<MalVal>, meta: MalVal) -> MalVal {
+pub fn vector(seq: Vec<MalVal>) -> MalVal { Rc::new(Vector(PVec::from(seq),_nil())) }
+pub fn vectorm(seq: PVec<MalVal>, meta: MalVal) -> MalVal {
     Rc::new(Vector(seq,meta))
 }
 pub fn vectorv(seq: Vec<MalVal>) -> MalRet { Ok(vector(seq)) }
@@ -298,13 +644,13 @@ pub fn vector_q(a:Vec<MalVal>) -> MalRet {
 }
 
 // Hash Maps
-pub fn hash_map(hm: HashMap<String,MalVal>) -> MalVal {
+pub fn hash_map(hm: PMap<MalVal>) -> MalVal {
     Rc::new(Hash_Map(hm,_nil()))
 }
-pub fn hash_mapm(hm: HashMap<String,MalVal>, meta: MalVal) -> MalVal {
+pub fn hash_mapm(hm: PMap<MalVal>, meta: MalVal) -> MalVal {
     Rc::new(Hash_Map(hm,meta))
 }
-pub fn _assoc(hm: &HashMap<String,MalVal>, a:Vec<MalVal>) -> MalRet {
+pub fn _assoc(hm: &PMap<MalVal>, a:Vec<MalVal>) -> MalRet {
     if a.len() % 2 == 1 {
         return err_str("odd number of hash-map keys/values");
     }
@@ -323,7 +669,7 @@ pub fn _assoc(hm: &HashMap<String,MalVal>, a:Vec<MalVal>) -> MalRet {
     }
     Ok(Rc::new(Hash_Map(new_hm,_nil())))
 }
-pub fn _dissoc(hm: &HashMap<String,MalVal>, a:Vec<MalVal>) -> MalRet {
+pub fn _dissoc(hm: &PMap<MalVal>, a:Vec<MalVal>) -> MalRet {
     let mut new_hm = hm.clone();
     let mut it = a.iter();
     loop {
@@ -339,8 +685,7 @@ pub fn _dissoc(hm: &HashMap<String,MalVal>, a:Vec<MalVal>) -> MalRet {
     Ok(Rc::new(Hash_Map(new_hm,_nil())))
 }
 pub fn hash_mapv(seq: Vec<MalVal>) -> MalRet {
-    let new_hm: HashMap<String,MalVal> = HashMap::new();
-    _assoc(&new_hm, seq)
+    _assoc(&PMap::new(), seq)
 }
 pub fn hash_map_q(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
@@ -364,11 +709,15 @@ pub fn malfunc(eval: fn(MalVal, Env) -> MalRet,
                env: Env,
                params: MalVal,
                meta: MalVal) -> MalVal {
+    let names = param_names(&params);
     Rc::new(MalFunc(MalFuncData{eval: eval,
                                 exp: exp,
                                 env: env,
                                 params: params,
-                                is_macro: false},meta))
+                                names: names,
+                                is_macro: false,
+                                code: None, bytecode: None,
+                                name: None, pos: source()},meta))
 }
 pub fn malfuncd(mfd: MalFuncData, meta: MalVal) -> MalVal {
     Rc::new(MalFunc(mfd,meta))
@@ -389,7 +738,9 @@ pub fn atom(a:Vec<MalVal>) -> MalRet {
     if a.len() != 1 {
         return err_str("Wrong arity to atom call");
     }
-    Ok(Rc::new(Atom(RefCell::new(a[0].clone()))))
+    let atom = Rc::new(Atom(RefCell::new(a[0].clone())));
+    gc::track_atom(&atom);
+    Ok(atom)
 }
 
 
@@ -0,0 +1,527 @@
+// A bytecode machine, stepA's third evaluator (--eval=vm).
+//
+// compiler.rs turns a form, after macroexpansion, into a Proto: a flat list
+// of Ops for a stack machine along with the constants and nested functions
+// they refer to. Every variable a function binds has a numbered slot in the
+// frame of a call to it, so finding a local is indexing rather than a lookup
+// by name. A closure captures variables of the functions around it as
+// upvalues: a slot is moved into a shared cell the first time a closure
+// captures it, so the frame and the closure go on seeing each other's def!s.
+//
+// run makes one call of a closure. A call in tail position replaces the
+// running frame instead of recursing, so tail calls run in constant stack;
+// other calls recurse as they do in eval. try* pushes a handler, and an error
+// in its body unwinds the operand stack back to where it was and jumps to the
+// catch* code.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use types::{MalVal,MalRet,MalError,ErrString,ErrMalVal,Bytecode,Call,Step,Depth,
+            MalFuncData,Nil,False,Strn,Sym,MalFunc,err_str,_nil,list,vector,
+            hash_mapv,malfuncd,named,source,exception};
+use env::{Env,env_set,env_get};
+use intern::{SymbolId,FN,EVAL};
+use gc;
+use gc::{Ref,Upval};
+use compiler;
+use macroexpand;
+
+#[derive(Clone, Copy)]
+pub enum Op {
+    Const(usize),           // push constant i
+    GetLocal(usize),        // push the value in slot i
+    GetUpval(usize),        // push the value of upvalue i
+    GetGlobal(usize),       // push the value of the symbol constant i
+    TryLocal(usize, usize), // push slot i and jump to j, if it has been bound
+    TryUpval(usize, usize), // likewise for upvalue i
+    SetLocal(usize),        // pop into slot i
+    DefLocal(usize),        // def! slot i to the top value
+    DefGlobal(usize),       // def! the symbol constant i to the top value
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),     // pop, and jump to i if it was nil or false
+    Closure(usize),         // push a closure of nested function i
+    Macro,                  // turn the function on top into a macro
+    Vector(usize),          // pop i values into a vector
+    Map(usize),             // pop i keys and values into a hash-map
+    Call(usize),            // call the function under the top i values
+    TailCall(usize),        // likewise, returning what it returns
+    Return,
+    Try(usize, usize),      // on an error before EndTry, go to i with it in slot j
+    EndTry,
+    Compile,                // replace the form on top with a function, eval, running it
+    MacroExpand(usize),     // push the expansion of constant i
+    Expanded(usize),        // check expansion i is still of the macro it was
+    Fail(usize),            // raise constant i, the error in a malformed form
+    Throw(usize),           // throw constant i, raised by a macro as it was expanded
+}
+
+// Where a closure finds an upvalue when it is made: in a slot of the
+// function making it, or among that function's own upvalues
+#[derive(Clone, Copy, PartialEq)]
+pub enum Capture {
+    Local(usize),
+    Upval(usize),
+}
+
+pub struct Proto {
+    pub params:   MalVal, // the fn* as written, for printing it
+    pub exp:      MalVal,
+    pub arity:    usize,  // the fixed parameters, in the first slots
+    pub variadic: bool,   // the rest go in a list in the next slot
+    pub slots:    Vec<SymbolId>,
+    pub upvals:   Vec<(SymbolId, Capture)>,
+    pub code:     Vec<Op>,
+    pub consts:   Vec<MalVal>,
+    pub protos:   Vec<Rc<Proto>>,
+    pub expansions: Vec<Expansion>,
+    pub calls:    Vec<(usize, Expansion)>, // calls of globals, by where the head is got
+}
+
+// A macro call the compiler expanded in place. If the macro is redefined, the
+// code after the Expanded op is out of date, so the call is compiled again as
+// a function of the locals it can see and run instead. A call in Proto::calls
+// is compiled again the same way, if what it calls is a macro when it's run.
+pub struct Expansion {
+    pub sym:    MalVal, // the head of the call, and the macro it named (nil in calls)
+    pub mac:    MalVal,
+    pub form:   MalVal,
+    pub vars:   Vec<(SymbolId, Capture)>,
+    pub end:    usize,  // where the code for the expansion ends
+    pub redone: RefCell<Option<(MalVal, MalVal)>>, // the last recompiled, by macro
+}
+
+impl Expansion {
+    // The call compiled as it would be now, given that what sym names has
+    // changed to m
+    fn recompile(&self, m: MalVal, globals: &Env) -> MalRet {
+        if let Some((ref old, ref f)) = *self.redone.borrow() {
+            if Rc::ptr_eq(old, &m) {
+                return Ok(f.clone());
+            }
+        }
+        let params = self.vars.iter().map(|&(name, _)| Rc::new(Sym(name))).collect();
+        let f = eval(list(vec![Rc::new(Sym(FN)), list(params), self.form.clone()]),
+                     globals.clone())?;
+        *self.redone.borrow_mut() = Some((m, f.clone()));
+        Ok(f)
+    }
+}
+
+#[derive(Clone)]
+pub struct Closure {
+    proto:   Rc<Proto>,
+    upvals:  Rc<Vec<Upval>>,
+    globals: Env,
+}
+
+impl Bytecode for Closure {
+    fn call(&self, args: Vec<MalVal>, call: Call) -> MalRet {
+        run(self, args, call)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn trace(&self, refs: &mut Vec<Ref>) {
+        refs.push(Ref::Upvals(self.upvals.clone()));
+        refs.push(Ref::Env(self.globals.clone()));
+    }
+}
+
+#[derive(Clone)]
+enum Slot {
+    Unbound,
+    Val(MalVal),
+    Boxed(Upval),
+}
+
+struct Handler {
+    target: usize,
+    slot:   usize,
+    depth:  usize,
+}
+
+// Compiles ast and runs it, in place of eval
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    let proto = compiler::compile(&ast, &env)?;
+    run(&Closure{proto: proto, upvals: Rc::new(vec![]), globals: env}, vec![], Call::new())
+}
+
+fn function(proto: Rc<Proto>, upvals: Vec<Upval>, globals: &Env) -> MalVal {
+    let (params, exp) = (proto.params.clone(), proto.exp.clone());
+    let closure = Closure{proto: proto, upvals: Rc::new(upvals), globals: globals.clone()};
+    malfuncd(MalFuncData{eval: eval, exp: exp, env: globals.clone(), params: params,
+                         names: Rc::new(vec![]), is_macro: false, code: None,
+                         bytecode: Some(Rc::new(closure)), name: None, pos: source()},
+             _nil())
+}
+
+// The call the GetGlobal at ip gets the head of, if what it got is a macro:
+// one defined after the call was compiled
+fn late_call<'a>(proto: &'a Proto, ip: usize, v: &MalVal) -> Option<&'a Expansion> {
+    match **v {
+        MalFunc(ref mf,_) if mf.is_macro => {
+            proto.calls.iter().find(|&&(head, _)| head == ip).map(|&(_, ref e)| e)
+        },
+        _ => None,
+    }
+}
+
+// The values of the locals an expansion sees, with a local not bound yet
+// passed as nil
+fn values(vars: &[(SymbolId, Capture)], slots: &[Slot], upvals: &[Upval]) -> Vec<MalVal> {
+    vars.iter().map(|&(_, c)| match c {
+        Capture::Local(s) => get(&slots[s]),
+        Capture::Upval(u) => upvals[u].borrow().clone(),
+    }.unwrap_or_else(_nil)).collect()
+}
+
+fn closure_of(f: &MalVal) -> Option<(&MalFuncData, Closure)> {
+    match **f {
+        MalFunc(ref mf,_) => mf.bytecode.as_ref()
+            .and_then(|b| b.as_any().downcast_ref::<Closure>())
+            .map(|c| (mf, c.clone())),
+        _ => None,
+    }
+}
+
+fn bind(proto: &Proto, mut args: Vec<MalVal>) -> Result<Vec<Slot>,MalError> {
+    if args.len() < proto.arity {
+        return Err(ErrString(format!("wrong number of arguments to {}: expected {}, got {}",
+                                     proto.params, proto.arity, args.len())));
+    }
+    let mut slots = vec![Slot::Unbound; proto.slots.len()];
+    if proto.variadic {
+        slots[proto.arity] = Slot::Val(list(args.split_off(proto.arity)));
+    } else {
+        args.truncate(proto.arity);
+    }
+    for (slot, arg) in slots.iter_mut().zip(args) {
+        *slot = Slot::Val(arg);
+    }
+    Ok(slots)
+}
+
+fn get(slot: &Slot) -> Option<MalVal> {
+    match *slot {
+        Slot::Unbound => None,
+        Slot::Val(ref v) => Some(v.clone()),
+        Slot::Boxed(ref cell) => cell.borrow().clone(),
+    }
+}
+
+fn set(slot: &mut Slot, v: MalVal) {
+    match *slot {
+        Slot::Boxed(ref cell) => *cell.borrow_mut() = Some(v),
+        _ => *slot = Slot::Val(v),
+    }
+}
+
+// The cell a slot is kept in once a closure has captured it
+fn capture(slot: &mut Slot) -> Upval {
+    let cell = match *slot {
+        Slot::Boxed(ref cell) => return cell.clone(),
+        Slot::Val(ref v) => Rc::new(RefCell::new(Some(v.clone()))),
+        Slot::Unbound => Rc::new(RefCell::new(None)),
+    };
+    gc::track_upval(&cell);
+    *slot = Slot::Boxed(cell.clone());
+    cell
+}
+
+// Names the function on top after sym, as def! does, and gives it back
+fn def(stack: &mut Vec<MalVal>, sym: &MalVal) -> MalVal {
+    let v = match **sym {
+        Sym(name) => named(stack.pop().unwrap(), name),
+        _ => stack.pop().unwrap(),
+    };
+    stack.push(v.clone());
+    v
+}
+
+fn not_found(name: SymbolId) -> MalError {
+    ErrString(format!("'{}' not found", name))
+}
+
+// Runs a call of closure, in the call stack entry given
+pub fn run(closure: &Closure, args: Vec<MalVal>, mut call: Call) -> MalRet {
+    let _depth = match Depth::enter() {
+        Ok(depth) => depth,
+        Err(e) => return call.exit(Err(e)),
+    };
+    match execute(closure, args, &mut call) {
+        // a mal function pushes its own frame, but a builtin runs in this one
+        Ok(Step::TailCall(f, args)) => match *f {
+            MalFunc(..) => {
+                call.leave();
+                f.apply(args)
+            },
+            _ => call.exit(f.apply(args)),
+        },
+        Ok(Step::Done(v)) => call.exit(Ok(v)),
+        Err(e) => call.exit(Err(e)),
+    }
+}
+
+fn execute(closure: &Closure, args: Vec<MalVal>, call: &mut Call) -> Result<Step,MalError> {
+    let mut proto = closure.proto.clone();
+    let mut upvals = closure.upvals.clone();
+    let mut globals = closure.globals.clone();
+    let mut slots = bind(&proto, args)?;
+    let mut stack: Vec<MalVal> = vec![];
+    let mut handlers: Vec<Handler> = vec![];
+    let mut ip = 0;
+    loop {
+        let op = proto.code[ip];
+        ip += 1;
+        let res = match op {
+            Op::Const(i) => {
+                stack.push(proto.consts[i].clone());
+                Ok(())
+            },
+            Op::GetLocal(i) => match get(&slots[i]) {
+                Some(v) => { stack.push(v); Ok(()) },
+                None => Err(not_found(proto.slots[i])),
+            },
+            Op::GetUpval(i) => match upvals[i].borrow().clone() {
+                Some(v) => { stack.push(v); Ok(()) },
+                None => Err(not_found(proto.upvals[i].0)),
+            },
+            Op::GetGlobal(i) => match env_get(globals.clone(), proto.consts[i].clone()) {
+                Ok(v) => match late_call(&proto, ip - 1, &v) {
+                    Some(e) => {
+                        let args = values(&e.vars, &slots, &upvals);
+                        e.recompile(v, &globals)
+                            .and_then(|f| f.apply(args))
+                            .map(|v| {
+                                stack.push(v);
+                                ip = e.end;
+                            })
+                    },
+                    None => {
+                        stack.push(v);
+                        Ok(())
+                    },
+                },
+                Err(e) => Err(e),
+            },
+            Op::TryLocal(i, target) => {
+                if let Some(v) = get(&slots[i]) {
+                    stack.push(v);
+                    ip = target;
+                }
+                Ok(())
+            },
+            Op::TryUpval(i, target) => {
+                if let Some(v) = upvals[i].borrow().clone() {
+                    stack.push(v);
+                    ip = target;
+                }
+                Ok(())
+            },
+            Op::SetLocal(i) => {
+                let v = stack.pop().unwrap();
+                set(&mut slots[i], v);
+                Ok(())
+            },
+            Op::DefGlobal(i) => {
+                let v = def(&mut stack, &proto.consts[i]);
+                env_set(&globals, proto.consts[i].clone(), v);
+                Ok(())
+            },
+            Op::DefLocal(i) => {
+                let v = def(&mut stack, &Rc::new(Sym(proto.slots[i])));
+                set(&mut slots[i], v);
+                Ok(())
+            },
+            Op::Pop => {
+                stack.pop();
+                Ok(())
+            },
+            Op::Jump(target) => {
+                ip = target;
+                Ok(())
+            },
+            Op::JumpIfFalse(target) => {
+                match *stack.pop().unwrap() {
+                    False | Nil => ip = target,
+                    _ => (),
+                }
+                Ok(())
+            },
+            Op::Closure(i) => {
+                let inner = &proto.protos[i];
+                let captured = inner.upvals.iter().map(|&(_, c)| match c {
+                    Capture::Local(s) => capture(&mut slots[s]),
+                    Capture::Upval(u) => upvals[u].clone(),
+                }).collect();
+                stack.push(function(inner.clone(), captured, &globals));
+                Ok(())
+            },
+            Op::Macro => {
+                let f = stack.pop().unwrap();
+                match *f {
+                    MalFunc(ref mfd,_) => {
+                        let mut new_mfd = mfd.clone();
+                        new_mfd.is_macro = true;
+                        stack.push(malfuncd(new_mfd,_nil()));
+                        Ok(())
+                    },
+                    _ => Err(ErrString("def! of non-symbol".to_string())),
+                }
+            },
+            Op::Vector(n) => {
+                let items = stack.split_off(stack.len() - n);
+                stack.push(vector(items));
+                Ok(())
+            },
+            Op::Map(n) => {
+                let items = stack.split_off(stack.len() - 2*n);
+                hash_mapv(items).map(|hm| stack.push(hm))
+            },
+            Op::Call(n) => {
+                let args = stack.split_off(stack.len() - n);
+                let f = stack.pop().unwrap();
+                f.apply(args).map(|v| stack.push(v))
+            },
+            Op::TailCall(n) => {
+                // nothing is left to unwind to in tail position, so errors
+                // go straight to the caller
+                let args = stack.split_off(stack.len() - n);
+                let f = stack.pop().unwrap();
+                match closure_of(&f) {
+                    Some((mf, c)) => {
+                        call.enter(mf);
+                        slots = bind(&c.proto, args)?;
+                        proto = c.proto;
+                        upvals = c.upvals;
+                        globals = c.globals;
+                        ip = 0;
+                        Ok(())
+                    },
+                    None => return Ok(Step::TailCall(f, args)),
+                }
+            },
+            Op::Return => return Ok(Step::Done(stack.pop().unwrap())),
+            Op::Try(target, slot) => {
+                handlers.push(Handler{target: target, slot: slot, depth: stack.len()});
+                Ok(())
+            },
+            Op::EndTry => {
+                handlers.pop();
+                Ok(())
+            },
+            Op::Compile => {
+                let form = stack.pop().unwrap();
+                compiler::compile(&form, &globals)
+                    .map(|p| stack.push(named(function(p, vec![], &globals), EVAL)))
+            },
+            Op::MacroExpand(i) => {
+                macroexpand(proto.consts[i].clone(), globals.clone()).map(|v| stack.push(v))
+            },
+            Op::Expanded(i) => {
+                let e = &proto.expansions[i];
+                match env_get(globals.clone(), e.sym.clone()) {
+                    Ok(ref m) if Rc::ptr_eq(m, &e.mac) => Ok(()),
+                    now => {
+                        let args = values(&e.vars, &slots, &upvals);
+                        now.and_then(|m| e.recompile(m, &globals))
+                            .and_then(|f| f.apply(args))
+                            .map(|v| {
+                                stack.push(v);
+                                ip = e.end;
+                            })
+                    },
+                }
+            },
+            Op::Fail(i) => match *proto.consts[i] {
+                Strn(ref s) => Err(ErrString(s.clone())),
+                _ => Err(ErrMalVal(proto.consts[i].clone())),
+            },
+            Op::Throw(i) => Err(ErrMalVal(proto.consts[i].clone())),
+        };
+        if let Err(e) = res {
+            let h = match handlers.pop() {
+                Some(h) => h,
+                None => return Err(e),
+            };
+            stack.truncate(h.depth);
+            set(&mut slots[h.slot], exception(e));
+            ip = h.target;
+        }
+    }
+}
+
+// (disassemble f) prints the code of a function compiled for the VM
+pub fn disassemble(a: Vec<MalVal>) -> MalRet {
+    if a.len() != 1 {
+        return err_str("Wrong arity to disassemble call");
+    }
+    match closure_of(&a[0]) {
+        Some((_, c)) => {
+            print!("{}", c.proto);
+            Ok(_nil())
+        },
+        None => err_str("disassemble of a function not compiled by --eval=vm"),
+    }
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "(fn* {} {})", self.params.pr_str(true), self.exp.pr_str(true))?;
+        let names = |names: Vec<SymbolId>| {
+            names.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(" ")
+        };
+        if !self.slots.is_empty() {
+            writeln!(f, "  slots: {}", names(self.slots.clone()))?;
+        }
+        if !self.upvals.is_empty() {
+            writeln!(f, "  upvalues: {}", names(self.upvals.iter().map(|u| u.0).collect()))?;
+        }
+        for (i, op) in self.code.iter().enumerate() {
+            let (name, args, note) = match *op {
+                Op::Const(c) => ("CONST", vec![c], Some(self.consts[c].pr_str(true))),
+                Op::GetLocal(s) => ("GET_LOCAL", vec![s], Some(self.slots[s].to_string())),
+                Op::GetUpval(u) => ("GET_UPVAL", vec![u], Some(self.upvals[u].0.to_string())),
+                Op::GetGlobal(c) => ("GET_GLOBAL", vec![c], Some(self.consts[c].pr_str(true))),
+                Op::TryLocal(s, t) => ("TRY_LOCAL", vec![s, t], Some(self.slots[s].to_string())),
+                Op::TryUpval(u, t) => ("TRY_UPVAL", vec![u, t], Some(self.upvals[u].0.to_string())),
+                Op::SetLocal(s) => ("SET_LOCAL", vec![s], Some(self.slots[s].to_string())),
+                Op::DefLocal(s) => ("DEF_LOCAL", vec![s], Some(self.slots[s].to_string())),
+                Op::DefGlobal(c) => ("DEF_GLOBAL", vec![c], Some(self.consts[c].pr_str(true))),
+                Op::Pop => ("POP", vec![], None),
+                Op::Jump(t) => ("JUMP", vec![t], None),
+                Op::JumpIfFalse(t) => ("JUMP_IF_FALSE", vec![t], None),
+                Op::Closure(p) => ("CLOSURE", vec![p], None),
+                Op::Macro => ("MACRO", vec![], None),
+                Op::Vector(n) => ("VECTOR", vec![n], None),
+                Op::Map(n) => ("MAP", vec![n], None),
+                Op::Call(n) => ("CALL", vec![n], None),
+                Op::TailCall(n) => ("TAIL_CALL", vec![n], None),
+                Op::Return => ("RETURN", vec![], None),
+                Op::Try(t, s) => ("TRY", vec![t, s], Some(self.slots[s].to_string())),
+                Op::EndTry => ("END_TRY", vec![], None),
+                Op::Compile => ("COMPILE", vec![], None),
+                Op::MacroExpand(c) => ("MACROEXPAND", vec![c], Some(self.consts[c].pr_str(true))),
+                Op::Expanded(e) => ("EXPANDED", vec![e], Some(self.expansions[e].form.pr_str(true))),
+                Op::Fail(c) => ("FAIL", vec![c], Some(self.consts[c].pr_str(true))),
+                Op::Throw(c) => ("THROW", vec![c], Some(self.consts[c].pr_str(true))),
+            };
+            let args = args.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(" ");
+            let line = match note {
+                Some(note) => format!("  {:4} {:14}{:6}; {}", i, name, args, note),
+                None => format!("  {:4} {:14}{}", i, name, args),
+            };
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        for (i, p) in self.protos.iter().enumerate() {
+            write!(f, "\nfunction {}: {}", i, p)?;
+        }
+        Ok(())
+    }
+}